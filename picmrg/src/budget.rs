@@ -0,0 +1,155 @@
+use image::DynamicImage;
+use std::fmt;
+use crate::error::Error;
+use crate::format::{Encoding, OutputFormat};
+use crate::output;
+use crate::provenance::Provenance;
+
+/// Lowest quality a size budget lowers lossy formats to
+const MIN_QUALITY: u8 = 50;
+/// Quality given up per attempt
+const QUALITY_STEP: u8 = 10;
+/// Factor the image is shrunk by per attempt once the lowest quality does not fit
+const DOWNSCALE_STEP: f64 = 0.8;
+/// Smallest share of the original size an image is shrunk to before giving up
+const MIN_SCALE: f64 = 0.25;
+
+/// How a merged image was fitted under a size budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fitted {
+    pub width: u32,
+    pub height: u32,
+    pub encoding: Encoding,
+    pub bytes: u64, // Size of the encoded file
+}
+
+impl fmt::Display for Fitted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{} {}", self.width, self.height, self.encoding.format.extension().to_uppercase())?;
+        if self.encoding.format.is_lossy() {
+            write!(f, " at quality {}", self.encoding.quality)?;
+        }
+        write!(f, ", {} bytes", self.bytes)
+    }
+}
+
+/// Format a lossless format falls back to when it does not fit a size budget
+pub fn fallback_format(format: OutputFormat) -> OutputFormat {
+    if format.is_lossy() { format } else { OutputFormat::Jpeg }
+}
+
+/// Encodings tried at full size, in order: the selected one, the lossy fallback of a lossless
+/// format, then ever lower qualities down to `MIN_QUALITY`
+fn encodings_to_try(encoding: &Encoding) -> Vec<Encoding> {
+    let mut encodings = vec![*encoding];
+    let lossy = Encoding { format: fallback_format(encoding.format), ..*encoding };
+    if lossy != *encoding {
+        encodings.push(lossy);
+    }
+
+    let mut quality = lossy.quality;
+    while quality > MIN_QUALITY {
+        quality = quality.saturating_sub(QUALITY_STEP).max(MIN_QUALITY);
+        encodings.push(Encoding { quality, ..lossy });
+    }
+    encodings
+}
+
+/// Encode an image so the file is at most `max_bytes` long
+///
+/// Tries, in order: the selected encoding; JPEG instead of a lossless format; lower qualities down
+/// to 50; then shrinking the image in 80% steps, at the lowest quality, down to a quarter of its size.
+pub fn fit_to_budget(
+    image: &DynamicImage,
+    encoding: &Encoding,
+    max_bytes: u64,
+    provenance: &Provenance,
+) -> Result<(Vec<u8>, Fitted), Error> {
+    let mut smallest = u64::MAX;
+    let encodings = encodings_to_try(encoding);
+    for candidate in &encodings {
+        let encoded = output::encode(image, candidate, provenance)?;
+        if let Some(fitted) = check_fit(&encoded, image, candidate, max_bytes, &mut smallest) {
+            return Ok((encoded, fitted));
+        }
+    }
+
+    let lowest = encodings[encodings.len() - 1];
+    let mut scale = DOWNSCALE_STEP;
+    while scale >= MIN_SCALE {
+        let width = ((image.width() as f64 * scale).round() as u32).max(1);
+        let height = ((image.height() as f64 * scale).round() as u32).max(1);
+        let resized = image.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
+        let encoded = output::encode(&resized, &lowest, provenance)?;
+        if let Some(fitted) = check_fit(&encoded, &resized, &lowest, max_bytes, &mut smallest) {
+            return Ok((encoded, fitted));
+        }
+        scale *= DOWNSCALE_STEP;
+    }
+
+    Err(Error::OverBudget { max_bytes, smallest })
+}
+
+/// Describe an attempt if it fits, otherwise remember its size
+fn check_fit(
+    encoded: &[u8],
+    image: &DynamicImage,
+    encoding: &Encoding,
+    max_bytes: u64,
+    smallest: &mut u64,
+) -> Option<Fitted> {
+    let bytes = encoded.len() as u64;
+    *smallest = (*smallest).min(bytes);
+    (bytes <= max_bytes).then_some(Fitted { width: image.width(), height: image.height(), encoding: *encoding, bytes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An image that compresses poorly, so size budgets bite
+    fn noisy_image(width: u32, height: u32) -> DynamicImage {
+        let mut state = 12345u32;
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |_, _| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let [red, green, blue, _] = state.to_be_bytes();
+            image::Rgb([red, green, blue])
+        }))
+    }
+
+    #[test]
+    fn test_encodings_to_try() {
+        let png = Encoding { format: OutputFormat::Png, quality: 85, dpi: None };
+        let tried = encodings_to_try(&png);
+        assert_eq!(tried[0], png);
+        assert_eq!(tried[1], Encoding { format: OutputFormat::Jpeg, quality: 85, dpi: None });
+        assert_eq!(tried.iter().map(|encoding| encoding.quality).collect::<Vec<_>>(), vec![85, 85, 75, 65, 55, 50]);
+
+        let jpeg = Encoding { format: OutputFormat::Jpeg, quality: 40, dpi: None };
+        assert_eq!(encodings_to_try(&jpeg), vec![jpeg]);
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let image = noisy_image(200, 100);
+        let provenance = Provenance::new("horizontal", &[]);
+        let png = Encoding::default();
+        let full_size = output::encode(&image, &png, &provenance).unwrap().len() as u64;
+
+        // A generous budget keeps the selected encoding
+        let (encoded, fitted) = fit_to_budget(&image, &png, full_size, &provenance).expect("Should fit");
+        assert_eq!(fitted, Fitted { width: 200, height: 100, encoding: png, bytes: encoded.len() as u64 });
+
+        // A tight one falls back to JPEG, lower quality and finally a smaller image
+        let (encoded, fitted) = fit_to_budget(&image, &png, 8_000, &provenance).expect("Should fit");
+        assert!(encoded.len() <= 8_000);
+        assert_eq!(fitted.encoding.format, OutputFormat::Jpeg);
+        assert_eq!(fitted.encoding.quality, MIN_QUALITY);
+        assert!(fitted.width < 200 && fitted.width.abs_diff(2 * fitted.height) <= 1, "{}", fitted);
+        assert!(fitted.to_string().starts_with(&format!("{}x{} JPG at quality 50", fitted.width, fitted.height)));
+
+        // An impossible one reports the smallest attempt
+        let result = fit_to_budget(&image, &png, 100, &provenance);
+        assert!(matches!(result, Err(Error::OverBudget { max_bytes: 100, smallest }) if smallest > 100));
+    }
+}
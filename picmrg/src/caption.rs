@@ -0,0 +1,190 @@
+use chrono::{DateTime, Local};
+use image::{Rgba, RgbaImage};
+use std::path::Path;
+use crate::layout::{Arrangement, Placement};
+use crate::text;
+
+/// Smallest and largest caption font size, in pixels
+const MIN_FONT_SIZE: f32 = 12.0;
+const MAX_FONT_SIZE: f32 = 48.0;
+/// Tile width per pixel of caption font size
+const TILE_WIDTH_PER_FONT_PIXEL: f32 = 40.0;
+/// Strip behind overlaid captions, so they are readable on any image
+const OVERLAY_STRIP_COLOR: Rgba<u8> = Rgba([0, 0, 0, 160]);
+/// Separator between the parts of a caption
+const SEPARATOR: &str = " · ";
+
+/// Where captions are drawn relative to their image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaptionPosition {
+    /// In space reserved below the image
+    #[default]
+    Below,
+    /// On a translucent strip over the bottom of the image
+    Overlay,
+}
+
+/// Which details the caption of every image shows; none by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Captions {
+    pub index: bool,     // Position in the merge, starting at 1
+    pub file_name: bool,
+    pub date: bool,      // Creation (or modification) time of the source file
+    pub position: CaptionPosition,
+}
+
+impl Captions {
+    /// Whether any detail is shown
+    pub fn is_enabled(&self) -> bool {
+        self.index || self.file_name || self.date
+    }
+
+    /// Caption of the image at `index` (from 0), with file details if the image came from a file
+    pub fn text(&self, index: usize, file: Option<&Path>) -> String {
+        let mut parts = Vec::new();
+        if self.index {
+            parts.push(format!("{}.", index + 1));
+        }
+        if let Some(file) = file {
+            if self.file_name
+                && let Some(name) = file.file_name()
+            {
+                parts.push(name.to_string_lossy().into_owned());
+            }
+            if self.date
+                && let Some(date) = file_date(file)
+            {
+                parts.push(date.format("%Y-%m-%d %H:%M").to_string());
+            }
+        }
+
+        // The index reads as a prefix rather than a separate part
+        match parts.split_first() {
+            Some((first, rest)) if self.index && !rest.is_empty() => format!("{} {}", first, rest.join(SEPARATOR)),
+            _ => parts.join(SEPARATOR),
+        }
+    }
+
+    /// Pixels to reserve below every image of an arrangement for its caption
+    pub fn space_below(&self, arrangement: &Arrangement) -> u32 {
+        if !self.is_enabled() || self.position != CaptionPosition::Below {
+            return 0;
+        }
+        band_height(below_font_size(arrangement))
+    }
+}
+
+/// Font size of captions for an image of the given width
+fn font_size(tile_width: u32) -> f32 {
+    (tile_width as f32 / TILE_WIDTH_PER_FONT_PIXEL).clamp(MIN_FONT_SIZE, MAX_FONT_SIZE)
+}
+
+/// Common font size of captions below the images, so every caption band has the same height
+fn below_font_size(arrangement: &Arrangement) -> f32 {
+    let mut widths: Vec<u32> = arrangement.placements.iter().map(|placement| placement.width).collect();
+    widths.sort_unstable();
+    font_size(widths.get(widths.len() / 2).copied().unwrap_or(0))
+}
+
+/// Height of a caption line with padding above and below
+fn band_height(size: f32) -> u32 {
+    text::line_height(size) + 2 * padding(size)
+}
+
+/// Space between a caption and the edges of its band
+fn padding(size: f32) -> u32 {
+    (size / 4.0).ceil() as u32
+}
+
+/// Draw one caption per image; `tiles` are the images' rectangles on the canvas and `below` the
+/// offset of the caption band under each of them
+pub fn draw_captions(
+    canvas: &mut RgbaImage,
+    arrangement: &Arrangement,
+    tiles: &[Placement],
+    below: u32,
+    texts: &[String],
+    captions: &Captions,
+    background: Rgba<u8>,
+) {
+    let below_size = below_font_size(arrangement);
+
+    for (tile, caption) in tiles.iter().zip(texts) {
+        match captions.position {
+            CaptionPosition::Below => {
+                let fitted = text::fit_text(caption, below_size, tile.width);
+                let x = tile.x + (tile.width - text::text_width(&fitted, below_size)) / 2;
+                let y = tile.y + tile.height + below + padding(below_size);
+                text::draw_text(canvas, &fitted, below_size, x as i64, y as i64, text::contrasting_color(background));
+            }
+            CaptionPosition::Overlay => {
+                let size = font_size(tile.width);
+                let strip_height = band_height(size).min(tile.height);
+                let strip = Placement { y: tile.y + tile.height - strip_height, height: strip_height, ..*tile };
+                crate::compositor::fill_rect(canvas, &strip, OVERLAY_STRIP_COLOR);
+
+                let fitted = text::fit_text(caption, size, tile.width.saturating_sub(2 * padding(size)));
+                let x = tile.x + (tile.width - text::text_width(&fitted, size)) / 2;
+                let y = strip.y + padding(size);
+                text::draw_text(canvas, &fitted, size, x as i64, y as i64, Rgba([255, 255, 255, 255]));
+            }
+        }
+    }
+}
+
+/// Creation time of a file, or its modification time where creation times are not recorded
+pub fn file_date(path: &Path) -> Option<DateTime<Local>> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let file_time = metadata.created().or_else(|_| metadata.modified()).ok()?;
+    Some(file_time.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::test_utils::{setup_test_data_for_test, cleanup_test_data_for_test};
+
+    #[test]
+    fn test_caption_text() {
+        let file = PathBuf::from("no-such-dir/shot.png");
+        let captions = Captions { index: true, file_name: true, ..Captions::default() };
+        assert_eq!(captions.text(0, Some(&file)), "1. shot.png");
+        assert_eq!(captions.text(2, None), "3.");
+
+        let captions = Captions { file_name: true, ..Captions::default() };
+        assert_eq!(captions.text(0, Some(&file)), "shot.png");
+        assert!(!Captions::default().is_enabled());
+        assert_eq!(Captions::default().text(0, Some(&file)), "");
+    }
+
+    #[test]
+    fn test_caption_date() {
+        let test_root = setup_test_data_for_test("caption_date").expect("Failed to setup test data");
+
+        let file = Path::new(&test_root).join("vertical-images/red.png");
+        let captions = Captions { file_name: true, date: true, ..Captions::default() };
+        let expected_date = file_date(&file).expect("File should have a date").format("%Y-%m-%d %H:%M").to_string();
+        assert_eq!(captions.text(0, Some(&file)), format!("red.png · {}", expected_date));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_space_below() {
+        let arrangement = Arrangement {
+            width: 800,
+            height: 400,
+            placements: vec![Placement { x: 0, y: 0, width: 800, height: 400 }],
+        };
+        let below = Captions { index: true, ..Captions::default() };
+        let overlay = Captions { position: CaptionPosition::Overlay, ..below };
+
+        // 800 px wide tiles get 20 px text
+        assert_eq!(below.space_below(&arrangement), band_height(20.0));
+        assert_eq!(overlay.space_below(&arrangement), 0);
+        assert_eq!(Captions::default().space_below(&arrangement), 0);
+        assert_eq!(font_size(100), MIN_FONT_SIZE);
+        assert_eq!(font_size(10000), MAX_FONT_SIZE);
+    }
+}
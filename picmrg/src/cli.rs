@@ -0,0 +1,867 @@
+use clap::builder::{PossibleValue, PossibleValuesParser};
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use std::path::PathBuf;
+use std::sync::Arc;
+use image::Rgba;
+use picmrg::{
+    check_glob, Alignment, CaptionPosition, Captions, Grid, GroupSelection, Header, HeaderTitle, LayoutRegistry, MergeOptions, MergedFileMatching,
+    OutputFormat, PageSize, PdfSettings, PrintPage, Scaling, DEFAULT_MAX_DEPTH, DEFAULT_PDF_MARGIN, DEFAULT_PRINT_MARGIN,
+    DEFAULT_QUALITY, DEFAULT_WATERMARK_OPACITY,
+};
+
+/// Layout name that leaves the choice to picmrg
+const AUTO_LAYOUT: &str = "auto";
+
+/// Merge groups of images in subdirectories into single composite images
+#[derive(Debug, Parser)]
+#[command(name = "picmrg", version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Arguments for the default `merge` command when no subcommand is given
+    #[command(flatten)]
+    pub merge: MergeArgs,
+}
+
+impl Cli {
+    /// Parse the command line like `Parser::parse`, exiting with a usage error on combinations
+    /// of options that `check` rejects
+    pub fn parse_checked() -> Self {
+        Self::parse().check().unwrap_or_else(|e| e.exit())
+    }
+
+    /// Reject combinations of options that clap's argument rules cannot express
+    pub fn check(self) -> Result<Self, clap::Error> {
+        match &self.command {
+            Some(Command::Merge(args)) => args.check()?,
+            Some(_) => {}
+            None => self.merge.check()?,
+        }
+        Ok(self)
+    }
+
+    /// Resolve the command to run, falling back to `merge` when none was given
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Merge(Box::new(self.merge)))
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Merge the images in each subdirectory (default)
+    Merge(Box<MergeArgs>),
+    /// Show which subdirectories have been merged and whether the merges are current
+    Status(RootArgs),
+    /// Remove previously generated merged files
+    Clean(RootArgs),
+    /// Print dimensions, orientation and format of image files
+    Inspect(InspectArgs),
+    /// Check that every merged file exists, decodes and is newer than its sources
+    Verify(RootArgs),
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the man page (roff) to stdout
+    Man,
+}
+
+#[derive(Debug, Args)]
+pub struct RootArgs {
+    /// Directory to use as root path (default: current directory)
+    #[arg(value_name = "ROOT_PATH")]
+    pub root: Option<PathBuf>,
+
+    /// Treat every file named merged.png or merged-YY-MM-DD.png as a previous merge,
+    /// even without the embedded picmrg marker (picmrg 1.0 behaviour)
+    #[arg(long)]
+    pub legacy_names: bool,
+
+    /// Scan this many levels of subdirectories below the root for images (1: only its direct
+    /// subdirectories)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_DEPTH,
+        value_parser = parse_max_depth)]
+    pub max_depth: usize,
+
+    /// Which scanned directories with images are merged
+    #[arg(long, value_enum, value_name = "GROUPS", default_value_t = GroupsArg::Leaves)]
+    pub groups: GroupsArg,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct FilterArgs {
+    /// Only merge files whose name matches this glob (repeatable; a file matching any is merged)
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    pub include: Vec<String>,
+
+    /// Pass over files and directories whose name matches this glob (repeatable)
+    #[arg(long, value_name = "GLOB", value_parser = parse_glob)]
+    pub exclude: Vec<String>,
+}
+
+impl FilterArgs {
+    /// Add the include and exclude patterns to library options
+    pub fn apply(&self, options: MergeOptions) -> MergeOptions {
+        let options = self.include.iter().fold(options, |options, pattern| options.include(pattern));
+        self.exclude.iter().fold(options, |options, pattern| options.exclude(pattern))
+    }
+}
+
+/// Merge group selections selectable on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GroupsArg {
+    /// Only directories without subdirectories that have images (within --max-depth)
+    Leaves,
+    /// Every directory with images
+    All,
+}
+
+impl From<GroupsArg> for GroupSelection {
+    fn from(groups: GroupsArg) -> Self {
+        match groups {
+            GroupsArg::Leaves => GroupSelection::Leaves,
+            GroupsArg::All => GroupSelection::All,
+        }
+    }
+}
+
+impl RootArgs {
+    /// How previously generated merged files are recognised
+    pub fn matching(&self) -> MergedFileMatching {
+        if self.legacy_names {
+            MergedFileMatching::LegacyName
+        } else {
+            MergedFileMatching::Marker
+        }
+    }
+
+    /// Library options for commands that only scan
+    pub fn options(&self) -> MergeOptions {
+        let options = MergeOptions::new()
+            .matching(self.matching())
+            .max_depth(self.max_depth)
+            .groups(self.groups.into());
+        self.filter.apply(options)
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct MergeArgs {
+    #[command(flatten)]
+    pub root: RootArgs,
+
+    /// Report what would be merged and deleted without writing or removing any file
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Merge again even if the existing merged file is current
+    #[arg(short, long)]
+    pub force: bool,
+
+    /// How to place the images
+    #[arg(short, long, value_name = "NAME", default_value = AUTO_LAYOUT, value_parser = layout_parser())]
+    pub layout: String,
+
+    /// Number of grid columns (default: as close to square as possible); implies `--layout grid`
+    /// and cannot be combined with another layout
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub columns: Option<u32>,
+
+    /// Print how every layout scores for each directory and which one is used
+    #[arg(long)]
+    pub explain: bool,
+
+    /// Size the images are scaled to along the axis they share
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = ScalingArg::ToMax)]
+    pub scaling: ScalingArg,
+
+    /// Scale towards the largest image, but enlarge no image by more than FACTOR
+    #[arg(long, value_name = "FACTOR", conflicts_with = "scaling", value_parser = parse_upscale_factor)]
+    pub max_upscale: Option<f64>,
+
+    /// Where images smaller than the largest are placed across the direction they are lined up in
+    #[arg(long, value_enum, value_name = "POSITION", default_value_t = AlignArg::Center)]
+    pub align: AlignArg,
+
+    /// Colour of the canvas around smaller images: a name (transparent, white, black), #rrggbb or #rrggbbaa
+    #[arg(long, value_name = "COLOR", default_value = "transparent", value_parser = parse_color)]
+    pub background: Rgba<u8>,
+
+    /// Empty pixels between neighbouring images
+    #[arg(long, value_name = "PX", default_value_t = 0)]
+    pub gutter: u32,
+
+    /// Empty pixels around the composite
+    #[arg(long, value_name = "PX", default_value_t = 0)]
+    pub margin: u32,
+
+    /// Width of a frame drawn around every image
+    #[arg(long, value_name = "PX", default_value_t = 0)]
+    pub border: u32,
+
+    /// Colour of the frame drawn by --border
+    #[arg(long, value_name = "COLOR", default_value = "black", value_parser = parse_color)]
+    pub border_color: Rgba<u8>,
+
+    /// Drop a shadow this many pixels below and right of every image
+    #[arg(long, value_name = "PX", default_value_t = 0)]
+    pub shadow: u32,
+
+    /// Caption every image with these details, comma separated
+    #[arg(long, value_enum, value_name = "PARTS", value_delimiter = ',')]
+    pub caption: Vec<CaptionPart>,
+
+    /// Where captions are drawn
+    #[arg(long, value_enum, value_name = "POSITION", default_value_t = CaptionPositionArg::Below)]
+    pub caption_position: CaptionPositionArg,
+
+    /// Put a banner with these details above the composite, comma separated
+    #[arg(long, value_enum, value_name = "PARTS", value_delimiter = ',')]
+    pub header: Vec<HeaderPart>,
+
+    /// Add a line below the composite; {name}, {title} and {date} are replaced with the
+    /// directory name, the name without a leading date and today's date
+    #[arg(long, value_name = "TEMPLATE")]
+    pub footer: Option<String>,
+
+    /// Draw this text diagonally across the output, with the same placeholders as --footer
+    #[arg(long, value_name = "TEMPLATE")]
+    pub watermark: Option<String>,
+
+    /// Opacity of the watermark in percent
+    #[arg(long, value_name = "PERCENT", default_value_t = DEFAULT_WATERMARK_OPACITY,
+        value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub watermark_opacity: u8,
+
+    /// File format of the merged images; the extension follows the format
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = FormatArg::Png)]
+    pub format: FormatArg,
+
+    /// Page size of PDF output
+    #[arg(long, value_enum, value_name = "SIZE", default_value_t = PageSizeArg::A4)]
+    pub pdf_page_size: PageSizeArg,
+
+    /// Space between the page edges and the image in PDF output, in millimetres
+    #[arg(long, value_name = "MM", default_value_t = DEFAULT_PDF_MARGIN)]
+    pub pdf_margin: u32,
+
+    /// Add every source image at full resolution on a page of its own after the composite
+    #[arg(long)]
+    pub pdf_sources: bool,
+
+    /// Quality of lossy formats (jpeg, avif) and of the JPEG fallback of `--max-bytes`, from 1
+    /// (smallest file) to 100 (best image) [default: 85]; WebP is always lossless, so `--format webp`
+    /// takes no quality without `--max-bytes`
+    #[arg(long, value_name = "1-100", value_parser = clap::value_parser!(u8).range(1..=100))]
+    pub quality: Option<u8>,
+
+    /// Keep merged files at or below this size, e.g. 5MB, 500KB or 2MiB, by switching to JPEG,
+    /// lowering the quality and finally shrinking the image
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_bytes: Option<u64>,
+
+    /// Shrink merged images wider than this many pixels, keeping their aspect ratio
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_width: Option<u32>,
+
+    /// Shrink merged images taller than this many pixels, keeping their aspect ratio
+    #[arg(long, value_name = "PIXELS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_height: Option<u32>,
+
+    /// Shrink merged images with more pixels (width times height) than this, keeping their aspect ratio
+    #[arg(long, value_name = "COUNT", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_pixels: Option<u64>,
+
+    /// Split directories with more images than this into evenly sized groups, written as
+    /// numbered files (merged-YY-MM-DD-1.png, -2.png, ...)
+    #[arg(long, value_name = "N", value_parser = parse_max_per_output)]
+    pub max_per_output: Option<usize>,
+
+    /// Resolution recorded in merged PNG, JPEG and TIFF files, in dots per inch
+    /// [default with --print-page: 300]
+    #[arg(long, value_name = "DPI", value_parser = clap::value_parser!(u32).range(1..=65535))]
+    pub dpi: Option<u32>,
+
+    /// Lay merged images out to print on this paper at --dpi: shrink them to fit inside
+    /// --print-margin and centre them on a page of the paper's size (fit adds just the margin)
+    #[arg(long, value_enum, value_name = "SIZE")]
+    pub print_page: Option<PageSizeArg>,
+
+    /// Space between the paper edges and the composite with --print-page, in millimetres
+    #[arg(long, value_name = "MM", default_value_t = DEFAULT_PRINT_MARGIN)]
+    pub print_margin: u32,
+}
+
+/// Scaling policies selectable on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ScalingArg {
+    /// Enlarge every image to the largest one
+    ToMax,
+    /// Shrink every image to the smallest one
+    ToMin,
+    /// Scale every image to the median size
+    ToMedian,
+    /// Keep smaller images at their native size, centred beside the largest
+    NeverUpscale,
+    /// Keep every image at its native size, never resizing any
+    Native,
+}
+
+/// Cross-axis alignments selectable on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AlignArg {
+    /// Top edge side by side, left edge when stacked
+    Start,
+    /// Centred
+    Center,
+    /// Bottom edge side by side, right edge when stacked
+    End,
+}
+
+/// Accept an enlargement factor of at least 1
+fn parse_upscale_factor(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(factor) if factor >= 1.0 => Ok(factor),
+        Ok(_) => Err("the factor must be at least 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Accept a scan depth of at least 1
+fn parse_max_depth(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(depth) if depth >= 1 => Ok(depth),
+        Ok(_) => Err("the root's subdirectories are at depth 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Accept a valid glob pattern
+fn parse_glob(value: &str) -> Result<String, String> {
+    check_glob(value).map(|()| value.to_string()).map_err(|e| e.to_string())
+}
+
+/// Accept a number of images per merged file of at least 2
+fn parse_max_per_output(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count >= 2 => Ok(count),
+        Ok(_) => Err("at least 2 images are needed per merged file".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Accept a file size in bytes, with an optional decimal (KB, MB, GB) or binary (KiB, MiB, GiB) unit
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value.find(|character: char| !(character.is_ascii_digit() || character == '.')).unwrap_or(value.len());
+    let (number, unit) = (&value[..split], value[split..].trim());
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => return Err(format!("unknown size unit '{}'; use B, KB, MB, GB, KiB, MiB or GiB", unit)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("'{}' is not a size such as 5MB", value))?;
+    let bytes = (number * multiplier as f64).round();
+    if bytes < 1.0 {
+        return Err("the size must be at least 1 byte".to_string());
+    }
+    Ok(bytes as u64)
+}
+
+/// Details that can be shown in captions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CaptionPart {
+    /// Position in the merge, starting at 1
+    Index,
+    /// File name of the source image
+    Name,
+    /// Creation date and time of the source image
+    Date,
+}
+
+/// Details that can be shown in the banner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HeaderPart {
+    /// Directory name as it is
+    Name,
+    /// Directory name without a leading date (takes precedence over `name`)
+    Title,
+    /// Evidence date: creation date of the newest image
+    Date,
+    /// Number of merged images
+    Count,
+}
+
+/// Output formats selectable on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FormatArg {
+    /// Lossless PNG (default)
+    Png,
+    /// Lossy JPEG on a white background, much smaller for screenshots of photos and video
+    #[value(alias = "jpg")]
+    Jpeg,
+    /// Lossless WebP, usually smaller than PNG
+    Webp,
+    /// Lossless TIFF
+    #[value(alias = "tif")]
+    Tiff,
+    /// Lossy AVIF, smallest at a given quality but slow to encode
+    Avif,
+    /// PDF document with the composite on the first page, and the sources with --pdf-sources
+    Pdf,
+}
+
+impl From<FormatArg> for OutputFormat {
+    fn from(format: FormatArg) -> Self {
+        match format {
+            FormatArg::Png => OutputFormat::Png,
+            FormatArg::Jpeg => OutputFormat::Jpeg,
+            FormatArg::Webp => OutputFormat::Webp,
+            FormatArg::Tiff => OutputFormat::Tiff,
+            FormatArg::Avif => OutputFormat::Avif,
+            FormatArg::Pdf => OutputFormat::Pdf,
+        }
+    }
+}
+
+/// PDF and print page sizes selectable on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PageSizeArg {
+    /// 210 x 297 mm, landscape for wide images
+    A4,
+    /// 8.5 x 11 inches, landscape for wide images
+    Letter,
+    /// Each page sized to its image
+    Fit,
+}
+
+impl From<PageSizeArg> for PageSize {
+    fn from(page_size: PageSizeArg) -> Self {
+        match page_size {
+            PageSizeArg::A4 => PageSize::A4,
+            PageSizeArg::Letter => PageSize::Letter,
+            PageSizeArg::Fit => PageSize::Fit,
+        }
+    }
+}
+
+/// Caption positions selectable on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CaptionPositionArg {
+    /// In a band below every image
+    Below,
+    /// On a translucent strip over the bottom of every image
+    Overlay,
+}
+
+/// Accept a colour name or hex colour
+fn parse_color(value: &str) -> Result<Rgba<u8>, String> {
+    picmrg::parse_color(value).ok_or_else(|| format!("'{}' is not a colour name or #rrggbb[aa] value", value))
+}
+
+impl MergeArgs {
+    /// Reject options that would be silently ignored
+    fn check(&self) -> Result<(), clap::Error> {
+        if self.columns.is_some() && !matches!(self.layout.as_str(), "grid" | AUTO_LAYOUT) {
+            let message = format!("--columns only applies to the grid layout, not to --layout {}", self.layout);
+            return Err(Cli::command().error(ErrorKind::ArgumentConflict, message));
+        }
+        if self.quality.is_some() && self.format == FormatArg::Webp && self.max_bytes.is_none() {
+            let message = "--quality has no effect on --format webp, which is always lossless";
+            return Err(Cli::command().error(ErrorKind::ArgumentConflict, message));
+        }
+        Ok(())
+    }
+
+    /// Library options for merging
+    pub fn options(&self) -> MergeOptions {
+        let options = self.root.options()
+            .force(self.force)
+            .scaling(self.scaling())
+            .alignment(self.alignment())
+            .background(self.background)
+            .gutter(self.gutter)
+            .margin(self.margin)
+            .border(self.border, self.border_color)
+            .shadow(self.shadow)
+            .captions(self.captions())
+            .header(self.header())
+            .format(self.format.into())
+            .quality(self.quality.unwrap_or(DEFAULT_QUALITY))
+            .pdf(self.pdf_settings());
+        let options = match &self.footer {
+            Some(template) => options.footer(template),
+            None => options,
+        };
+        let options = match &self.watermark {
+            Some(template) => options.watermark(template, self.watermark_opacity),
+            None => options,
+        };
+        let options = match self.max_bytes {
+            Some(max_bytes) => options.max_bytes(max_bytes),
+            None => options,
+        };
+        let options = match self.max_width {
+            Some(max_width) => options.max_width(max_width),
+            None => options,
+        };
+        let options = match self.max_height {
+            Some(max_height) => options.max_height(max_height),
+            None => options,
+        };
+        let options = match self.max_pixels {
+            Some(max_pixels) => options.max_pixels(max_pixels),
+            None => options,
+        };
+        let options = match self.max_per_output {
+            Some(max_per_output) => options.max_per_output(max_per_output),
+            None => options,
+        };
+        let options = match self.dpi {
+            Some(dpi) => options.dpi(dpi),
+            None => options,
+        };
+        let options = match self.print_page() {
+            Some(page) => options.print_page(page),
+            None => options,
+        };
+        match (self.layout.as_str(), self.columns) {
+            (_, Some(columns)) => options.layout(Arc::new(Grid::with_columns(columns))),
+            (name, None) => match LayoutRegistry::default().get(name) {
+                Some(layout) => options.layout(layout),
+                None => options, // auto
+            },
+        }
+    }
+
+    /// The scaling policy selected by `--scaling` or `--max-upscale`
+    pub fn scaling(&self) -> Scaling {
+        if let Some(factor) = self.max_upscale {
+            return Scaling::MaxUpscale(factor);
+        }
+        match self.scaling {
+            ScalingArg::ToMax => Scaling::ToMax,
+            ScalingArg::ToMin => Scaling::ToMin,
+            ScalingArg::ToMedian => Scaling::ToMedian,
+            ScalingArg::NeverUpscale => Scaling::NeverUpscale,
+            ScalingArg::Native => Scaling::Native,
+        }
+    }
+
+    /// The captions selected by `--caption` and `--caption-position`
+    pub fn captions(&self) -> Captions {
+        Captions {
+            index: self.caption.contains(&CaptionPart::Index),
+            file_name: self.caption.contains(&CaptionPart::Name),
+            date: self.caption.contains(&CaptionPart::Date),
+            position: match self.caption_position {
+                CaptionPositionArg::Below => CaptionPosition::Below,
+                CaptionPositionArg::Overlay => CaptionPosition::Overlay,
+            },
+        }
+    }
+
+    /// The banner selected by `--header`
+    pub fn header(&self) -> Header {
+        let title = if self.header.contains(&HeaderPart::Title) {
+            HeaderTitle::Parsed
+        } else if self.header.contains(&HeaderPart::Name) {
+            HeaderTitle::DirectoryName
+        } else {
+            HeaderTitle::None
+        };
+        Header {
+            title,
+            date: self.header.contains(&HeaderPart::Date),
+            count: self.header.contains(&HeaderPart::Count),
+        }
+    }
+
+    /// The PDF layout selected by `--pdf-page-size`, `--pdf-margin` and `--pdf-sources`
+    pub fn pdf_settings(&self) -> PdfSettings {
+        PdfSettings {
+            page_size: self.pdf_page_size.into(),
+            margin: self.pdf_margin,
+            include_sources: self.pdf_sources,
+        }
+    }
+
+    /// The paper selected by `--print-page` and `--print-margin`
+    pub fn print_page(&self) -> Option<PrintPage> {
+        self.print_page.map(|page_size| PrintPage { page_size: page_size.into(), margin: self.print_margin })
+    }
+
+    /// The alignment selected by `--align`
+    pub fn alignment(&self) -> Alignment {
+        match self.align {
+            AlignArg::Start => Alignment::Start,
+            AlignArg::Center => Alignment::Center,
+            AlignArg::End => Alignment::End,
+        }
+    }
+}
+
+/// Accept `auto` and the name of every registered layout
+fn layout_parser() -> PossibleValuesParser {
+    let auto = PossibleValue::new(AUTO_LAYOUT)
+        .help("Whichever layout scores best for the images (see --explain)");
+    let registered = LayoutRegistry::default()
+        .layouts()
+        .map(|layout| PossibleValue::new(layout.name()).help(layout.description()))
+        .collect::<Vec<_>>();
+    PossibleValuesParser::new(std::iter::once(auto).chain(registered))
+}
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    /// Image files or directories of images to inspect
+    #[arg(value_name = "PATH", required = true)]
+    pub paths: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+impl InspectArgs {
+    /// Library options selecting the images listed in directories
+    pub fn options(&self) -> MergeOptions {
+        self.filter.apply(MergeOptions::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use picmrg::Layout;
+
+    /// Parse a command line the way `main` does, including the checks clap cannot express
+    fn parse(args: &[&str]) -> Result<Command, clap::Error> {
+        Cli::try_parse_from(args).and_then(Cli::check).map(Cli::into_command)
+    }
+
+    /// Library options of a command line that parses
+    fn options(args: &[&str]) -> MergeOptions {
+        match parse(args).unwrap_or_else(|e| panic!("Failed to parse {:?}: {}", args, e)) {
+            Command::Merge(args) => args.options(),
+            Command::Status(args) | Command::Clean(args) | Command::Verify(args) => args.options(),
+            Command::Inspect(args) => args.options(),
+            other => panic!("Expected a command with options, got {:?}", other),
+        }
+    }
+
+    /// Options of a merge without any arguments
+    fn merge_defaults() -> MergeOptions {
+        root_defaults()
+            .border(0, Rgba([0, 0, 0, 255]))
+            .quality(DEFAULT_QUALITY)
+            .pdf(PdfSettings::default())
+    }
+
+    /// Options of a status, clean or verify without any arguments
+    fn root_defaults() -> MergeOptions {
+        MergeOptions::new()
+            .matching(MergedFileMatching::Marker)
+            .max_depth(DEFAULT_MAX_DEPTH)
+            .groups(GroupSelection::Leaves)
+    }
+
+    fn layout(name: &str) -> Arc<dyn Layout> {
+        LayoutRegistry::default().get(name).expect("Unknown layout")
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        let cases: &[(&[&str], bool)] = &[
+            (&["picmrg"], true),
+            (&["picmrg", "/path/to/images"], true),
+            (&["picmrg", "merge", "-n", "/tmp"], true),
+            // Layouts
+            (&["picmrg", "--layout", "vertical"], true),
+            (&["picmrg", "--layout", "spiral"], false),
+            (&["picmrg", "--columns", "3"], true),
+            (&["picmrg", "--columns", "0"], false),
+            (&["picmrg", "--columns", "3", "--layout", "grid"], true),
+            (&["picmrg", "--columns", "3", "--layout", "auto"], true),
+            (&["picmrg", "merge", "--columns", "3", "--layout", "horizontal"], false),
+            (&["picmrg", "--columns", "3", "--layout", "vertical"], false),
+            (&["picmrg", "--columns", "3", "--layout", "rows"], false),
+            // Scaling and styling
+            (&["picmrg", "--max-upscale", "1.5"], true),
+            (&["picmrg", "--max-upscale", "0.5"], false),
+            (&["picmrg", "--scaling", "to-min", "--max-upscale", "2"], false),
+            (&["picmrg", "--background", "#ffffff"], true),
+            (&["picmrg", "--background", "mauve-ish"], false),
+            (&["picmrg", "--gutter", "-1"], false),
+            (&["picmrg", "--caption", "size"], false),
+            (&["picmrg", "--header", "author"], false),
+            (&["picmrg", "--watermark-opacity", "101"], false),
+            // Formats and sizes
+            (&["picmrg", "--format", "gif"], false),
+            (&["picmrg", "--quality", "0"], false),
+            (&["picmrg", "--format", "webp", "--quality", "70"], false),
+            (&["picmrg", "--format", "webp", "--quality", "70", "--max-bytes", "1MB"], true),
+            (&["picmrg", "--pdf-page-size", "a3"], false),
+            (&["picmrg", "--print-page", "a3"], false),
+            (&["picmrg", "--dpi", "0"], false),
+            (&["picmrg", "--max-bytes", "5XB"], false),
+            (&["picmrg", "--max-width", "0"], false),
+            (&["picmrg", "--max-per-output", "1"], false),
+            (&["picmrg", "--max-per-output", "many"], false),
+            // Scanning
+            (&["picmrg", "--max-depth", "0"], false),
+            (&["picmrg", "--include", "shot[.png"], false),
+            // Subcommands
+            (&["picmrg", "status", "/tmp"], true),
+            (&["picmrg", "clean", "--legacy-names"], true),
+            (&["picmrg", "inspect"], false),
+            (&["picmrg", "completions", "bash"], true),
+            (&["picmrg", "completions", "tcsh"], false),
+        ];
+        for (args, accepted) in cases {
+            assert_eq!(parse(args).is_ok(), *accepted, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_merge_options() {
+        let cases: Vec<(&[&str], MergeOptions)> = vec![
+            (&["picmrg"], merge_defaults()),
+            (&["picmrg", "--force"], merge_defaults().force(true)),
+            (&["picmrg", "--legacy-names"], merge_defaults().matching(MergedFileMatching::LegacyName)),
+            (&["picmrg", "--layout", "vertical"], merge_defaults().layout(layout("vertical"))),
+            (&["picmrg", "--columns", "3"], merge_defaults().layout(Arc::new(Grid::with_columns(3)))),
+            (&["picmrg", "--columns", "3", "--layout", "grid"], merge_defaults().layout(Arc::new(Grid::with_columns(3)))),
+            (&["picmrg", "--scaling", "never-upscale"], merge_defaults().scaling(Scaling::NeverUpscale)),
+            (&["picmrg", "--max-upscale", "1.5"], merge_defaults().scaling(Scaling::MaxUpscale(1.5))),
+            (
+                &["picmrg", "--scaling", "native", "--align", "start"],
+                merge_defaults().scaling(Scaling::Native).alignment(Alignment::Start),
+            ),
+            (&["picmrg", "--background", "#ffffff"], merge_defaults().background(Rgba([255, 255, 255, 255]))),
+            (
+                &["picmrg", "--gutter", "8", "--margin", "16", "--border", "2", "--border-color", "#cccccc", "--shadow", "4"],
+                merge_defaults().gutter(8).margin(16).border(2, Rgba([204, 204, 204, 255])).shadow(4),
+            ),
+            (
+                &["picmrg", "--caption", "index,name", "--caption-position", "overlay"],
+                merge_defaults().captions(Captions {
+                    index: true,
+                    file_name: true,
+                    date: false,
+                    position: CaptionPosition::Overlay,
+                }),
+            ),
+            (
+                &["picmrg", "--header", "title,date,count"],
+                merge_defaults().header(Header { title: HeaderTitle::Parsed, date: true, count: true }),
+            ),
+            (
+                &["picmrg", "--header", "name"],
+                merge_defaults().header(Header { title: HeaderTitle::DirectoryName, ..Header::default() }),
+            ),
+            (
+                &["picmrg", "--footer", "J. Doe – submitted {date}", "--watermark", "J. Doe", "--watermark-opacity", "35"],
+                merge_defaults().footer("J. Doe – submitted {date}").watermark("J. Doe", 35),
+            ),
+            (
+                &["picmrg", "--watermark", "J. Doe"],
+                merge_defaults().watermark("J. Doe", DEFAULT_WATERMARK_OPACITY),
+            ),
+            (
+                &["picmrg", "--format", "jpg", "--quality", "70"],
+                merge_defaults().format(OutputFormat::Jpeg).quality(70),
+            ),
+            (
+                &["picmrg", "--format", "pdf", "--pdf-page-size", "letter", "--pdf-margin", "0", "--pdf-sources"],
+                merge_defaults().format(OutputFormat::Pdf).pdf(PdfSettings {
+                    page_size: PageSize::Letter,
+                    margin: 0,
+                    include_sources: true,
+                }),
+            ),
+            (&["picmrg", "--print-page", "a4"], merge_defaults().print_page(PrintPage::default())),
+            (
+                &["picmrg", "--print-page", "letter", "--print-margin", "5", "--dpi", "600"],
+                merge_defaults().print_page(PrintPage { page_size: PageSize::Letter, margin: 5 }).dpi(600),
+            ),
+            (&["picmrg", "--max-bytes", "5MB"], merge_defaults().max_bytes(5_000_000)),
+            (&["picmrg", "--max-bytes", "2MiB"], merge_defaults().max_bytes(2 * 1024 * 1024)),
+            (
+                &["picmrg", "--max-width", "16000", "--max-height", "9000", "--max-pixels", "50000000"],
+                merge_defaults().max_width(16000).max_height(9000).max_pixels(50_000_000),
+            ),
+            (&["picmrg", "--max-per-output", "6"], merge_defaults().max_per_output(6)),
+            (
+                &["picmrg", "--max-depth", "3", "--groups", "all"],
+                merge_defaults().max_depth(3).groups(GroupSelection::All),
+            ),
+            (
+                &["picmrg", "--include", "*.png", "--include", "*-annotated.*", "--exclude", "raw"],
+                merge_defaults().include("*.png").include("*-annotated.*").exclude("raw"),
+            ),
+            // Status, verify and clean scan the same tree and skip the same files as a merge
+            (&["picmrg", "clean", "--legacy-names"], root_defaults().matching(MergedFileMatching::LegacyName)),
+            (&["picmrg", "clean", "--max-depth", "4"], root_defaults().max_depth(4)),
+            (&["picmrg", "status", "--exclude", "*-draft*"], root_defaults().exclude("*-draft*")),
+            (&["picmrg", "verify", "--groups", "all"], root_defaults().groups(GroupSelection::All)),
+            // Inspect lists the same images of a directory
+            (&["picmrg", "inspect", "dir", "--include", "*.png"], MergeOptions::new().include("*.png")),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(format!("{:?}", options(args)), format!("{:?}", expected), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("5MB"), Ok(5_000_000));
+        assert_eq!(parse_size("500KB"), Ok(500_000));
+        assert_eq!(parse_size("2MiB"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1.5 mb"), Ok(1_500_000));
+        assert_eq!(parse_size("1234"), Ok(1234));
+        assert!(parse_size("5XB").is_err());
+        assert!(parse_size("MB").is_err());
+        assert!(parse_size("0").is_err());
+    }
+
+    #[test]
+    fn test_subcommands() {
+        // Without a subcommand, the legacy invocations merge
+        assert!(matches!(parse(&["picmrg"]), Ok(Command::Merge(args)) if args.root.root.is_none()));
+        assert!(matches!(
+            parse(&["picmrg", "/path/to/images"]),
+            Ok(Command::Merge(args)) if args.root.root == Some(PathBuf::from("/path/to/images"))
+        ));
+
+        // Flags of a merge that are not library options
+        assert!(matches!(parse(&["picmrg", "--dry-run"]), Ok(Command::Merge(args)) if args.dry_run));
+        assert!(matches!(
+            parse(&["picmrg", "merge", "--explain", "-n", "/tmp"]),
+            Ok(Command::Merge(args)) if args.explain && args.dry_run
+        ));
+
+        assert!(matches!(parse(&["picmrg", "status", "/tmp"]), Ok(Command::Status(_))));
+        assert!(matches!(parse(&["picmrg", "clean"]), Ok(Command::Clean(_))));
+        assert!(matches!(parse(&["picmrg", "verify"]), Ok(Command::Verify(_))));
+        assert!(matches!(parse(&["picmrg", "inspect", "a.png", "b.png"]), Ok(Command::Inspect(args)) if args.paths.len() == 2));
+        assert!(matches!(parse(&["picmrg", "man"]), Ok(Command::Man)));
+    }
+}
@@ -0,0 +1,257 @@
+use image::{DynamicImage, ImageBuffer, Pixel, Rgba, RgbaImage};
+use crate::caption::{self, Captions};
+use crate::header::Header;
+use crate::layout::{Arrangement, Placement};
+
+/// Colour of drop shadows, blended over the background
+const SHADOW_COLOR: Rgba<u8> = Rgba([0, 0, 0, 96]);
+
+/// How the canvas around and between the images is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Style {
+    pub background: Rgba<u8>,   // Fill of canvas not covered by an image
+    pub margin: u32,            // Empty space around the outermost tiles
+    pub border_width: u32,      // Frame drawn around every tile, outside the image
+    pub border_color: Rgba<u8>,
+    pub shadow: u32,            // Offset of a drop shadow below and right of every tile (0 for none)
+    pub captions: Captions,     // Details shown in the caption of every tile
+    pub header: Header,         // Banner above the composite
+}
+
+impl Default for Style {
+    /// A transparent background without margin, borders, shadows, captions or banner
+    fn default() -> Self {
+        Style {
+            background: Rgba([0, 0, 0, 0]),
+            margin: 0,
+            border_width: 0,
+            border_color: Rgba([0, 0, 0, 255]),
+            shadow: 0,
+            captions: Captions::default(),
+            header: Header::default(),
+        }
+    }
+}
+
+impl Style {
+    /// Space a tile's border and shadow take up between neighbouring images
+    pub fn tile_padding(&self) -> u32 {
+        2 * self.border_width + self.shadow
+    }
+
+    /// Size of the rendered canvas for an arrangement, including margins, borders and shadows
+    pub fn canvas_size(&self, arrangement: &Arrangement) -> (u32, u32) {
+        let extra = 2 * (self.margin + self.border_width) + self.shadow;
+        (arrangement.width + extra, arrangement.height + extra)
+    }
+}
+
+/// Render images onto a canvas at the positions and sizes given by an arrangement
+///
+/// The arrangement is drawn inside the margin; borders and shadows extend outside each placement,
+/// so the layout's spacing should leave room for `Style::tile_padding` between images.
+pub fn render(images: &[DynamicImage], arrangement: &Arrangement, style: &Style) -> DynamicImage {
+    render_captioned(images, arrangement, style, &[])
+}
+
+/// Render images like `render`, drawing one caption per image as configured in the style
+///
+/// Captions below the images go into the space the layout reserved with `caption_space`.
+pub fn render_captioned(
+    images: &[DynamicImage],
+    arrangement: &Arrangement,
+    style: &Style,
+    captions: &[String],
+) -> DynamicImage {
+    // Create the output image
+    let (width, height) = style.canvas_size(arrangement);
+    let mut output: RgbaImage = ImageBuffer::from_pixel(width, height, style.background);
+    let inset = style.margin + style.border_width;
+
+    for (image, placement) in images.iter().zip(&arrangement.placements) {
+        let (x, y) = (placement.x + inset, placement.y + inset);
+        let frame = Placement {
+            x: x - style.border_width,
+            y: y - style.border_width,
+            width: placement.width + 2 * style.border_width,
+            height: placement.height + 2 * style.border_width,
+        };
+        if style.shadow > 0 {
+            let shadow = Placement { x: frame.x + style.shadow, y: frame.y + style.shadow, ..frame };
+            fill_rect(&mut output, &shadow, SHADOW_COLOR);
+        }
+        if style.border_width > 0 {
+            fill_rect(&mut output, &frame, style.border_color);
+        }
+
+        let resized = resize_to(image, placement.width, placement.height);
+        image::imageops::replace(&mut output, &resized.to_rgba8(), x as i64, y as i64);
+    }
+
+    if style.captions.is_enabled() && !captions.is_empty() {
+        let tiles: Vec<Placement> = arrangement.placements.iter()
+            .map(|placement| Placement { x: placement.x + inset, y: placement.y + inset, ..*placement })
+            .collect();
+        let below = style.border_width + style.shadow;
+        caption::draw_captions(&mut output, arrangement, &tiles, below, captions, &style.captions, style.background);
+    }
+
+    DynamicImage::ImageRgba8(output)
+}
+
+/// Blend a colour over a rectangle of the canvas, clipped to its bounds
+pub(crate) fn fill_rect(canvas: &mut RgbaImage, rect: &Placement, color: Rgba<u8>) {
+    let right = (rect.x + rect.width).min(canvas.width());
+    let bottom = (rect.y + rect.height).min(canvas.height());
+    for y in rect.y..bottom {
+        for x in rect.x..right {
+            canvas.get_pixel_mut(x, y).blend(&color);
+        }
+    }
+}
+
+/// Resize an image to exactly the given size, unless it already has that size
+fn resize_to(image: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    if image.width() == width && image.height() == height {
+        return image.clone();
+    }
+
+    image.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+}
+
+/// Parse a colour given as a name (`transparent`, `white`, `black`) or as `#rrggbb` / `#rrggbbaa` hex
+pub fn parse_color(text: &str) -> Option<Rgba<u8>> {
+    match text.to_ascii_lowercase().as_str() {
+        "transparent" => return Some(Rgba([0, 0, 0, 0])),
+        "white" => return Some(Rgba([255, 255, 255, 255])),
+        "black" => return Some(Rgba([0, 0, 0, 255])),
+        _ => {}
+    }
+
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |index: usize| u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok();
+    let alpha = if hex.len() == 8 { channel(3)? } else { 255 };
+    Some(Rgba([channel(0)?, channel(1)?, channel(2)?, alpha]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caption::CaptionPosition;
+    use crate::test_utils::generate_test_image;
+
+    #[test]
+    fn test_render_places_images() {
+        let images = vec![
+            generate_test_image(10, 20, [255, 0, 0]),  // Red
+            generate_test_image(30, 20, [0, 255, 0]),  // Green
+        ];
+        let arrangement = Arrangement {
+            width: 50,
+            height: 40,
+            placements: vec![
+                Placement { x: 0, y: 0, width: 10, height: 20 },
+                Placement { x: 10, y: 20, width: 40, height: 20 },
+            ],
+        };
+
+        let rendered = render(&images, &arrangement, &Style::default()).to_rgba8();
+        assert_eq!(rendered.dimensions(), (50, 40));
+        assert_eq!(rendered.get_pixel(5, 10).0, [255, 0, 0, 255]);
+        assert_eq!(rendered.get_pixel(49, 39).0, [0, 255, 0, 255]);
+        // Uncovered canvas stays transparent
+        assert_eq!(rendered.get_pixel(40, 5).0, [0, 0, 0, 0]);
+
+        // ... or takes the background colour
+        let style = Style { background: Rgba([255, 255, 255, 255]), ..Style::default() };
+        let rendered = render(&images, &arrangement, &style).to_rgba8();
+        assert_eq!(rendered.get_pixel(40, 5).0, [255, 255, 255, 255]);
+        assert_eq!(rendered.get_pixel(5, 10).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn test_render_margin_border_and_shadow() {
+        let images = vec![generate_test_image(10, 10, [255, 0, 0])];
+        let arrangement = Arrangement {
+            width: 10,
+            height: 10,
+            placements: vec![Placement { x: 0, y: 0, width: 10, height: 10 }],
+        };
+        let style = Style {
+            background: Rgba([255, 255, 255, 255]),
+            margin: 5,
+            border_width: 2,
+            border_color: Rgba([0, 0, 255, 255]),
+            shadow: 3,
+            ..Style::default()
+        };
+        assert_eq!(style.tile_padding(), 7);
+
+        let rendered = render(&images, &arrangement, &style).to_rgba8();
+        // 5 margin + 2 border + 10 image + 2 border + 3 shadow + 5 margin
+        assert_eq!(rendered.dimensions(), (27, 27));
+        assert_eq!(rendered.get_pixel(2, 2).0, [255, 255, 255, 255]);
+        assert_eq!(rendered.get_pixel(6, 10).0, [0, 0, 255, 255]);
+        assert_eq!(rendered.get_pixel(10, 10).0, [255, 0, 0, 255]);
+        // Shadow below the bottom right corner of the border, darker than the background
+        let shadow = rendered.get_pixel(20, 20).0;
+        assert!(shadow[0] < 255 && shadow[0] == shadow[1] && shadow[3] == 255, "{:?}", shadow);
+        assert_eq!(rendered.get_pixel(23, 23).0, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_render_captions() {
+        let images = vec![generate_test_image(400, 200, [255, 255, 255])];
+        let captions = vec!["1. shot.png".to_string()];
+        let below = Captions { index: true, ..Captions::default() };
+
+        // Below the image, in the space the layout reserved
+        let arrangement = Arrangement {
+            width: 400,
+            height: 240,
+            placements: vec![Placement { x: 0, y: 0, width: 400, height: 200 }],
+        };
+        let style = Style { background: Rgba([255, 255, 255, 255]), captions: below, ..Style::default() };
+        let rendered = render_captioned(&images, &arrangement, &style, &captions).to_rgba8();
+        assert!((0..200).all(|y| (0..400).all(|x| rendered.get_pixel(x, y).0 == [255, 255, 255, 255])));
+        assert!((200..240).any(|y| (0..400).any(|x| rendered.get_pixel(x, y).0[0] < 128)), "No caption below the image");
+
+        // Overlaid on a dark strip at the bottom of the image
+        let arrangement = Arrangement { height: 200, ..arrangement };
+        let style = Style { captions: Captions { position: CaptionPosition::Overlay, ..below }, ..style };
+        let rendered = render_captioned(&images, &arrangement, &style, &captions).to_rgba8();
+        assert_eq!(rendered.get_pixel(0, 0).0, [255, 255, 255, 255]);
+        assert!(rendered.get_pixel(0, 199).0[0] < 128, "No strip behind the caption");
+
+        // Without caption texts nothing is drawn
+        let rendered = render(&images, &arrangement, &style).to_rgba8();
+        assert_eq!(rendered.get_pixel(0, 199).0, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("white"), Some(Rgba([255, 255, 255, 255])));
+        assert_eq!(parse_color("Transparent"), Some(Rgba([0, 0, 0, 0])));
+        assert_eq!(parse_color("#ff8000"), Some(Rgba([255, 128, 0, 255])));
+        assert_eq!(parse_color("ff800080"), Some(Rgba([255, 128, 0, 128])));
+        assert_eq!(parse_color("#ff80"), None);
+        assert_eq!(parse_color("#gg8000"), None);
+        assert_eq!(parse_color("#ü12345"), None);
+    }
+
+    #[test]
+    fn test_resize_to() {
+        let image = generate_test_image(100, 200, [255, 0, 0]);
+
+        // Test resizing to same size (should return clone)
+        let same = resize_to(&image, 100, 200);
+        assert_eq!((same.width(), same.height()), (100, 200));
+
+        // Test resizing to a different size
+        let resized = resize_to(&image, 200, 400);
+        assert_eq!((resized.width(), resized.height()), (200, 400));
+    }
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors returned while scanning directories and merging images
+#[derive(Debug)]
+pub enum Error {
+    /// The directory contains no image files at all
+    NoImages,
+    /// Fewer images than a merge needs
+    TooFewImages { found: usize },
+    /// None of the image files could be decoded
+    NoDecodableImages,
+    /// A file system operation failed
+    Io(io::Error),
+    /// An image file could not be decoded
+    Decode { path: PathBuf, source: image::ImageError },
+    /// The merged image could not be encoded
+    Encode(Box<dyn std::error::Error + Send + Sync>),
+    /// An image exceeds the decoder's or encoder's size limits
+    Limits { path: PathBuf, source: image::ImageError },
+    /// A file that picmrg did not create is in the way of the output
+    ForeignOutput { path: PathBuf },
+    /// The merged image could not be made small enough for the size budget
+    OverBudget { max_bytes: u64, smallest: u64 },
+    /// An include or exclude pattern is not a valid glob
+    InvalidGlob { pattern: String, message: String },
+    /// A .picmrgignore file could not be read or has invalid rules
+    InvalidIgnoreFile { path: PathBuf, message: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoImages => write!(f, "No image files to merge"),
+            Error::TooFewImages { found } => {
+                if *found == 1 {
+                    write!(f, "Only one image file found, skipping merge")
+                } else {
+                    write!(f, "Only {} image files found, skipping merge", found)
+                }
+            }
+            Error::NoDecodableImages => write!(f, "No valid images could be loaded"),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Decode { path, source } => write!(f, "Failed to load {}: {}", path.display(), source),
+            Error::Encode(e) => write!(f, "Failed to encode merged image: {}", e),
+            Error::Limits { path, source } => write!(f, "{} is too large: {}", path.display(), source),
+            Error::ForeignOutput { path } => write!(f, "{} exists and was not created by picmrg", path.display()),
+            Error::OverBudget { max_bytes, smallest } => write!(
+                f,
+                "Cannot fit the merged image into {} bytes; the smallest attempt was {} bytes",
+                max_bytes, smallest
+            ),
+            Error::InvalidGlob { pattern, message } => write!(f, "Invalid pattern '{}': {}", pattern, message),
+            Error::InvalidIgnoreFile { path, message } => write!(f, "Invalid {}: {}", path.display(), message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Decode { source, .. } | Error::Limits { source, .. } => Some(source),
+            Error::Encode(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(e: png::EncodingError) -> Self {
+        match e {
+            png::EncodingError::IoError(e) => Error::Io(e),
+            other => Error::Encode(Box::new(other)),
+        }
+    }
+}
+
+impl From<tiff::TiffError> for Error {
+    fn from(e: tiff::TiffError) -> Self {
+        match e {
+            tiff::TiffError::IoError(e) => Error::Io(e),
+            other => Error::Encode(Box::new(other)),
+        }
+    }
+}
+
+impl Error {
+    /// Classify an error from decoding the image at `path`
+    pub fn from_image(path: PathBuf, source: image::ImageError) -> Self {
+        match source {
+            image::ImageError::Limits(_) => Error::Limits { path, source },
+            source => Error::Decode { path, source },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        assert_eq!(Error::NoImages.to_string(), "No image files to merge");
+        assert!(Error::TooFewImages { found: 1 }.to_string().contains("Only one image file"));
+        let foreign = Error::ForeignOutput { path: PathBuf::from("merged.png") }.to_string();
+        assert!(foreign.contains("merged.png") && !foreign.contains("--"), "{}", foreign);
+        assert!(Error::OverBudget { max_bytes: 1000, smallest: 2500 }.to_string().contains("2500 bytes"));
+    }
+
+    #[test]
+    fn test_error_from_image() {
+        let limits = image::ImageError::Limits(image::error::LimitError::from_kind(
+            image::error::LimitErrorKind::DimensionError,
+        ));
+        assert!(matches!(Error::from_image(PathBuf::from("big.png"), limits), Error::Limits { .. }));
+
+        let io = image::ImageError::IoError(io::Error::new(io::ErrorKind::NotFound, "missing"));
+        assert!(matches!(Error::from_image(PathBuf::from("gone.png"), io), Error::Decode { .. }));
+    }
+}
@@ -0,0 +1,184 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use ignore::Match;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::Error;
+
+/// Name of the files holding gitignore-style rules for their directory and everything below it
+pub const IGNORE_FILE_NAME: &str = ".picmrgignore";
+
+/// Glob patterns selecting files and directories by name; nothing is filtered by default
+///
+/// Patterns such as `*-annotated.*` or `raw` match a whole name; `*` never crosses a `/`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileFilter {
+    pub include: Vec<String>, // If any are given, only files whose name matches one of them are merged
+    pub exclude: Vec<String>, // Files and directories whose name matches one of them are passed over
+}
+
+impl FileFilter {
+    /// Compile the patterns for matching
+    pub(crate) fn compile(&self) -> Result<CompiledFilter, Error> {
+        let include = match self.include.is_empty() {
+            true => None,
+            false => Some(glob_set(&self.include)?),
+        };
+        Ok(CompiledFilter { include, exclude: glob_set(&self.exclude)? })
+    }
+}
+
+/// Check that a pattern is a valid glob, as `FileFilter` patterns must be
+pub fn check_glob(pattern: &str) -> Result<(), Error> {
+    glob_set(&[pattern.to_string()]).map(|_| ())
+}
+
+/// Build a set matching any of the patterns
+fn glob_set(patterns: &[String]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| Error::InvalidGlob { pattern: pattern.clone(), message: e.kind().to_string() })?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|e| Error::InvalidGlob { pattern: patterns.join(", "), message: e.to_string() })
+}
+
+/// A `FileFilter` ready for matching names
+#[derive(Debug, Clone)]
+pub(crate) struct CompiledFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl CompiledFilter {
+    /// Whether a file with this name may be merged
+    pub(crate) fn accepts_file(&self, name: &OsStr) -> bool {
+        !self.exclude.is_match(name) && self.include.as_ref().is_none_or(|include| include.is_match(name))
+    }
+
+    /// Whether a directory with this name is scanned
+    pub(crate) fn accepts_directory(&self, name: &OsStr) -> bool {
+        !self.exclude.is_match(name)
+    }
+}
+
+/// The `.picmrgignore` rules that apply in one directory: its own and those of every directory
+/// above it, shallowest first
+#[derive(Debug, Clone)]
+pub(crate) struct IgnoreRules {
+    directory: PathBuf,
+    files: Vec<Gitignore>,
+}
+
+impl IgnoreRules {
+    /// The rules of a directory, read from it and all its ancestors
+    pub(crate) fn for_directory(directory: &Path) -> Result<Self, Error> {
+        let directory = fs::canonicalize(directory)?;
+        let mut files = Vec::new();
+        for ancestor in directory.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            files.extend(read_ignore_file(ancestor)?);
+        }
+        Ok(IgnoreRules { directory, files })
+    }
+
+    /// The rules of a subdirectory: these and its own, if it has an ignore file
+    pub(crate) fn enter(&self, name: &OsStr) -> Result<Self, Error> {
+        let directory = self.directory.join(name);
+        let mut files = self.files.clone();
+        files.extend(read_ignore_file(&directory)?);
+        Ok(IgnoreRules { directory, files })
+    }
+
+    /// Whether the file or subdirectory with this name is ignored
+    ///
+    /// The deepest ignore file with a matching rule decides, and within a file the last matching
+    /// line, so `!pattern` lines re-include what an earlier line or a file further up ignores.
+    pub(crate) fn is_ignored(&self, name: &OsStr, is_dir: bool) -> bool {
+        let path = self.directory.join(name);
+        for file in self.files.iter().rev() {
+            match file.matched_path_or_any_parents(&path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+/// Read the ignore file of a directory, if it has one
+fn read_ignore_file(directory: &Path) -> Result<Option<Gitignore>, Error> {
+    let path = directory.join(IGNORE_FILE_NAME);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    match Gitignore::new(&path) {
+        (gitignore, None) => Ok(Some(gitignore)),
+        // The path is part of the error already; keep the line number and the problem
+        (_, Some(ignore::Error::WithPath { err, .. })) => Err(Error::InvalidIgnoreFile { path, message: err.to_string() }),
+        (_, Some(e)) => Err(Error::InvalidIgnoreFile { path, message: e.to_string() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{cleanup_test_data_for_test, setup_test_data_for_test};
+
+    #[test]
+    fn test_file_filter() {
+        let name = |name: &str| OsStr::new(name).to_os_string();
+
+        let filter = FileFilter::default().compile().unwrap();
+        assert!(filter.accepts_file(&name("shot.png")) && filter.accepts_directory(&name("raw")));
+
+        let filter = FileFilter {
+            include: vec!["*-annotated.*".to_string(), "cert*".to_string()],
+            exclude: vec!["*-draft*".to_string(), "raw".to_string()],
+        }.compile().unwrap();
+        assert!(filter.accepts_file(&name("shot-annotated.png")));
+        assert!(filter.accepts_file(&name("cert.jpg")));
+        assert!(!filter.accepts_file(&name("shot.png")), "Not included");
+        assert!(!filter.accepts_file(&name("shot-draft-annotated.png")), "Excluded");
+        assert!(!filter.accepts_directory(&name("raw")));
+        assert!(filter.accepts_directory(&name("2024-01-15")), "Include only selects files");
+
+        assert!(matches!(check_glob("shot[.png"), Err(Error::InvalidGlob { .. })));
+        assert!(check_glob("*.{png,jpg}").is_ok());
+    }
+
+    #[test]
+    fn test_ignore_rules() {
+        let test_root = setup_test_data_for_test("ignore_rules").expect("Failed to setup test data");
+        let root = Path::new(&test_root);
+        let name = |name: &str| OsStr::new(name).to_os_string();
+
+        // Rules at the root apply below it; a deeper file re-includes one name
+        fs::write(root.join(IGNORE_FILE_NAME), "# originals\n*-raw.png\noriginals/\n").expect("Failed to write file");
+        fs::write(root.join("vertical-images").join(IGNORE_FILE_NAME), "!keep-raw.png\n").expect("Failed to write file");
+
+        let rules = IgnoreRules::for_directory(root).unwrap();
+        assert!(rules.is_ignored(&name("originals"), true));
+        assert!(!rules.is_ignored(&name("originals"), false), "Directory patterns only match directories");
+
+        let vertical = rules.enter(&name("vertical-images")).unwrap();
+        assert!(vertical.is_ignored(&name("shot-raw.png"), false));
+        assert!(!vertical.is_ignored(&name("keep-raw.png"), false));
+        assert!(!vertical.is_ignored(&name("shot.png"), false));
+
+        // Rules of the ancestors are found from the directory alone
+        let direct = IgnoreRules::for_directory(&root.join("vertical-images")).unwrap();
+        assert!(direct.is_ignored(&name("shot-raw.png"), false));
+        assert!(!direct.is_ignored(&name("keep-raw.png"), false));
+
+        // Invalid rules are reported with their file
+        fs::write(root.join("mixed-images").join(IGNORE_FILE_NAME), "[z-a].png\n").expect("Failed to write file");
+        assert!(matches!(rules.enter(&name("mixed-images")), Err(Error::InvalidIgnoreFile { .. })));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+}
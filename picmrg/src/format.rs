@@ -0,0 +1,109 @@
+use std::path::Path;
+
+/// Default quality of lossy formats, from 1 (smallest) to 100 (best)
+pub const DEFAULT_QUALITY: u8 = 85;
+
+/// File format of merged outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// Lossless, with transparency
+    #[default]
+    Png,
+    /// Lossy, without transparency; transparent areas become white
+    Jpeg,
+    /// Lossless, with transparency
+    Webp,
+    /// Lossless (LZW compressed), with transparency
+    Tiff,
+    /// Lossy, with transparency; much smaller than JPEG at the same quality but slow to encode
+    Avif,
+    /// Document with the composite on the first page, optionally followed by the sources
+    Pdf,
+}
+
+impl OutputFormat {
+    /// Every output format, in the order merged files are looked for
+    pub const ALL: [OutputFormat; 6] = [
+        OutputFormat::Png,
+        OutputFormat::Jpeg,
+        OutputFormat::Webp,
+        OutputFormat::Tiff,
+        OutputFormat::Avif,
+        OutputFormat::Pdf,
+    ];
+
+    /// Extension of output files in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Pdf => "pdf",
+        }
+    }
+
+    /// Format of a file judged by its extension, accepting common variants such as `jpeg` and `tif`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg),
+            "webp" => Some(OutputFormat::Webp),
+            "tif" | "tiff" => Some(OutputFormat::Tiff),
+            "avif" => Some(OutputFormat::Avif),
+            "pdf" => Some(OutputFormat::Pdf),
+            _ => None,
+        }
+    }
+
+    /// Whether the quality setting changes the output
+    pub fn is_lossy(self) -> bool {
+        matches!(self, OutputFormat::Jpeg | OutputFormat::Avif)
+    }
+
+    /// Whether picmrg can read images in this format back (AVIF and PDF can only be written)
+    pub fn is_decodable(self) -> bool {
+        !matches!(self, OutputFormat::Avif | OutputFormat::Pdf)
+    }
+}
+
+/// How merged images are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoding {
+    pub format: OutputFormat,
+    pub quality: u8,      // 1 to 100, used by lossy formats
+    pub dpi: Option<u32>, // Resolution recorded in PNG, JPEG and TIFF files, in dots per inch
+}
+
+impl Default for Encoding {
+    /// PNG without a resolution, with the default quality should a lossy format be selected
+    fn default() -> Self {
+        Encoding {
+            format: OutputFormat::default(),
+            quality: DEFAULT_QUALITY,
+            dpi: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_format_extensions() {
+        for format in OutputFormat::ALL {
+            let path = format!("merged-24-01-16.{}", format.extension());
+            assert_eq!(OutputFormat::from_path(Path::new(&path)), Some(format));
+        }
+        assert_eq!(OutputFormat::from_path(Path::new("a.JPEG")), Some(OutputFormat::Jpeg));
+        assert_eq!(OutputFormat::from_path(Path::new("a.tif")), Some(OutputFormat::Tiff));
+        assert_eq!(OutputFormat::from_path(Path::new("a.gif")), None);
+        assert_eq!(OutputFormat::from_path(Path::new("merged")), None);
+
+        assert!(OutputFormat::Jpeg.is_lossy() && !OutputFormat::Png.is_lossy());
+        assert!(!OutputFormat::Avif.is_decodable() && !OutputFormat::Pdf.is_decodable());
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::{DateTime, Local};
+use image::{DynamicImage, ImageBuffer, RgbaImage};
+use std::path::Path;
+use crate::text;
+
+/// Smallest and largest font size of the banner's title line, in pixels
+const MIN_TITLE_SIZE: f32 = 16.0;
+const MAX_TITLE_SIZE: f32 = 72.0;
+/// Canvas width per pixel of title font size
+const CANVAS_WIDTH_PER_FONT_PIXEL: f32 = 32.0;
+/// Size of the details line relative to the title line
+const DETAILS_SCALE: f32 = 0.6;
+/// Separator between the details of the banner
+const SEPARATOR: &str = " · ";
+
+/// Which name the banner shows as its title
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderTitle {
+    /// No title line
+    #[default]
+    None,
+    /// The directory name as it is
+    DirectoryName,
+    /// The directory name without a leading date, e.g. `ISC2 Webinar - Zero Trust`
+    /// for `2024-01-15 ISC2 Webinar - Zero Trust`
+    Parsed,
+}
+
+/// What the banner at the top of the composite shows; no banner by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Header {
+    pub title: HeaderTitle,
+    pub date: bool,  // Evidence date: the newest source's creation date
+    pub count: bool, // Number of merged images
+}
+
+impl Header {
+    /// Whether a banner is drawn
+    pub fn is_enabled(&self) -> bool {
+        self.title != HeaderTitle::None || self.date || self.count
+    }
+
+    /// Lines of the banner: the title, then the details, leaving out what is unknown
+    pub fn lines(&self, directory: Option<&Path>, date: Option<DateTime<Local>>, count: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        let name = directory.and_then(Path::file_name).map(|name| name.to_string_lossy().into_owned());
+        match (self.title, name) {
+            (HeaderTitle::DirectoryName, Some(name)) => lines.push(name),
+            (HeaderTitle::Parsed, Some(name)) => lines.push(parse_title(&name)),
+            _ => {}
+        }
+
+        let mut details = Vec::new();
+        if self.date
+            && let Some(date) = date
+        {
+            details.push(date.format("%Y-%m-%d").to_string());
+        }
+        if self.count {
+            details.push(if count == 1 { "1 image".to_string() } else { format!("{} images", count) });
+        }
+        if !details.is_empty() {
+            lines.push(details.join(SEPARATOR));
+        }
+        lines
+    }
+}
+
+/// Title of an activity from its directory name, without a leading date such as `2024-01-15`,
+/// `2024_01_15`, `20240115` or `24-01-15` and the separators after it
+///
+/// Names without a leading date, or with nothing but a date, are returned unchanged.
+pub fn parse_title(directory_name: &str) -> String {
+    let date_end = directory_name
+        .find(|character: char| !(character.is_ascii_digit() || matches!(character, '-' | '_' | '.')))
+        .unwrap_or(directory_name.len());
+    let digits = directory_name[..date_end].chars().filter(char::is_ascii_digit).count();
+    if !matches!(digits, 6 | 8) {
+        return directory_name.to_string();
+    }
+
+    let title = directory_name[date_end..]
+        .trim_start_matches(|character: char| character.is_whitespace() || matches!(character, '-' | '_' | '–' | '—' | ':'))
+        .trim_end();
+    if title.is_empty() {
+        directory_name.to_string()
+    } else {
+        title.to_string()
+    }
+}
+
+/// Font size of the title line on a canvas of the given width
+fn title_size(canvas_width: u32) -> f32 {
+    (canvas_width as f32 / CANVAS_WIDTH_PER_FONT_PIXEL).clamp(MIN_TITLE_SIZE, MAX_TITLE_SIZE)
+}
+
+/// Font sizes of the banner lines: the first is the title if there are two
+fn line_sizes(line_count: usize, canvas_width: u32) -> Vec<f32> {
+    let size = title_size(canvas_width);
+    match line_count {
+        0 => Vec::new(),
+        1 => vec![size],
+        _ => vec![size, (size * DETAILS_SCALE).max(text::line_height(12.0) as f32)],
+    }
+}
+
+/// Space between the banner's lines and edges
+fn padding(canvas_width: u32) -> u32 {
+    (title_size(canvas_width) / 2.0).ceil() as u32
+}
+
+/// Height of a banner with the given lines above a composite of the given width (0 without lines)
+pub fn height(lines: &[String], canvas_width: u32) -> u32 {
+    let sizes = line_sizes(lines.len(), canvas_width);
+    if sizes.is_empty() {
+        return 0;
+    }
+    let padding = padding(canvas_width);
+    sizes.iter().map(|size| text::line_height(*size)).sum::<u32>() + padding * (sizes.len() as u32 + 1)
+}
+
+/// Put a banner with the given lines on the style's background above a composite
+pub fn add_header(composite: DynamicImage, lines: &[String], background: image::Rgba<u8>) -> DynamicImage {
+    let banner_height = height(lines, composite.width());
+    if banner_height == 0 {
+        return composite;
+    }
+
+    let width = composite.width();
+    let mut output: RgbaImage = ImageBuffer::from_pixel(width, composite.height() + banner_height, background);
+    let padding = padding(width);
+    let color = text::contrasting_color(background);
+    let mut y = padding;
+    for (line, size) in lines.iter().zip(line_sizes(lines.len(), width)) {
+        let fitted = text::fit_text(line, size, width.saturating_sub(2 * padding));
+        let x = (width - text::text_width(&fitted, size)) / 2;
+        text::draw_text(&mut output, &fitted, size, x as i64, y as i64, color);
+        y += text::line_height(size) + padding;
+    }
+
+    image::imageops::replace(&mut output, &composite.to_rgba8(), 0, banner_height as i64);
+    DynamicImage::ImageRgba8(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use image::Rgba;
+    use crate::test_utils::generate_test_image;
+
+    #[test]
+    fn test_parse_title() {
+        assert_eq!(parse_title("2024-01-15 ISC2 Webinar - Zero Trust"), "ISC2 Webinar - Zero Trust");
+        assert_eq!(parse_title("2024_01_15-cloud-security"), "cloud-security");
+        assert_eq!(parse_title("20240115 – Keynote"), "Keynote");
+        assert_eq!(parse_title("24-01-15: Panel"), "Panel");
+        assert_eq!(parse_title("Zero Trust 2024"), "Zero Trust 2024");
+        assert_eq!(parse_title("2024 Congress"), "2024 Congress");
+        assert_eq!(parse_title("2024-01-15"), "2024-01-15");
+    }
+
+    #[test]
+    fn test_header_lines() {
+        let directory = Path::new("evidence/2024-01-15 ISC2 Webinar - Zero Trust");
+        let date = Local.with_ymd_and_hms(2024, 1, 16, 9, 30, 0).single();
+
+        let header = Header { title: HeaderTitle::Parsed, date: true, count: true };
+        assert_eq!(header.lines(Some(directory), date, 5), vec!["ISC2 Webinar - Zero Trust", "2024-01-16 · 5 images"]);
+
+        let header = Header { title: HeaderTitle::DirectoryName, ..Header::default() };
+        assert_eq!(header.lines(Some(directory), date, 5), vec!["2024-01-15 ISC2 Webinar - Zero Trust"]);
+
+        // Without a directory or date only the count is known
+        let header = Header { title: HeaderTitle::Parsed, date: true, count: true };
+        assert_eq!(header.lines(None, None, 1), vec!["1 image"]);
+        assert!(Header::default().lines(Some(directory), date, 5).is_empty());
+        assert!(!Header::default().is_enabled());
+    }
+
+    #[test]
+    fn test_add_header() {
+        let composite = generate_test_image(640, 100, [255, 0, 0]);
+        let lines = vec!["Zero Trust".to_string(), "2024-01-16 · 2 images".to_string()];
+        let banner_height = height(&lines, 640);
+        assert!(banner_height > height(&lines[..1], 640));
+        assert_eq!(height(&[], 640), 0);
+
+        let white = Rgba([255, 255, 255, 255]);
+        let output = add_header(composite.clone(), &lines, white).to_rgba8();
+        assert_eq!(output.dimensions(), (640, 100 + banner_height));
+        assert!((0..banner_height).any(|y| (0..640).any(|x| output.get_pixel(x, y).0[0] < 128)), "No text in the banner");
+        assert_eq!(output.get_pixel(0, banner_height).0, [255, 0, 0, 255]);
+
+        // Without lines the composite is returned as it is
+        assert_eq!(add_header(composite, &[], white).height(), 100);
+    }
+}
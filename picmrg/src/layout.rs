@@ -0,0 +1,749 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::scoring;
+
+/// Where one image is drawn on the canvas, in output pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Canvas size and the placement of every image, in input order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arrangement {
+    pub width: u32,
+    pub height: u32,
+    pub placements: Vec<Placement>,
+}
+
+/// How images are scaled towards a common size along the axis they share
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Scaling {
+    /// Scale every image to the largest one
+    #[default]
+    ToMax,
+    /// Scale every image to the smallest one
+    ToMin,
+    /// Scale every image to the median size
+    ToMedian,
+    /// Scale towards the largest image, but never enlarge any image
+    NeverUpscale,
+    /// Scale towards the largest image, but enlarge no image by more than this factor
+    MaxUpscale(f64),
+    /// Keep every image at its native size
+    Native,
+}
+
+impl Scaling {
+    /// Common size for images of the given sizes along the shared axis
+    pub fn target(&self, sizes: impl Iterator<Item = u32>) -> u32 {
+        let mut sizes: Vec<u32> = sizes.collect();
+        sizes.sort_unstable();
+        let target = match self {
+            Scaling::ToMin => sizes.first(),
+            Scaling::ToMedian => sizes.get(sizes.len() / 2),
+            Scaling::ToMax | Scaling::NeverUpscale | Scaling::MaxUpscale(_) | Scaling::Native => sizes.last(),
+        };
+        target.copied().unwrap_or(0)
+    }
+
+    /// Largest factor by which an image may be enlarged
+    pub fn max_factor(&self) -> f64 {
+        match self {
+            Scaling::NeverUpscale | Scaling::Native => 1.0,
+            Scaling::MaxUpscale(factor) => factor.max(1.0),
+            Scaling::ToMax | Scaling::ToMin | Scaling::ToMedian => f64::INFINITY,
+        }
+    }
+
+    /// Size an image of the given native size is scaled to when the target is `target`
+    pub fn limit(&self, native: u32, target: u32) -> u32 {
+        if *self == Scaling::Native {
+            return native;
+        }
+        let largest = native as f64 * self.max_factor();
+        if target as f64 > largest {
+            largest as u32
+        } else {
+            target
+        }
+    }
+}
+
+/// Where an image that is smaller than its slot is placed in it, across the direction images are lined up in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Alignment {
+    /// Top or left edge
+    Start,
+    /// Centred
+    #[default]
+    Center,
+    /// Bottom or right edge
+    End,
+}
+
+impl Alignment {
+    /// Offset of an image within a slot that leaves `free` pixels uncovered
+    pub fn offset(&self, free: u32) -> u32 {
+        match self {
+            Alignment::Start => 0,
+            Alignment::Center => free / 2,
+            Alignment::End => free,
+        }
+    }
+}
+
+/// Settings every layout applies when placing images
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LayoutSettings {
+    pub scaling: Scaling,
+    pub alignment: Alignment,
+    pub spacing: u32,                   // Pixels between neighbouring images
+    pub caption_space: u32,             // Pixels reserved below every image for its caption
+    pub page_aspect_ratio: Option<f64>, // Width / height of the portrait page the output is printed on
+}
+
+/// A strategy for placing images on a canvas
+pub trait Layout: fmt::Debug + Send + Sync {
+    /// Name used to select the layout, e.g. on the command line
+    fn name(&self) -> &'static str;
+
+    /// One-line description for help output
+    fn description(&self) -> &'static str;
+
+    /// Place images of the given (width, height) sizes, returning one placement per image
+    fn arrange(&self, dimensions: &[(u32, u32)], settings: &LayoutSettings) -> Arrangement;
+}
+
+/// Images side by side at a common height, vertically aligned if the scaling leaves some shorter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Horizontal;
+
+impl Layout for Horizontal {
+    fn name(&self) -> &'static str {
+        "horizontal"
+    }
+
+    fn description(&self) -> &'static str {
+        "Side by side, scaled to a common height"
+    }
+
+    fn arrange(&self, dimensions: &[(u32, u32)], settings: &LayoutSettings) -> Arrangement {
+        // Find the common height, then how far each image may be scaled towards it
+        let target_height = settings.scaling.target(dimensions.iter().map(|(_, height)| *height));
+        let heights: Vec<u32> = dimensions.iter()
+            .map(|(_, height)| settings.scaling.limit(*height, target_height))
+            .collect();
+        let images_height = heights.iter().copied().max().unwrap_or(0);
+        let canvas_height = if dimensions.is_empty() { 0 } else { images_height + settings.caption_space };
+
+        let mut placements = Vec::new();
+        let mut x_offset = 0;
+        for ((width, height), scaled_height) in dimensions.iter().zip(heights) {
+            let scaled_width = width_at_height(*width, *height, scaled_height);
+            let y = settings.alignment.offset(images_height - scaled_height);
+            placements.push(Placement { x: x_offset, y, width: scaled_width, height: scaled_height });
+            x_offset += scaled_width + settings.spacing;
+        }
+
+        Arrangement { width: x_offset.saturating_sub(settings.spacing), height: canvas_height, placements }
+    }
+}
+
+/// Images stacked top to bottom at a common width, horizontally aligned if the scaling leaves some narrower
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vertical;
+
+impl Layout for Vertical {
+    fn name(&self) -> &'static str {
+        "vertical"
+    }
+
+    fn description(&self) -> &'static str {
+        "Stacked top to bottom, scaled to a common width"
+    }
+
+    fn arrange(&self, dimensions: &[(u32, u32)], settings: &LayoutSettings) -> Arrangement {
+        // Find the common width, then how far each image may be scaled towards it
+        let target_width = settings.scaling.target(dimensions.iter().map(|(width, _)| *width));
+        let widths: Vec<u32> = dimensions.iter()
+            .map(|(width, _)| settings.scaling.limit(*width, target_width))
+            .collect();
+        let canvas_width = widths.iter().copied().max().unwrap_or(0);
+
+        let mut placements = Vec::new();
+        let mut y_offset = 0;
+        for ((width, height), scaled_width) in dimensions.iter().zip(widths) {
+            let scaled_height = height_at_width(*width, *height, scaled_width);
+            let x = settings.alignment.offset(canvas_width - scaled_width);
+            placements.push(Placement { x, y: y_offset, width: scaled_width, height: scaled_height });
+            y_offset += scaled_height + settings.caption_space + settings.spacing;
+        }
+
+        Arrangement { width: canvas_width, height: y_offset.saturating_sub(settings.spacing), placements }
+    }
+}
+
+/// Images in rows and columns of equally sized cells, each scaled to fit and aligned in its cell
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Grid {
+    columns: Option<u32>,
+}
+
+impl Grid {
+    /// A grid with as many columns as make it closest to square
+    pub fn new() -> Self {
+        Grid { columns: None }
+    }
+
+    /// A grid with a fixed number of columns (at least one)
+    pub fn with_columns(columns: u32) -> Self {
+        Grid { columns: Some(columns.max(1)) }
+    }
+
+    /// Number of columns used for `count` images
+    pub fn columns_for(&self, count: usize) -> u32 {
+        let count = count.max(1) as u32;
+        match self.columns {
+            Some(columns) => columns.min(count),
+            None => (count as f64).sqrt().ceil() as u32,
+        }
+    }
+}
+
+impl Layout for Grid {
+    fn name(&self) -> &'static str {
+        "grid"
+    }
+
+    fn description(&self) -> &'static str {
+        "Rows and columns of equal cells, each image centred in its cell"
+    }
+
+    fn arrange(&self, dimensions: &[(u32, u32)], settings: &LayoutSettings) -> Arrangement {
+        if dimensions.is_empty() {
+            return Arrangement { width: 0, height: 0, placements: Vec::new() };
+        }
+
+        // Every cell has the common width and the common height of the images
+        let cell_width = settings.scaling.target(dimensions.iter().map(|(width, _)| *width));
+        let cell_height = settings.scaling.target(dimensions.iter().map(|(_, height)| *height));
+        let columns = self.columns_for(dimensions.len());
+        let rows = (dimensions.len() as u32).div_ceil(columns);
+        let row_pitch = cell_height + settings.caption_space + settings.spacing;
+
+        let placements = dimensions.iter().enumerate().map(|(index, (width, height))| {
+            let (column, row) = (index as u32 % columns, index as u32 / columns);
+            let (mut scaled_width, mut scaled_height) = fit_within(*width, *height, cell_width, cell_height);
+            let limited_width = settings.scaling.limit(*width, scaled_width);
+            if limited_width < scaled_width {
+                scaled_width = limited_width;
+                scaled_height = height_at_width(*width, *height, limited_width);
+            }
+            Placement {
+                x: column * (cell_width + settings.spacing) + settings.alignment.offset(cell_width - scaled_width),
+                y: row * row_pitch + settings.alignment.offset(cell_height - scaled_height),
+                width: scaled_width,
+                height: scaled_height,
+            }
+        }).collect();
+
+        Arrangement {
+            width: columns * cell_width + (columns - 1) * settings.spacing,
+            height: rows * row_pitch - settings.spacing,
+            placements,
+        }
+    }
+}
+
+/// Rows of equal height that fill the target width, like a photo gallery, without cropping
+///
+/// Images keep their aspect ratios; a row is closed once it is at least as wide as the target at
+/// the common height, then scaled down to exactly the target width. The last row keeps the common
+/// height and is centred if it is not full, as is any row the scaling policy keeps from being
+/// enlarged to full width. With native scaling every image keeps its size and is aligned
+/// vertically within its row.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rows {
+    target_width: Option<u32>,
+}
+
+impl Rows {
+    /// Rows with a target width that makes the result close to square
+    pub fn new() -> Self {
+        Rows { target_width: None }
+    }
+
+    /// Rows filling the given width (at least one pixel)
+    pub fn with_target_width(target_width: u32) -> Self {
+        Rows { target_width: Some(target_width.max(1)) }
+    }
+}
+
+impl Layout for Rows {
+    fn name(&self) -> &'static str {
+        "rows"
+    }
+
+    fn description(&self) -> &'static str {
+        "Justified rows of equal height, keeping every aspect ratio"
+    }
+
+    fn arrange(&self, dimensions: &[(u32, u32)], settings: &LayoutSettings) -> Arrangement {
+        if dimensions.is_empty() {
+            return Arrangement { width: 0, height: 0, placements: Vec::new() };
+        }
+
+        let ideal_height = settings.scaling.target(dimensions.iter().map(|(_, height)| *height)).max(1);
+        let aspect_ratios: Vec<f64> = dimensions.iter()
+            .map(|(width, height)| *width as f64 / (*height).max(1) as f64)
+            .collect();
+        let target_width = self.target_width.unwrap_or_else(|| {
+            // Square: target width equals the total height of rows holding all images
+            let total_width = aspect_ratios.iter().sum::<f64>() * ideal_height as f64;
+            let widest = dimensions.iter().map(|(width, _)| *width).max().unwrap_or(0);
+            ((total_width * ideal_height as f64).sqrt() as u32).max(widest).max(1)
+        });
+
+        // Spacing between the images of a row of `count` images
+        let gaps = |count: usize| (count as u32).saturating_sub(1) * settings.spacing;
+
+        // Split into rows, each full row at least as wide as the target at the ideal height
+        let mut rows = Vec::new();
+        let mut row_start = 0;
+        let mut row_aspect_ratio = 0.0;
+        for (index, aspect_ratio) in aspect_ratios.iter().enumerate() {
+            row_aspect_ratio += aspect_ratio;
+            let row_width = row_aspect_ratio * ideal_height as f64 + gaps(index + 1 - row_start) as f64;
+            if row_width >= target_width as f64 {
+                rows.push((row_start..index + 1, true));
+                row_start = index + 1;
+                row_aspect_ratio = 0.0;
+            }
+        }
+        if row_start < aspect_ratios.len() {
+            rows.push((row_start..aspect_ratios.len(), false));
+        }
+
+        // Size the images of each row
+        let mut sized_rows = Vec::with_capacity(rows.len());
+        for (range, is_full) in rows {
+            let row_aspect_ratios = &aspect_ratios[range.clone()];
+            let images_width = target_width.saturating_sub(gaps(range.len()));
+            let justified_height = if is_full {
+                ((images_width as f64 / row_aspect_ratios.iter().sum::<f64>()) as u32).max(1)
+            } else {
+                ideal_height
+            };
+            if settings.scaling == Scaling::Native {
+                sized_rows.push(dimensions[range].to_vec());
+                continue;
+            }
+
+            // No image in the row may be enlarged beyond what the scaling policy allows
+            let row_height = dimensions[range].iter()
+                .map(|(_, height)| settings.scaling.limit(*height, justified_height).max(1))
+                .fold(justified_height, u32::min);
+
+            let mut widths: Vec<u32> = row_aspect_ratios.iter()
+                .map(|aspect_ratio| ((aspect_ratio * row_height as f64) as u32).max(1))
+                .collect();
+            if is_full && row_height == justified_height {
+                // Give the rounding remainder to the last image so the row ends flush
+                let remainder = images_width.saturating_sub(widths.iter().sum());
+                if let Some(last) = widths.last_mut() {
+                    *last += remainder;
+                }
+            }
+            sized_rows.push(widths.into_iter().map(|width| (width, row_height)).collect());
+        }
+
+        // Centre every row that is narrower than the widest
+        let row_width = |sizes: &Vec<(u32, u32)>| sizes.iter().map(|(width, _)| *width).sum::<u32>() + gaps(sizes.len());
+        let canvas_width = sized_rows.iter().map(row_width).max().unwrap_or(0);
+        let mut placements = Vec::with_capacity(dimensions.len());
+        let mut y_offset = 0;
+        for sizes in sized_rows {
+            let row_height = sizes.iter().map(|(_, height)| *height).max().unwrap_or(0);
+            let mut x_offset = (canvas_width - row_width(&sizes)) / 2;
+            for (width, height) in sizes {
+                let y = y_offset + settings.alignment.offset(row_height - height);
+                placements.push(Placement { x: x_offset, y, width, height });
+                x_offset += width + settings.spacing;
+            }
+            y_offset += row_height + settings.caption_space + settings.spacing;
+        }
+
+        Arrangement { width: canvas_width, height: y_offset.saturating_sub(settings.spacing), placements }
+    }
+}
+
+/// Choose the built-in layout with the lowest cost for images of the given sizes
+pub fn choose_layout(dimensions: &[(u32, u32)], settings: &LayoutSettings) -> Arc<dyn Layout> {
+    let scores = scoring::rank_layouts(&LayoutRegistry::default(), dimensions, settings);
+    match scores.into_iter().next() {
+        Some(best) => best.layout,
+        None => Arc::new(Vertical),
+    }
+}
+
+/// Layouts that can be selected by name
+#[derive(Clone)]
+pub struct LayoutRegistry {
+    layouts: Vec<Arc<dyn Layout>>,
+}
+
+impl LayoutRegistry {
+    /// A registry with no layouts
+    pub fn empty() -> Self {
+        LayoutRegistry { layouts: Vec::new() }
+    }
+
+    /// Add a layout, replacing any registered layout with the same name
+    pub fn register(&mut self, layout: Arc<dyn Layout>) {
+        self.layouts.retain(|existing| existing.name() != layout.name());
+        self.layouts.push(layout);
+    }
+
+    /// Look up a layout by name
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Layout>> {
+        self.layouts.iter().find(|layout| layout.name() == name).cloned()
+    }
+
+    /// All registered layouts in registration order
+    pub fn layouts(&self) -> impl Iterator<Item = &Arc<dyn Layout>> {
+        self.layouts.iter()
+    }
+}
+
+impl Default for LayoutRegistry {
+    /// A registry with the built-in layouts
+    fn default() -> Self {
+        let mut registry = LayoutRegistry::empty();
+        registry.register(Arc::new(Horizontal));
+        registry.register(Arc::new(Vertical));
+        registry.register(Arc::new(Grid::new()));
+        registry.register(Arc::new(Rows::new()));
+        registry
+    }
+}
+
+impl fmt::Debug for LayoutRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.layouts.iter().map(|layout| layout.name())).finish()
+    }
+}
+
+/// Width of an image of the given size once scaled to the target height
+fn width_at_height(width: u32, height: u32, target_height: u32) -> u32 {
+    if height == target_height {
+        return width;
+    }
+
+    let aspect_ratio = width as f32 / height as f32;
+    (target_height as f32 * aspect_ratio) as u32
+}
+
+/// Height of an image of the given size once scaled to the target width
+fn height_at_width(width: u32, height: u32, target_width: u32) -> u32 {
+    if width == target_width {
+        return height;
+    }
+
+    let aspect_ratio = height as f32 / width as f32;
+    (target_width as f32 * aspect_ratio) as u32
+}
+
+/// Size of an image scaled to fit within the given box, preserving its aspect ratio
+fn fit_within(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let scaled_height = height_at_width(width, height, max_width);
+    if scaled_height <= max_height {
+        (max_width, scaled_height)
+    } else {
+        (width_at_height(width, height, max_height), max_height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_layout() {
+        let settings = LayoutSettings::default();
+        // Test majority vertical -> horizontal merge
+        assert_eq!(choose_layout(&[(100, 200), (150, 300)], &settings).name(), "horizontal");
+
+        // Test majority horizontal -> vertical merge
+        assert_eq!(choose_layout(&[(300, 150), (400, 200)], &settings).name(), "vertical");
+
+        // Test mixed orientations -> justified rows instead of rescaling everything to one width
+        assert_eq!(choose_layout(&[(100, 200), (300, 150), (200, 100)], &settings).name(), "rows");
+
+        // Test many screenshots -> no tall strip
+        assert_ne!(choose_layout(&[(300, 150); 10], &settings).name(), "vertical");
+    }
+
+    #[test]
+    fn test_horizontal_layout() {
+        let settings = LayoutSettings::default();
+        let arrangement = Horizontal.arrange(&[(100, 200), (150, 300)], &settings);
+
+        // Should use the tallest height (300) and sum up widths proportionally
+        assert_eq!(arrangement.height, 300);
+        // First image: 100 * (300/200) = 150 width
+        // Second image: 150 * (300/300) = 150 width
+        // Total: 150 + 150 = 300
+        assert_eq!(arrangement.width, 300);
+        assert_eq!(arrangement.placements, vec![
+            Placement { x: 0, y: 0, width: 150, height: 300 },
+            Placement { x: 150, y: 0, width: 150, height: 300 },
+        ]);
+    }
+
+    #[test]
+    fn test_vertical_layout() {
+        let settings = LayoutSettings::default();
+        let arrangement = Vertical.arrange(&[(200, 100), (300, 150)], &settings);
+
+        // Should use the widest width (300) and sum up heights proportionally
+        assert_eq!(arrangement.width, 300);
+        // First image: 100 * (300/200) = 150 height
+        // Second image: 150 * (300/300) = 150 height
+        // Total: 150 + 150 = 300
+        assert_eq!(arrangement.height, 300);
+        assert_eq!(arrangement.placements[1], Placement { x: 0, y: 150, width: 300, height: 150 });
+    }
+
+    #[test]
+    fn test_grid_layout() {
+        let settings = LayoutSettings::default();
+        // Five images -> 3 columns, 2 rows of 400x200 cells
+        let arrangement = Grid::new().arrange(&[(400, 200), (400, 200), (200, 200), (400, 100), (400, 200)], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (1200, 400));
+        assert_eq!(arrangement.placements[0], Placement { x: 0, y: 0, width: 400, height: 200 });
+        // Square image centred horizontally in the third cell
+        assert_eq!(arrangement.placements[2], Placement { x: 900, y: 0, width: 200, height: 200 });
+        // Wide image centred vertically in the first cell of the second row
+        assert_eq!(arrangement.placements[3], Placement { x: 0, y: 250, width: 400, height: 100 });
+
+        // Fixed columns, never more than there are images
+        let arrangement = Grid::with_columns(2).arrange(&[(100, 100); 5], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (200, 300));
+        assert_eq!(Grid::with_columns(10).columns_for(3), 3);
+        assert_eq!(Grid::with_columns(0).columns_for(3), 1);
+    }
+
+    #[test]
+    fn test_rows_layout() {
+        let settings = LayoutSettings::default();
+        // Aspect ratios 2 + 2 + 1 + 3 = 8 fill one row: 600 / 8 = 75 high
+        let arrangement = Rows::with_target_width(600).arrange(&[(200, 100), (200, 100), (100, 100), (300, 100)], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (600, 75));
+        let widths: Vec<u32> = arrangement.placements.iter().map(|placement| placement.width).collect();
+        assert_eq!(widths, vec![150, 150, 75, 225]);
+        assert_eq!(arrangement.placements[3].x, 375);
+
+        // A full row at the ideal height, then a partial last row centred at that height
+        let arrangement = Rows::with_target_width(600).arrange(&[(200, 100), (400, 100), (100, 100)], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (600, 200));
+        assert_eq!(arrangement.placements[1], Placement { x: 200, y: 0, width: 400, height: 100 });
+        assert_eq!(arrangement.placements[2], Placement { x: 250, y: 100, width: 100, height: 100 });
+    }
+
+    #[test]
+    fn test_rows_layout_keeps_aspect_ratios() {
+        let settings = LayoutSettings::default();
+        let dimensions = [(1000, 2000), (800, 1600), (1920, 1080), (1280, 720), (2560, 1440)];
+        let arrangement = Rows::new().arrange(&dimensions, &settings);
+
+        for ((width, height), placement) in dimensions.iter().zip(&arrangement.placements) {
+            let expected = *width as f64 / *height as f64;
+            let actual = placement.width as f64 / placement.height as f64;
+            assert!((expected - actual).abs() / expected < 0.02, "{:?} distorted to {:?}", (width, height), placement);
+            assert!(placement.x + placement.width <= arrangement.width);
+            assert!(placement.y + placement.height <= arrangement.height);
+        }
+    }
+
+    #[test]
+    fn test_scaling_target() {
+        let sizes = [300, 2160, 1080];
+        assert_eq!(Scaling::ToMax.target(sizes.into_iter()), 2160);
+        assert_eq!(Scaling::ToMin.target(sizes.into_iter()), 300);
+        assert_eq!(Scaling::ToMedian.target(sizes.into_iter()), 1080);
+        assert_eq!(Scaling::NeverUpscale.target(sizes.into_iter()), 2160);
+        assert_eq!(Scaling::ToMax.target(std::iter::empty()), 0);
+
+        // Enlargement is capped, shrinking is not
+        assert_eq!(Scaling::NeverUpscale.limit(300, 2160), 300);
+        assert_eq!(Scaling::MaxUpscale(2.0).limit(300, 2160), 600);
+        assert_eq!(Scaling::MaxUpscale(2.0).limit(3000, 2160), 2160);
+        assert_eq!(Scaling::ToMax.limit(300, 2160), 2160);
+    }
+
+    #[test]
+    fn test_never_upscale_layouts() {
+        let settings = LayoutSettings { scaling: Scaling::NeverUpscale, ..LayoutSettings::default() };
+        let dimensions = [(100, 100), (200, 400)];
+
+        // The small image keeps its size and is centred beside the tall one
+        let arrangement = Horizontal.arrange(&dimensions, &settings);
+        assert_eq!((arrangement.width, arrangement.height), (300, 400));
+        assert_eq!(arrangement.placements[0], Placement { x: 0, y: 150, width: 100, height: 100 });
+
+        let arrangement = Vertical.arrange(&dimensions, &settings);
+        assert_eq!((arrangement.width, arrangement.height), (200, 500));
+        assert_eq!(arrangement.placements[0], Placement { x: 50, y: 0, width: 100, height: 100 });
+
+        let arrangement = Grid::new().arrange(&dimensions, &settings);
+        assert_eq!(arrangement.placements[0], Placement { x: 50, y: 150, width: 100, height: 100 });
+
+        // No layout enlarges any image
+        for layout in LayoutRegistry::default().layouts() {
+            let arrangement = layout.arrange(&dimensions, &settings);
+            for ((width, height), placement) in dimensions.iter().zip(&arrangement.placements) {
+                assert!(placement.width <= *width && placement.height <= *height, "{} enlarged {:?}", layout.name(), placement);
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_scaling_and_alignment() {
+        let dimensions = [(100, 100), (200, 400), (300, 50)];
+        let settings = LayoutSettings { scaling: Scaling::Native, alignment: Alignment::End, ..LayoutSettings::default() };
+
+        // No layout resizes any image
+        for layout in LayoutRegistry::default().layouts() {
+            let arrangement = layout.arrange(&dimensions, &settings);
+            for ((width, height), placement) in dimensions.iter().zip(&arrangement.placements) {
+                assert_eq!((placement.width, placement.height), (*width, *height), "{} resized an image", layout.name());
+            }
+        }
+
+        // Shorter images sit on the bottom edge, narrower ones on the right edge
+        let arrangement = Horizontal.arrange(&dimensions, &settings);
+        assert_eq!((arrangement.width, arrangement.height), (600, 400));
+        assert_eq!(arrangement.placements[0], Placement { x: 0, y: 300, width: 100, height: 100 });
+        let arrangement = Vertical.arrange(&dimensions, &settings);
+        assert_eq!(arrangement.placements[1], Placement { x: 100, y: 100, width: 200, height: 400 });
+
+        let settings = LayoutSettings { alignment: Alignment::Start, ..settings };
+        let arrangement = Horizontal.arrange(&dimensions, &settings);
+        assert!(arrangement.placements.iter().all(|placement| placement.y == 0));
+        let arrangement = Rows::with_target_width(1000).arrange(&dimensions, &settings);
+        assert_eq!((arrangement.width, arrangement.height), (600, 400));
+        assert_eq!(arrangement.placements[2], Placement { x: 300, y: 0, width: 300, height: 50 });
+    }
+
+    #[test]
+    fn test_spacing() {
+        let settings = LayoutSettings { spacing: 10, ..LayoutSettings::default() };
+
+        let arrangement = Horizontal.arrange(&[(100, 100), (100, 100)], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (210, 100));
+        assert_eq!(arrangement.placements[1].x, 110);
+
+        let arrangement = Vertical.arrange(&[(100, 100), (100, 100)], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (100, 210));
+        assert_eq!(arrangement.placements[1].y, 110);
+
+        let arrangement = Grid::with_columns(2).arrange(&[(100, 100); 3], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (210, 210));
+        assert_eq!(arrangement.placements[2], Placement { x: 0, y: 110, width: 100, height: 100 });
+
+        // Full rows still end flush at the target width, gaps included
+        let arrangement = Rows::with_target_width(620).arrange(&[(200, 100), (200, 100), (200, 100), (100, 100)], &settings);
+        assert_eq!(arrangement.width, 620);
+        let last = arrangement.placements[2];
+        assert_eq!(last.x + last.width, 620);
+        assert_eq!(arrangement.placements[1].x, arrangement.placements[0].width + 10);
+
+        // No gaps around a single image
+        for layout in LayoutRegistry::default().layouts() {
+            let arrangement = layout.arrange(&[(100, 50)], &settings);
+            assert_eq!((arrangement.width, arrangement.height), (100, 50), "{} added spacing", layout.name());
+        }
+    }
+
+    #[test]
+    fn test_caption_space() {
+        let settings = LayoutSettings { spacing: 10, caption_space: 20, ..LayoutSettings::default() };
+
+        // Reserved below every image, before the spacing to the next one
+        let native = LayoutSettings { scaling: Scaling::Native, ..settings };
+        let arrangement = Horizontal.arrange(&[(100, 100), (100, 50)], &native);
+        assert_eq!((arrangement.width, arrangement.height), (210, 120));
+        // Shorter images are aligned within the image band, not the caption
+        assert_eq!(arrangement.placements[1].y, 25);
+
+        let arrangement = Vertical.arrange(&[(100, 100), (100, 100)], &settings);
+        assert_eq!(arrangement.height, 250);
+        assert_eq!(arrangement.placements[1].y, 130);
+
+        let arrangement = Grid::with_columns(1).arrange(&[(100, 100); 2], &settings);
+        assert_eq!(arrangement.height, 250);
+        assert_eq!(arrangement.placements[1].y, 130);
+
+        let arrangement = Rows::with_target_width(100).arrange(&[(100, 100); 2], &settings);
+        assert_eq!(arrangement.height, 250);
+        assert_eq!(arrangement.placements[1].y, 130);
+    }
+
+    #[test]
+    fn test_to_min_scaling() {
+        let settings = LayoutSettings { scaling: Scaling::ToMin, ..LayoutSettings::default() };
+        let arrangement = Horizontal.arrange(&[(100, 100), (200, 400)], &settings);
+        assert_eq!((arrangement.width, arrangement.height), (150, 100));
+        assert_eq!(arrangement.placements[1], Placement { x: 100, y: 0, width: 50, height: 100 });
+    }
+
+    #[test]
+    fn test_fit_within() {
+        // Smaller image scaled up until one side touches the box
+        assert_eq!(fit_within(100, 50, 400, 400), (400, 200));
+        assert_eq!(fit_within(50, 100, 400, 400), (200, 400));
+        assert_eq!(fit_within(400, 400, 400, 400), (400, 400));
+    }
+
+    #[test]
+    fn test_empty_layout() {
+        let settings = LayoutSettings::default();
+        for layout in LayoutRegistry::default().layouts() {
+            let arrangement = layout.arrange(&[], &settings);
+            assert_eq!((arrangement.width, arrangement.height), (0, 0));
+            assert!(arrangement.placements.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_scaled_sizes() {
+        // Same size: unchanged
+        assert_eq!(width_at_height(100, 200, 200), 100);
+        assert_eq!(height_at_width(200, 100, 200), 100);
+
+        // Aspect ratio 100/200 = 0.5, so new width should be 400 * 0.5 = 200
+        assert_eq!(width_at_height(100, 200, 400), 200);
+        // Aspect ratio 100/200 = 0.5, so new height should be 400 * 0.5 = 200
+        assert_eq!(height_at_width(200, 100, 400), 200);
+    }
+
+    #[test]
+    fn test_layout_registry() {
+        let registry = LayoutRegistry::default();
+        assert_eq!(registry.get("horizontal").map(|layout| layout.name()), Some("horizontal"));
+        assert_eq!(registry.get("vertical").map(|layout| layout.name()), Some("vertical"));
+        assert_eq!(registry.get("grid").map(|layout| layout.name()), Some("grid"));
+        assert_eq!(registry.get("rows").map(|layout| layout.name()), Some("rows"));
+        assert!(registry.get("spiral").is_none());
+
+        // Registering a layout under an existing name replaces it
+        let mut registry = LayoutRegistry::empty();
+        registry.register(Arc::new(Vertical));
+        registry.register(Arc::new(Vertical));
+        assert_eq!(registry.layouts().count(), 1);
+    }
+}
@@ -0,0 +1,62 @@
+//! Merge groups of images in subdirectories into single composite images.
+//!
+//! ```no_run
+//! use picmrg::{merge_directory, MergeOptions, MergeOutcome};
+//! use std::path::Path;
+//!
+//! let report = merge_directory(Path::new("evidence/2024-01-15"), &MergeOptions::new());
+//! if let Ok(MergeOutcome::Merged(path)) = report.result {
+//!     println!("wrote {}", path.display());
+//! }
+//! ```
+
+mod budget;
+mod caption;
+mod compositor;
+mod error;
+mod filter;
+mod format;
+mod header;
+mod layout;
+mod limit;
+mod merger;
+mod options;
+mod output;
+mod pdf;
+mod print;
+mod provenance;
+mod scanner;
+mod scoring;
+mod stamp;
+mod text;
+#[cfg(test)]
+mod test_utils;
+
+pub use budget::{fit_to_budget, Fitted};
+pub use caption::{CaptionPosition, Captions};
+pub use compositor::{parse_color, render, render_captioned, Style};
+pub use error::Error;
+pub use filter::{check_glob, FileFilter, IGNORE_FILE_NAME};
+pub use format::{Encoding, OutputFormat, DEFAULT_QUALITY};
+pub use header::{add_header, parse_title, Header, HeaderTitle};
+pub use layout::{
+    choose_layout, Alignment, Arrangement, Grid, Horizontal, Layout, LayoutRegistry, LayoutSettings, Placement, Rows,
+    Scaling, Vertical,
+};
+pub use limit::{downscale, SizeLimit};
+pub use merger::{
+    find_existing_merged_files, load_image_info, merge, merge_directory, merge_images_in_directory,
+    merge_status, output_path, part_output_path, plan_merge, plan_merge_parts, remove_existing_merged_files,
+    split_sources, ImageInfo, MergeOutcome, MergePlan, MergeReport, MergeStatus,
+};
+pub use options::MergeOptions;
+pub use output::{install_interrupt_handler, remove_leftover_temp_files};
+pub use pdf::{encode_pdf, PageSize, PdfMetadata, PdfSettings, DEFAULT_PDF_MARGIN};
+pub use print::{place_on_page, PrintPage, DEFAULT_DPI, DEFAULT_PRINT_MARGIN};
+pub use provenance::{is_picmrg_output, is_replaceable_output, read_marker, MergedFileMatching, Provenance};
+pub use scanner::{
+    collect_image_files, find_directories, find_image_files, find_subdirectories, scan_for_images, GroupSelection,
+    ScanResult, ScanSettings, SkipReason, SkippedFile, DEFAULT_MAX_DEPTH,
+};
+pub use scoring::{rank_layouts, LayoutScore};
+pub use stamp::{add_footer, add_watermark, fill_template, Stamp, DEFAULT_WATERMARK_OPACITY};
@@ -0,0 +1,118 @@
+use image::DynamicImage;
+
+/// Largest dimensions of a merged output; no limits by default
+///
+/// Outputs that exceed a limit are shrunk, keeping their aspect ratio, until they fit all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SizeLimit {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_pixels: Option<u64>, // Width times height
+}
+
+impl SizeLimit {
+    /// Whether any limit is set
+    pub fn is_set(&self) -> bool {
+        self.max_width.is_some() || self.max_height.is_some() || self.max_pixels.is_some()
+    }
+
+    /// Factor an image of the given size must be scaled by to fit every limit (1 if it already fits)
+    pub fn factor(&self, width: u32, height: u32) -> f64 {
+        if width == 0 || height == 0 {
+            return 1.0;
+        }
+        let mut factor: f64 = 1.0;
+        if let Some(max_width) = self.max_width {
+            factor = factor.min(max_width as f64 / width as f64);
+        }
+        if let Some(max_height) = self.max_height {
+            factor = factor.min(max_height as f64 / height as f64);
+        }
+        if let Some(max_pixels) = self.max_pixels {
+            factor = factor.min((max_pixels as f64 / (width as f64 * height as f64)).sqrt());
+        }
+        factor
+    }
+
+    /// The tighter of two limits on every dimension
+    pub fn within(&self, other: &SizeLimit) -> SizeLimit {
+        fn tighter<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        SizeLimit {
+            max_width: tighter(self.max_width, other.max_width),
+            max_height: tighter(self.max_height, other.max_height),
+            max_pixels: tighter(self.max_pixels, other.max_pixels),
+        }
+    }
+
+    /// Size of an image of the given size once shrunk to fit every limit, never below 1x1
+    pub fn fit(&self, width: u32, height: u32) -> (u32, u32) {
+        let factor = self.factor(width, height);
+        if factor >= 1.0 {
+            return (width, height);
+        }
+        (scale(width, factor), scale(height, factor))
+    }
+}
+
+/// Scale a length down, rounding so that scaling to a limit lands on it rather than a pixel below
+pub(crate) fn scale(length: u32, factor: f64) -> u32 {
+    ((length as f64 * factor + 1e-6).floor() as u32).max(1)
+}
+
+/// Shrink an image to fit a size limit, keeping its aspect ratio; images that fit are returned as they are
+pub fn downscale(image: DynamicImage, limit: &SizeLimit) -> DynamicImage {
+    let (width, height) = limit.fit(image.width(), image.height());
+    if (width, height) == (image.width(), image.height()) {
+        return image;
+    }
+    image.resize_exact(width, height, image::imageops::FilterType::Lanczos3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::generate_test_image;
+
+    #[test]
+    fn test_size_limit_fit() {
+        let limit = SizeLimit { max_width: Some(4000), ..SizeLimit::default() };
+        assert_eq!(limit.fit(20_000, 3000), (4000, 600));
+        assert_eq!(limit.fit(3000, 20_000), (3000, 20_000));
+
+        let limit = SizeLimit { max_width: Some(4000), max_height: Some(1000), max_pixels: None };
+        assert_eq!(limit.fit(8000, 4000), (2000, 1000));
+
+        let limit = SizeLimit { max_pixels: Some(1_000_000), ..SizeLimit::default() };
+        let (width, height) = limit.fit(4000, 1000);
+        assert_eq!((width, height), (2000, 500));
+        assert!(u64::from(width) * u64::from(height) <= 1_000_000);
+
+        // Combined with a page, the tighter of each
+        let page = SizeLimit { max_width: Some(2000), max_height: Some(3000), max_pixels: None };
+        let combined = limit.within(&page);
+        assert_eq!((combined.max_width, combined.max_height, combined.max_pixels), (Some(2000), Some(3000), Some(1_000_000)));
+        assert_eq!(SizeLimit { max_width: Some(1000), ..SizeLimit::default() }.within(&page).max_width, Some(1000));
+
+        // Nothing set, or already small enough: unchanged
+        assert!(!SizeLimit::default().is_set());
+        assert_eq!(SizeLimit::default().fit(20_000, 3000), (20_000, 3000));
+        assert_eq!(SizeLimit { max_width: Some(1), ..SizeLimit::default() }.fit(10, 1), (1, 1));
+    }
+
+    #[test]
+    fn test_downscale() {
+        let image = generate_test_image(300, 100, [255, 0, 0]);
+        let limit = SizeLimit { max_width: Some(150), ..SizeLimit::default() };
+        let output = downscale(image.clone(), &limit);
+        assert_eq!((output.width(), output.height()), (150, 50));
+        assert_eq!(output.to_rgb8().get_pixel(75, 25).0, [255, 0, 0]);
+
+        let output = downscale(image, &SizeLimit::default());
+        assert_eq!((output.width(), output.height()), (300, 100));
+    }
+}
@@ -1,86 +1,474 @@
+use std::collections::BTreeMap;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::Write;
+use clap::CommandFactory;
 
-mod scanner;
-mod merger;
-#[cfg(test)]
-mod test_utils;
+use picmrg::{Error, MergeOptions, MergeOutcome, MergePlan, MergeStatus, MergedFileMatching, OutputFormat};
+
+mod cli;
+
+use cli::{Cli, Command, InspectArgs, MergeArgs, RootArgs};
+
+/// How a command finished, mapped to the process exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Completion {
+    Success,        // Exit code 0
+    Failure,        // Exit code 1: nothing succeeded, or the root could not be scanned
+    PartialFailure, // Exit code 2: some directories failed while others succeeded
+}
+
+impl Completion {
+    /// Summarize per-directory results
+    fn from_counts(succeeded: usize, failed: usize) -> Self {
+        match (succeeded, failed) {
+            (_, 0) => Completion::Success,
+            (0, _) => Completion::Failure,
+            _ => Completion::PartialFailure,
+        }
+    }
+
+    fn exit_code(self) -> i32 {
+        match self {
+            Completion::Success => 0,
+            Completion::Failure => 1,
+            Completion::PartialFailure => 2,
+        }
+    }
+}
 
 fn main() {
+    let command = Cli::parse_checked().into_command();
+
+    // Generated output goes to stdout unadorned so it can be redirected to a file
+    match command {
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "picmrg", &mut std::io::stdout());
+            return;
+        }
+        Command::Man => {
+            if let Err(e) = clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout()) {
+                eprintln!("Error writing man page: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
     println!("picmrg v{}: image merger\n", env!("CARGO_PKG_VERSION"));
-    let args: Vec<String> = env::args().collect();
-    
-    // Check for help flag
-    if args.len() > 1 && args[1] == "-h" {
-        print_usage(&args[0]);
-        return;
-    }
-    
-    // Determine root path
-    let root_path = if args.len() > 1 {
-        PathBuf::from(&args[1])
-    } else {
-        env::current_dir().expect("Failed to get current directory")
+
+    let result = match command {
+        Command::Merge(args) => run_merge(&args),
+        Command::Status(args) => run_status(&args),
+        Command::Clean(args) => run_clean(&args),
+        Command::Inspect(args) => run_inspect(&args),
+        Command::Verify(args) => run_verify(&args),
+        Command::Completions { .. } | Command::Man => unreachable!(),
     };
-    
+
+    match result {
+        Ok(Completion::Success) => {}
+        Ok(completion) => std::process::exit(completion.exit_code()),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(Completion::Failure.exit_code());
+        }
+    }
+}
+
+/// Resolve the root path argument, defaulting to the current directory
+fn root_path(args: &RootArgs) -> PathBuf {
+    let root_path = match &args.root {
+        Some(path) => path.clone(),
+        None => env::current_dir().expect("Failed to get current directory"),
+    };
+
     println!("Root path: {}", root_path.display());
-    
-    // Scan for images
-    match scanner::scan_for_images(&root_path) {
-        Ok(scan_result) => {
-            
-            // Merge images in each directory (in alphabetical order)
-            let mut sorted_directories: Vec<_> = scan_result.directories.iter().collect();
-            sorted_directories.sort_by_key(|(dir_name, _)| *dir_name);
-            
-            for (dir_name, image_files) in sorted_directories {
-                let dir_path = root_path.join(dir_name);
-                
-                // Print initial status
-                print!("\rMerging images in directory: {} ... ", dir_name);
-                std::io::stdout().flush().unwrap();
-                
-                match merger::merge_images_in_directory(&dir_path, image_files) {
-                    Ok(()) => {
-                        print!("\r✓ Successfully merged images in {}", dir_name);
-                        // Pad with spaces to clear any remaining characters, then newline
-                        println!("{}", " ".repeat(20));
-                    },
-                    Err(e) => {
-                        let error_msg = e.to_string();
-                        if error_msg.contains("Only one image file") {
-                            print!("\r- Skipped {} (only one image)", dir_name);
-                            println!("{}", " ".repeat(20));
+    root_path
+}
+
+/// Scan the root path and return its image directories, relative to it, in alphabetical order,
+/// warning about the files passed over in them
+fn sorted_directories(
+    root_path: &Path,
+    options: &MergeOptions,
+) -> Result<BTreeMap<PathBuf, Vec<PathBuf>>, Error> {
+    let scan_result = picmrg::scan_for_images(root_path, options)?;
+    for file in &scan_result.skipped {
+        let path = file.path.strip_prefix(root_path).unwrap_or(&file.path);
+        eprintln!("Warning: skipped {}: {}", path.display(), file.reason);
+    }
+    Ok(scan_result.directories.into_iter().collect())
+}
+
+/// Merge images in each directory
+fn run_merge(args: &MergeArgs) -> Result<Completion, Error> {
+    if args.dry_run {
+        return run_dry_run(args);
+    }
+
+    let root_path = root_path(&args.root);
+    let options = args.options();
+    let directories = sorted_directories(&root_path, &options)?;
+
+    // Remove half-written outputs if the user interrupts the merge
+    if let Err(e) = picmrg::install_interrupt_handler() {
+        eprintln!("Warning: Ctrl-C will not clean up temporary files: {}", e);
+    }
+
+    let mut succeeded = 0;
+    let mut failed = 0;
+    for (relative_path, image_files) in &directories {
+        let dir_path = root_path.join(relative_path);
+        let dir_name = relative_path.display();
+
+        // Print initial status
+        print!("\rMerging images in directory: {} ... ", dir_name);
+        std::io::stdout().flush().unwrap();
+
+        let report = picmrg::merge_images_in_directory(&dir_path, image_files, &options);
+        for e in &report.unreadable {
+            eprintln!("Warning: {}", e);
+        }
+
+        match report.result {
+            Ok(outcome) => {
+                succeeded += 1;
+                match outcome {
+                    MergeOutcome::Merged(_) => print!("\r✓ Successfully merged images in {}", dir_name),
+                    MergeOutcome::Skipped => print!("\r- Skipped {} (only one image)", dir_name),
+                    MergeOutcome::Unchanged(path) => {
+                        print!("\r= Unchanged {} ({} is up to date)", dir_name, file_name(&path));
+                    }
+                    MergeOutcome::Split(parts) => {
+                        let written = parts.iter().filter(|part| matches!(part, MergeOutcome::Merged(_))).count();
+                        if written == 0 {
+                            print!("\r= Unchanged {} ({} files are up to date)", dir_name, parts.len());
                         } else {
-                            print!("\r✗ Failed to merge images in {}: {}", dir_name, e);
-                            println!("{}", " ".repeat(10));
+                            print!("\r✓ Successfully merged images in {} into {} files", dir_name, parts.len());
                         }
-                    },
+                    }
+                }
+                // Pad with spaces to clear any remaining characters, then newline
+                println!("{}", " ".repeat(20));
+                for fitted in &report.fitted {
+                    println!("  fitted: {}", fitted);
+                }
+            },
+            Err(e) => {
+                failed += 1;
+                print!("\r✗ Failed to merge images in {}: {}", dir_name, describe_error(&e));
+                println!("{}", " ".repeat(10));
+            },
+        }
+
+        if args.explain {
+            report.plans.iter().for_each(print_layout_scores);
+        }
+    }
+
+    if directories.is_empty() {
+        println!("No directories with images found to merge.");
+    } else {
+        println!("\nMerging complete!");
+    }
+
+    Ok(Completion::from_counts(succeeded, failed))
+}
+
+/// Print the planned merge of each directory without touching disk
+fn run_dry_run(args: &MergeArgs) -> Result<Completion, Error> {
+    let root_path = root_path(&args.root);
+    let options = args.options();
+    let directories = sorted_directories(&root_path, &options)?;
+
+    println!("Dry run: no files will be written or deleted\n");
+
+    let mut failed = 0;
+    for (relative_path, image_files) in &directories {
+        let dir_path = root_path.join(relative_path);
+        let dir_name = relative_path.display();
+
+        match picmrg::plan_merge_parts(&dir_path, image_files, &options) {
+            Ok(plans) => {
+                for (part, plan) in plans.iter().enumerate() {
+                    let label = match plans.len() {
+                        1 => dir_name.to_string(),
+                        count => format!("{} (part {} of {})", dir_name, part + 1, count),
+                    };
+                    println!("{}: would merge {} images with the {} layout ({})",
+                        label, plan.sources.len(), plan.layout.name(), plan.layout.description().to_lowercase());
+                    for (index, (path, width, height)) in plan.sources.iter().enumerate() {
+                        println!("  {}. {} ({}x{})", index + 1, file_name(path), width, height);
+                    }
+                    for (path, reason) in &plan.unreadable {
+                        println!("  - would skip {}: {}", file_name(path), reason);
+                    }
+                    println!("  → {} ({}x{})", file_name(&plan.output_path), plan.output_width, plan.output_height);
+                    if args.explain {
+                        print_layout_scores(plan);
+                    }
+                }
+                // Every part lists the same merged files
+                for path in plans.first().map(|plan| plan.existing_merged_files.as_slice()).unwrap_or_default() {
+                    println!("  would delete {}", file_name(path));
                 }
             }
-            
-            if scan_result.directories.is_empty() {
-                println!("No directories with images found to merge.");
-            } else {
-                println!("\nMerging complete!");
+            Err(Error::TooFewImages { .. }) => println!("- Would skip {} (only one image)", dir_name),
+            Err(e) => {
+                failed += 1;
+                println!("✗ Cannot merge images in {}: {}", dir_name, describe_error(&e));
             }
         }
-        Err(e) => {
-            eprintln!("Error scanning for images: {}", e);
-            std::process::exit(1);
+    }
+
+    if directories.is_empty() {
+        println!("No directories with images found to merge.");
+    }
+
+    Ok(Completion::from_counts(directories.len() - failed, failed))
+}
+
+/// Print the score of every layout for a planned merge, marking the one used
+fn print_layout_scores(plan: &MergePlan) {
+    println!("  Layout scores (lower cost is better):");
+    for score in &plan.scores {
+        let marker = if score.layout.name() == plan.layout.name() { "*" } else { " " };
+        println!("  {} {}", marker, score);
+    }
+}
+
+/// Print the merge status of each directory
+fn run_status(args: &RootArgs) -> Result<Completion, Error> {
+    let root_path = root_path(args);
+    let directories = sorted_directories(&root_path, &args.options())?;
+
+    let mut failed = 0;
+    for (relative_path, image_files) in &directories {
+        let dir_path = root_path.join(relative_path);
+        let dir_name = relative_path.display();
+        let count = image_files.len();
+
+        let status = match picmrg::merge_status(&dir_path, image_files, args.matching()) {
+            Ok(status) => status,
+            Err(e) => {
+                failed += 1;
+                println!("✗ {}: cannot check merged files: {}", dir_name, e);
+                continue;
+            }
+        };
+        match status {
+            MergeStatus::Single => println!("- {} (only one image)", dir_name),
+            MergeStatus::Missing => println!("✗ {}: {} images, not merged", dir_name, count),
+            MergeStatus::Stale(paths) => {
+                println!("✗ {}: {} images, {} out of date", dir_name, count, describe_outputs(&paths));
+            }
+            MergeStatus::Current(paths) => {
+                println!("✓ {}: {} images, {} up to date", dir_name, count, describe_outputs(&paths));
+            }
+        }
+    }
+
+    if directories.is_empty() {
+        println!("No directories with images found.");
+    }
+
+    Ok(Completion::from_counts(directories.len() - failed, failed))
+}
+
+/// Remove merged files from every scanned directory below the root path
+fn run_clean(args: &RootArgs) -> Result<Completion, Error> {
+    let root_path = root_path(args);
+    let directories = picmrg::find_directories(&root_path, &args.options())?;
+    let mut removed_count = 0;
+
+    let mut failed = 0;
+    for dir_path in &directories {
+        let mut dir_failed = false;
+        match picmrg::remove_existing_merged_files(dir_path, args.matching()) {
+            Ok(paths) => {
+                for path in paths {
+                    println!("Removed {}", path.display());
+                    removed_count += 1;
+                }
+            }
+            Err(e) => {
+                dir_failed = true;
+                println!("✗ Cannot remove merged files in {}: {}", dir_path.display(), e);
+            }
+        }
+        match picmrg::remove_leftover_temp_files(dir_path) {
+            Ok(paths) => {
+                for path in paths {
+                    println!("Removed leftover temporary file {}", path.display());
+                }
+            }
+            Err(e) => {
+                dir_failed = true;
+                println!("✗ Cannot remove temporary files in {}: {}", dir_path.display(), e);
+            }
+        }
+        if dir_failed {
+            failed += 1;
         }
     }
+
+    println!("\nRemoved {} merged file(s).", removed_count);
+    Ok(Completion::from_counts(directories.len() - failed, failed))
+}
+
+/// Print information about individual image files or the images in directories
+fn run_inspect(args: &InspectArgs) -> Result<Completion, Error> {
+    let options = args.options();
+    let mut all_ok = true;
+
+    for path in &args.paths {
+        let files = if path.is_dir() {
+            println!("{}:", path.display());
+            let mut files = picmrg::find_image_files(path, &options)?;
+            files.extend(picmrg::find_existing_merged_files(path, MergedFileMatching::Marker)?);
+            files
+        } else {
+            vec![path.clone()]
+        };
+
+        for file_path in &files {
+            // Merged files in formats picmrg only writes are described by their marker alone
+            if OutputFormat::from_path(file_path).is_some_and(|format| !format.is_decodable())
+                && let Some(marker) = picmrg::read_marker(file_path)
+            {
+                println!("  {}: picmrg v{} output, {} layout of {} source(s): {}", file_name(file_path),
+                    marker.version, marker.layout, marker.sources.len(), marker.sources.join(", "));
+                continue;
+            }
+            match picmrg::load_image_info(file_path) {
+                Ok(info) => {
+                    let orientation = if info.is_vertical { "vertical" } else { "horizontal" };
+                    println!("  {}: {}x{} {}, {:?}", file_name(file_path), info.width, info.height,
+                        orientation, info.image.color());
+                    if let Some(marker) = picmrg::read_marker(file_path) {
+                        println!("    picmrg v{} output, {} layout of {} source(s): {}", marker.version,
+                            marker.layout, marker.sources.len(), marker.sources.join(", "));
+                    }
+                }
+                Err(e) => {
+                    all_ok = false;
+                    println!("  ✗ {}: {}", file_name(file_path), e);
+                }
+            }
+        }
+    }
+
+    Ok(if all_ok { Completion::Success } else { Completion::Failure })
 }
 
-fn print_usage(program_name: &str) {
-    println!("Usage: {} [ROOT_PATH]", program_name);
-    println!();
-    println!("Arguments:");
-    println!("  ROOT_PATH    Directory to use as root path (default: current directory)");
-    println!("  -h           Show this help message");
-    println!();
-    println!("Examples:");
-    println!("  {}           # Use current directory", program_name);
-    println!("  {} /path/to/images  # Use specified directory", program_name);
+/// Check that every mergeable directory has a current, decodable merged file
+fn run_verify(args: &RootArgs) -> Result<Completion, Error> {
+    let root_path = root_path(args);
+    let directories = sorted_directories(&root_path, &args.options())?;
+    let mut all_ok = true;
+    let mut failed = 0;
+
+    for (relative_path, image_files) in &directories {
+        let dir_path = root_path.join(relative_path);
+        let dir_name = relative_path.display();
+
+        let status = match picmrg::merge_status(&dir_path, image_files, args.matching()) {
+            Ok(status) => status,
+            Err(e) => {
+                failed += 1;
+                println!("✗ {}: cannot check merged files: {}", dir_name, e);
+                continue;
+            }
+        };
+        match status {
+            MergeStatus::Single => {}
+            MergeStatus::Missing => {
+                all_ok = false;
+                println!("✗ {}: merged file is missing", dir_name);
+            }
+            MergeStatus::Stale(paths) => {
+                all_ok = false;
+                println!("✗ {}: {} older than its source images", dir_name, describe_outputs(&paths));
+            }
+            MergeStatus::Current(paths) => {
+                for path in &paths {
+                    // Only files with the picmrg marker count as merged, unless legacy names are used
+                    match decode_check(path) {
+                        Ok(()) => println!("✓ {}: {}", dir_name, file_name(path)),
+                        Err(e) => {
+                            all_ok = false;
+                            println!("✗ {}: {} cannot be decoded: {}", dir_name, file_name(path), e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if !all_ok {
+        println!("\nVerification failed.");
+        return Ok(Completion::Failure);
+    }
+    if failed == 0 {
+        println!("\nAll merged files verified.");
+    } else {
+        println!("\n{} director{} could not be checked.", failed, if failed == 1 { "y" } else { "ies" });
+    }
+
+    Ok(Completion::from_counts(directories.len() - failed, failed))
+}
+
+/// Check that a merged file decodes; formats picmrg can only write are checked for their marker alone
+fn decode_check(path: &Path) -> Result<(), image::ImageError> {
+    if OutputFormat::from_path(path).is_some_and(|format| !format.is_decodable()) {
+        return Ok(());
+    }
+    image::open(path).map(|_| ())
+}
+
+/// Describe an error merging a directory, with the option that resolves it where there is one
+fn describe_error(e: &Error) -> String {
+    match e {
+        Error::ForeignOutput { .. } => format!("{}; rename it or use --legacy-names", e),
+        e => e.to_string(),
+    }
+}
+
+/// Names of a directory's merged files with the verb to follow them, e.g. "merged-24-01-16.png is"
+fn describe_outputs(paths: &[PathBuf]) -> String {
+    let names: Vec<String> = paths.iter().map(|path| file_name(path)).collect();
+    match names.len() {
+        1 => format!("{} is", names[0]),
+        _ => format!("{} are", names.join(", ")),
+    }
+}
+
+/// Display name of a file for status output
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completion_from_counts() {
+        assert_eq!(Completion::from_counts(3, 0), Completion::Success);
+        assert_eq!(Completion::from_counts(0, 0), Completion::Success);
+        assert_eq!(Completion::from_counts(0, 2), Completion::Failure);
+        assert_eq!(Completion::from_counts(1, 2), Completion::PartialFailure);
+        assert_eq!(Completion::PartialFailure.exit_code(), 2);
+    }
+
+    #[test]
+    fn test_describe_error() {
+        let foreign = Error::ForeignOutput { path: PathBuf::from("merged.png") };
+        assert!(describe_error(&foreign).ends_with("rename it or use --legacy-names"));
+        assert_eq!(describe_error(&Error::NoImages), Error::NoImages.to_string());
+    }
 }
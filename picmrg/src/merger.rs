@@ -1,12 +1,49 @@
-use image::{DynamicImage, ImageBuffer, RgbaImage};
+use image::DynamicImage;
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::Arc;
 use chrono::{DateTime, Local};
+use crate::budget::{self, Fitted};
+use crate::compositor;
+use crate::error::Error;
+use crate::format::OutputFormat;
+use crate::header;
+use crate::options::MergeOptions;
+use crate::output::{self, StagedOutput};
+use crate::pdf::{self, PdfMetadata};
+use crate::print;
+use crate::layout::{Layout, LayoutRegistry};
+use crate::limit;
+use crate::provenance::{self, MergedFileMatching, Provenance};
+use crate::scanner;
+use crate::scoring::{self, LayoutScore};
+use crate::stamp;
 
-#[derive(Debug, Clone, Copy)]
-pub enum MergeOrientation {
-    Horizontal, // Images side by side
-    Vertical,   // Images stacked vertically
+/// The output of one part of a merge: kept as it was, or written to a temporary file that is
+/// moved into place once every part has been written
+enum PartOutput {
+    Unchanged(PathBuf),
+    Staged(StagedOutput, MergePlan),
+}
+
+/// What merging a directory did
+#[derive(Debug, PartialEq, Eq)]
+pub enum MergeOutcome {
+    Merged(PathBuf),          // A new merged file was written
+    Skipped,                  // Only one image, nothing to merge
+    Unchanged(PathBuf),       // The existing merged file is current and was kept
+    Split(Vec<MergeOutcome>), // The sources were split into numbered merged files, one outcome per part
+}
+
+impl MergeOutcome {
+    /// Merged files written or kept, in part order
+    pub fn outputs(&self) -> Vec<PathBuf> {
+        match self {
+            MergeOutcome::Merged(path) | MergeOutcome::Unchanged(path) => vec![path.clone()],
+            MergeOutcome::Skipped => Vec::new(),
+            MergeOutcome::Split(parts) => parts.iter().flat_map(MergeOutcome::outputs).collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -17,61 +54,574 @@ pub struct ImageInfo {
     pub is_vertical: bool,
 }
 
-/// Merge images from a directory based on their orientation
+/// Result of merging one directory
+#[derive(Debug)]
+pub struct MergeReport {
+    pub directory: PathBuf,
+    pub result: Result<MergeOutcome, Error>,
+    pub unreadable: Vec<Error>, // Source images that could not be decoded and were left out
+    pub fitted: Vec<Fitted>,    // How each written output was fitted under the size budget, if one was set
+    pub plans: Vec<MergePlan>,  // How each written output was laid out, with the layout scores behind it
+}
+
+/// Merge images in memory with the selected layout, or one chosen from their orientations
+///
+/// Returns an empty (0x0) image if `images` is empty.
+pub fn merge(images: &[DynamicImage], options: &MergeOptions) -> DynamicImage {
+    let dimensions: Vec<(u32, u32)> = images.iter().map(|image| (image.width(), image.height())).collect();
+    let layout = options.layout_for(&dimensions);
+    let captions: Vec<String> = (0..images.len()).map(|index| options.style.captions.text(index, None)).collect();
+    let composite = compositor::render_captioned(images, &options.arrange(layout.as_ref(), &dimensions), &options.style, &captions);
+
+    // Without a directory the banner can only show the image count
+    let header_lines = options.style.header.lines(None, None, images.len());
+    let composite = header::add_header(composite, &header_lines, options.style.background);
+    finish(composite, None, options)
+}
+
+/// Find the image files in a directory and merge them
+pub fn merge_directory(directory: &Path, options: &MergeOptions) -> MergeReport {
+    match scanner::find_image_files(directory, options) {
+        Ok(image_files) => merge_images_in_directory(directory, &image_files, options),
+        Err(e) => MergeReport {
+            directory: directory.to_path_buf(),
+            result: Err(e),
+            unreadable: Vec::new(),
+            fitted: Vec::new(),
+            plans: Vec::new(),
+        },
+    }
+}
+
+/// Merge images from a directory with the selected layout, or one chosen from their orientations
 pub fn merge_images_in_directory(
     directory: &Path,
     image_files: &[PathBuf],
-) -> Result<(), Box<dyn std::error::Error>> {
+    options: &MergeOptions,
+) -> MergeReport {
+    let mut unreadable = Vec::new();
+    let mut fitted = Vec::new();
+    let mut plans = Vec::new();
+    let result = merge_files(directory, image_files, options, &mut unreadable, &mut fitted, &mut plans);
+
+    MergeReport {
+        directory: directory.to_path_buf(),
+        result,
+        unreadable,
+        fitted,
+        plans,
+    }
+}
+
+/// Merge the given files, collecting decoding failures of individual files in `unreadable`, how
+/// each output was fitted under a size budget in `fitted` and how it was laid out in `plans`
+fn merge_files(
+    directory: &Path,
+    image_files: &[PathBuf],
+    options: &MergeOptions,
+    unreadable: &mut Vec<Error>,
+    fitted: &mut Vec<Fitted>,
+    plans: &mut Vec<MergePlan>,
+) -> Result<MergeOutcome, Error> {
     if image_files.is_empty() {
-        return Err("No image files to merge".into());
+        return Err(Error::NoImages);
     }
     
     if image_files.len() <= 1 {
-        return Err("Only one image file found, skipping merge".into());
+        return Ok(MergeOutcome::Skipped);
     }
 
-    // Find the latest creation date among all image files
-    let latest_date = find_latest_creation_date(image_files)?;
-    let date_string = latest_date.format("%y-%m-%d").to_string();
-    let output_filename = format!("merged-{}.png", date_string);
-    let output_path = directory.join(&output_filename);
+    // Merge each group of sources into its own output, numbered if there is more than one
+    let previous_outputs = find_existing_merged_files(directory, options.matching)?;
+    let parts = split_sources(image_files, options.max_per_output);
+    let mut part_outputs = Vec::new();
+    let mut failure = None;
+    for (index, part_files) in parts.iter().enumerate() {
+        let part = (parts.len() > 1).then_some(index + 1);
+        match merge_part(directory, image_files, part_files, part, options, unreadable, fitted) {
+            Ok(part_output) => part_outputs.push(part_output),
+            Err(e) => {
+                failure.get_or_insert(e);
+            }
+        }
+    }
+
+    // If a part failed, drop the written parts with their temporary files, so the previous
+    // merges stay as they were
+    if let Some(e) = failure {
+        return Err(e);
+    }
 
-    // Remove any existing merged files before creating a new one
-    remove_existing_merged_files(directory)?;
+    // Every part is written, so move them all into place
+    let mut outcomes = Vec::new();
+    for part_output in part_outputs {
+        match part_output {
+            PartOutput::Unchanged(path) => outcomes.push(MergeOutcome::Unchanged(path)),
+            PartOutput::Staged(staged, plan) => {
+                outcomes.push(MergeOutcome::Merged(staged.commit()?));
+                plans.push(plan);
+            }
+        }
+    }
+
+    // Only now that the new merges exist, remove older merged files
+    if outcomes.iter().any(|outcome| matches!(outcome, MergeOutcome::Merged(_))) {
+        let outputs: Vec<PathBuf> = outcomes.iter().flat_map(MergeOutcome::outputs).collect();
+        let replaced: Vec<PathBuf> = previous_outputs.into_iter().filter(|path| !outputs.contains(path)).collect();
+        for path in &replaced {
+            fs::remove_file(path)?;
+        }
+        for plan in plans.iter_mut() {
+            plan.existing_merged_files = replaced.clone();
+        }
+    }
+
+    Ok(match outcomes.len() {
+        1 => outcomes.remove(0),
+        _ => MergeOutcome::Split(outcomes),
+    })
+}
+
+/// Merge one group of a directory's sources into a temporary file for the output for `part`
+/// (`None` if the sources are not split), with the plan it followed
+fn merge_part(
+    directory: &Path,
+    image_files: &[PathBuf],
+    part_files: &[PathBuf],
+    part: Option<usize>,
+    options: &MergeOptions,
+    unreadable: &mut Vec<Error>,
+    fitted: &mut Vec<Fitted>,
+) -> Result<PartOutput, Error> {
+    // Name the output after the latest creation date among all image files
+    let output_path = part_output_path(directory, image_files, options.encoding.format, part)?;
+    ensure_output_path_is_ours(&output_path, options.matching)?;
+
+    // Keep a current merge of the same sources unless asked to redo it
+    if !options.force && let Some(current) = unchanged_output(directory, image_files, part_files, part, options)? {
+        return Ok(PartOutput::Unchanged(current));
+    }
 
     // Load all images and analyze their dimensions
     let mut image_infos = Vec::new();
-    for file_path in image_files {
+    let mut merged_files = Vec::new();
+    let mut skipped_files = Vec::new();
+    for file_path in part_files {
         match load_image_info(file_path) {
-            Ok(info) => image_infos.push(info),
+            Ok(info) => {
+                image_infos.push(info);
+                merged_files.push(file_path.clone());
+            }
             Err(e) => {
-                eprintln!("Warning: Failed to load {}: {}", file_path.display(), e);
-                continue;
+                skipped_files.push((file_path.clone(), e.to_string()));
+                unreadable.push(e);
             }
         }
     }
 
     if image_infos.is_empty() {
-        return Err("No valid images could be loaded".into());
+        return Err(Error::NoDecodableImages);
     }
 
-    // Determine merge orientation based on majority orientation
-    let orientation = determine_merge_orientation(&image_infos);
+    // Place the images with the selected layout, or one based on majority orientation
+    let dimensions: Vec<(u32, u32)> = image_infos.iter().map(|info| (info.width, info.height)).collect();
+    let layout = options.layout_for(&dimensions);
+    let arrangement = options.arrange(layout.as_ref(), &dimensions);
 
     // Perform the merge
-    let merged_image = match orientation {
-        MergeOrientation::Horizontal => merge_horizontally(&image_infos)?,
-        MergeOrientation::Vertical => merge_vertically(&image_infos)?,
+    let images: Vec<DynamicImage> = image_infos.into_iter().map(|info| info.image).collect();
+    let captions: Vec<String> = merged_files.iter().enumerate()
+        .map(|(index, path)| options.style.captions.text(index, Some(path)))
+        .collect();
+    let merged_image = compositor::render_captioned(&images, &arrangement, &options.style, &captions);
+    let header_lines = header_lines(directory, part_files, merged_files.len(), options)?;
+    let merged_image = header::add_header(merged_image, &header_lines, options.style.background);
+    let merged_image = finish(merged_image, Some(directory), options);
+
+    // Record what was merged so later runs recognise the file as ours
+    let skipped_paths: Vec<PathBuf> = skipped_files.iter().map(|(path, _)| path.clone()).collect();
+    let provenance = Provenance::new(layout.name(), &merged_files)
+        .settings(options.settings_fingerprint())
+        .skipped(&skipped_paths);
+
+    // Encode within the size budget, which may change the format and with it the file name
+    let metadata = pdf_metadata(directory, &merged_files, options)?;
+    let (encoded, format) = encode_merged(&merged_image, &images, &metadata, &provenance, options, fitted)?;
+    let output_path = if format == options.encoding.format {
+        output_path
+    } else {
+        let fallback_path = part_output_path(directory, image_files, format, part)?;
+        ensure_output_path_is_ours(&fallback_path, options.matching)?;
+        fallback_path
+    };
+
+    // Write next to the output, replacing it only when the other parts are written as well
+    let staged = output::stage_encoded(&encoded, &output_path)?;
+
+    let plan = MergePlan {
+        output_path: output_path.clone(),
+        sources: merged_files.into_iter().zip(&dimensions).map(|(path, (width, height))| (path, *width, *height)).collect(),
+        unreadable: skipped_files,
+        output_width: merged_image.width(),
+        output_height: merged_image.height(),
+        existing_merged_files: Vec::new(),
+        scores: scoring::rank_layouts(&LayoutRegistry::default(), &dimensions, &options.layout_settings()),
+        layout,
     };
+    Ok(PartOutput::Staged(staged, plan))
+}
+
+/// Encode a merged image in the selected format within the size budget, returning the format
+/// written; a PDF holds the sources as well, and is replaced by an image if it is over the budget
+fn encode_merged(
+    merged_image: &DynamicImage,
+    sources: &[DynamicImage],
+    metadata: &PdfMetadata,
+    provenance: &Provenance,
+    options: &MergeOptions,
+    fitted: &mut Vec<Fitted>,
+) -> Result<(Vec<u8>, OutputFormat), Error> {
+    let mut encoding = options.encoding;
+    if encoding.format == OutputFormat::Pdf {
+        let encoded = pdf::encode_pdf(merged_image, sources, &options.pdf, encoding.dpi, metadata, provenance);
+        let bytes = encoded.len() as u64;
+        match options.max_bytes {
+            Some(max_bytes) if bytes > max_bytes => encoding.format = budget::fallback_format(encoding.format),
+            Some(_) => {
+                let (width, height) = (merged_image.width(), merged_image.height());
+                fitted.push(Fitted { width, height, encoding, bytes });
+                return Ok((encoded, OutputFormat::Pdf));
+            }
+            None => return Ok((encoded, OutputFormat::Pdf)),
+        }
+    }
+
+    match options.max_bytes {
+        Some(max_bytes) => {
+            let (encoded, fit) = budget::fit_to_budget(merged_image, &encoding, max_bytes, provenance)?;
+            fitted.push(fit);
+            Ok((encoded, fit.encoding.format))
+        }
+        None => Ok((output::encode(merged_image, &encoding, provenance)?, encoding.format)),
+    }
+}
+
+/// Document information of a PDF of some of a directory's sources: the activity title from the
+/// directory name and the evidence date of the sources
+fn pdf_metadata(directory: &Path, part_files: &[PathBuf], options: &MergeOptions) -> Result<PdfMetadata, Error> {
+    if options.encoding.format != OutputFormat::Pdf {
+        return Ok(PdfMetadata::default());
+    }
+    let title = directory.file_name().map(|name| header::parse_title(&name.to_string_lossy()));
+    Ok(PdfMetadata { title, date: Some(find_latest_creation_date(part_files)?) })
+}
+
+/// Split sources into groups of at most `max_per_output` images, as evenly sized as possible and
+/// in their original order; all sources form one group without a maximum
+pub fn split_sources(image_files: &[PathBuf], max_per_output: Option<usize>) -> Vec<&[PathBuf]> {
+    let Some(max_per_output) = max_per_output.filter(|max| *max > 0 && image_files.len() > *max) else {
+        return vec![image_files];
+    };
+
+    let count = image_files.len().div_ceil(max_per_output);
+    let (size, larger) = (image_files.len() / count, image_files.len() % count);
+    let mut parts = Vec::with_capacity(count);
+    let mut rest = image_files;
+    for index in 0..count {
+        let (part, remainder) = rest.split_at(size + usize::from(index < larger));
+        parts.push(part);
+        rest = remainder;
+    }
+    parts
+}
+
+/// The output for `part` if it is current, in the selected format, within the size budget and
+/// was made from exactly these sources (those that could be decoded, the others recorded as
+/// skipped) with the same settings, and the selected layout if one was given
+fn unchanged_output(
+    directory: &Path,
+    image_files: &[PathBuf],
+    part_files: &[PathBuf],
+    part: Option<usize>,
+    options: &MergeOptions,
+) -> Result<Option<PathBuf>, Error> {
+    // A size budget may have made a lossless format fall back to a lossy one
+    let mut formats = vec![options.encoding.format];
+    if options.max_bytes.is_some() {
+        formats.push(budget::fallback_format(options.encoding.format));
+    }
+    let mut existing = None;
+    for format in formats {
+        let path = part_output_path(directory, image_files, format, part)?;
+        if path.is_file() {
+            existing = Some(path);
+            break;
+        }
+    }
+    let Some(path) = existing else {
+        return Ok(None);
+    };
+
+    let metadata = fs::metadata(&path)?;
+    if options.max_bytes.is_some_and(|max_bytes| metadata.len() > max_bytes) {
+        return Ok(None);
+    }
+    let merged_time = metadata.modified()?;
+    for file_path in part_files {
+        if fs::metadata(file_path)?.modified()? > merged_time {
+            return Ok(None);
+        }
+    }
+
+    let expected_sources = Provenance::new("", part_files).sources;
+    let matches = provenance::read_marker(&path).is_some_and(|marker| {
+        let decoded = expected_sources.iter().filter(|source| !marker.skipped.contains(source));
+        decoded.eq(marker.sources.iter())
+            && marker.skipped.iter().all(|source| expected_sources.contains(source))
+            && marker.settings == options.settings_fingerprint()
+            && options.layout.as_ref().is_none_or(|layout| layout.name() == marker.layout)
+    });
+    Ok(matches.then_some(path))
+}
+
+/// Describes what merging a directory would do, without touching disk, or what a merge did
+#[derive(Debug)]
+pub struct MergePlan {
+    pub output_path: PathBuf,
+    pub layout: Arc<dyn Layout>,
+    pub sources: Vec<(PathBuf, u32, u32)>,   // Readable sources in merge order with their dimensions
+    pub unreadable: Vec<(PathBuf, String)>,  // Sources that would be skipped, with the reason
+    pub output_width: u32,
+    pub output_height: u32,
+    pub existing_merged_files: Vec<PathBuf>, // Merged files that would be deleted
+    pub scores: Vec<LayoutScore>,            // Every built-in layout scored for these sources, best first
+}
+
+/// Plan the merge of a directory by reading only image dimensions
+pub fn plan_merge(
+    directory: &Path,
+    image_files: &[PathBuf],
+    options: &MergeOptions,
+) -> Result<MergePlan, Error> {
+    check_plannable(image_files)?;
+    let output_path = output_path(directory, image_files, options.encoding.format)?;
+    ensure_output_path_is_ours(&output_path, options.matching)?;
+    let existing_merged_files = find_existing_merged_files(directory, options.matching)?;
+    plan_output(directory, image_files, output_path, existing_merged_files, options)
+}
+
+/// Plan the merge of a directory like `plan_merge`, with one plan per numbered output when
+/// `MergeOptions::max_per_output` splits the sources
+///
+/// Every plan lists the same merged files that would be deleted.
+pub fn plan_merge_parts(
+    directory: &Path,
+    image_files: &[PathBuf],
+    options: &MergeOptions,
+) -> Result<Vec<MergePlan>, Error> {
+    check_plannable(image_files)?;
+    let parts = split_sources(image_files, options.max_per_output);
+    if parts.len() == 1 {
+        return Ok(vec![plan_merge(directory, image_files, options)?]);
+    }
+
+    let existing_merged_files = find_existing_merged_files(directory, options.matching)?;
+    let mut plans = Vec::new();
+    for (index, part_files) in parts.iter().enumerate() {
+        let output_path = part_output_path(directory, image_files, options.encoding.format, Some(index + 1))?;
+        ensure_output_path_is_ours(&output_path, options.matching)?;
+        plans.push(plan_output(directory, part_files, output_path, existing_merged_files.clone(), options)?);
+    }
+    Ok(plans)
+}
+
+/// Refuse to plan a merge of fewer than two images
+fn check_plannable(image_files: &[PathBuf]) -> Result<(), Error> {
+    if image_files.is_empty() {
+        return Err(Error::NoImages);
+    }
+    
+    if image_files.len() <= 1 {
+        return Err(Error::TooFewImages { found: image_files.len() });
+    }
+    Ok(())
+}
+
+/// Plan merging some of a directory's sources into one output
+fn plan_output(
+    directory: &Path,
+    image_files: &[PathBuf],
+    output_path: PathBuf,
+    existing_merged_files: Vec<PathBuf>,
+    options: &MergeOptions,
+) -> Result<MergePlan, Error> {
+    let mut sources = Vec::new();
+    let mut unreadable = Vec::new();
+    for file_path in image_files {
+        match scanner::open_image(file_path).and_then(|reader| reader.into_dimensions()) {
+            Ok((width, height)) => sources.push((file_path.clone(), width, height)),
+            Err(e) => unreadable.push((file_path.clone(), e.to_string())),
+        }
+    }
+
+    if sources.is_empty() {
+        return Err(Error::NoDecodableImages);
+    }
+
+    let dimensions: Vec<(u32, u32)> = sources.iter().map(|(_, width, height)| (*width, *height)).collect();
+    let layout = options.layout_for(&dimensions);
+    let arrangement = options.arrange(layout.as_ref(), &dimensions);
+    let (output_width, output_height) = options.style.canvas_size(&arrangement);
+    let header_lines = header_lines(directory, image_files, sources.len(), options)?;
+    let footer = options.stamp.footer_text(Some(directory), Local::now());
+    let output_height = output_height
+        + header::height(&header_lines, output_width)
+        + stamp::footer_height(footer.as_deref(), output_width);
+    let (output_width, output_height) = options.output_size(output_width, output_height);
+
+    Ok(MergePlan {
+        output_path,
+        layout,
+        sources,
+        unreadable,
+        output_width,
+        output_height,
+        existing_merged_files,
+        scores: scoring::rank_layouts(&LayoutRegistry::default(), &dimensions, &options.layout_settings()),
+    })
+}
+
+/// Build the merged file path for a directory (merged-yy-mm-dd.png, dated by the newest source,
+/// with the extension of the output format)
+pub fn output_path(directory: &Path, image_files: &[PathBuf], format: OutputFormat) -> Result<PathBuf, Error> {
+    part_output_path(directory, image_files, format, None)
+}
+
+/// Build the path of a numbered merged file (merged-yy-mm-dd-2.png for part 2), dated by the
+/// newest of all the directory's sources, or the unnumbered path for `None`
+pub fn part_output_path(
+    directory: &Path,
+    image_files: &[PathBuf],
+    format: OutputFormat,
+    part: Option<usize>,
+) -> Result<PathBuf, Error> {
+    let latest_date = find_latest_creation_date(image_files)?;
+    let date_string = latest_date.format("%y-%m-%d").to_string();
+    let suffix = part.map(|part| format!("-{}", part)).unwrap_or_default();
+    Ok(directory.join(format!("merged-{}{}.{}", date_string, suffix, format.extension())))
+}
 
-    // Save the result
-    merged_image.save(&output_path)?;
+/// Lines of the banner above a directory's composite of `count` images, dated like its output
+fn header_lines(
+    directory: &Path,
+    image_files: &[PathBuf],
+    count: usize,
+    options: &MergeOptions,
+) -> Result<Vec<String>, Error> {
+    let header = &options.style.header;
+    if !header.is_enabled() {
+        return Ok(Vec::new());
+    }
+    let date = if header.date { Some(find_latest_creation_date(image_files)?) } else { None };
+    Ok(header.lines(Some(directory), date, count))
+}
 
+/// Add the footer below a merged image, shrink it to the size limit, draw the watermark across it,
+/// dated today, and centre it on the print page; the watermark comes after shrinking so it stays
+/// sharp at the final size
+fn finish(merged_image: DynamicImage, directory: Option<&Path>, options: &MergeOptions) -> DynamicImage {
+    let merge_date = Local::now();
+    let footer = options.stamp.footer_text(directory, merge_date);
+    let merged_image = stamp::add_footer(merged_image, footer.as_deref(), options.style.background);
+    let size_limit = options.size_limit_for(merged_image.width(), merged_image.height());
+    let merged_image = limit::downscale(merged_image, &size_limit);
+    let watermark = options.stamp.watermark_text(directory, merge_date);
+    let merged_image = stamp::add_watermark(merged_image, watermark.as_deref(), options.stamp.watermark_opacity);
+    match (options.print, options.encoding.dpi) {
+        (Some(page), Some(dpi)) => print::place_on_page(merged_image, &page, dpi, options.style.background),
+        _ => merged_image,
+    }
+}
+
+/// Refuse to overwrite a file at the output path that picmrg did not create
+fn ensure_output_path_is_ours(output_path: &Path, matching: MergedFileMatching) -> Result<(), Error> {
+    if output_path.exists() && !provenance::is_picmrg_output(output_path, matching) {
+        return Err(Error::ForeignOutput { path: output_path.to_path_buf() });
+    }
     Ok(())
 }
 
+/// Describes whether a directory's merged files reflect its current source images
+#[derive(Debug)]
+pub enum MergeStatus {
+    Single,                // Only one image, nothing to merge
+    Missing,               // No merged file with the expected name
+    Stale(Vec<PathBuf>),   // Merged files exist but a source was modified after one of them
+    Current(Vec<PathBuf>), // Merged files exist and are newer than every source
+}
+
+/// Determine the merge status of a directory without modifying anything
+///
+/// The merged files are the unnumbered output or, if the sources were split, every numbered part.
+/// Only files recognised as picmrg output under `matching` count; a user's own file with the
+/// expected name leaves the directory unmerged.
+pub fn merge_status(
+    directory: &Path,
+    image_files: &[PathBuf],
+    matching: MergedFileMatching,
+) -> Result<MergeStatus, Error> {
+    if image_files.len() <= 1 {
+        return Ok(MergeStatus::Single);
+    }
+
+    let outputs = existing_outputs(directory, image_files, matching)?;
+    if outputs.is_empty() {
+        return Ok(MergeStatus::Missing);
+    }
+
+    for output in &outputs {
+        let merged_time = fs::metadata(output)?.modified()?;
+        for file_path in image_files {
+            if fs::metadata(file_path)?.modified()? > merged_time {
+                return Ok(MergeStatus::Stale(outputs));
+            }
+        }
+    }
+
+    Ok(MergeStatus::Current(outputs))
+}
+
+/// The directory's merged files with the expected names: the unnumbered output, or the numbered
+/// parts from 1 up to the first missing one, in whichever output format they were written
+fn existing_outputs(
+    directory: &Path,
+    image_files: &[PathBuf],
+    matching: MergedFileMatching,
+) -> Result<Vec<PathBuf>, Error> {
+    let is_output = |path: &Path| path.is_file() && provenance::is_picmrg_output(path, matching);
+    for format in OutputFormat::ALL {
+        let path = output_path(directory, image_files, format)?;
+        if is_output(&path) {
+            return Ok(vec![path]);
+        }
+
+        let mut parts = Vec::new();
+        loop {
+            let path = part_output_path(directory, image_files, format, Some(parts.len() + 1))?;
+            if !is_output(&path) {
+                break;
+            }
+            parts.push(path);
+        }
+        if !parts.is_empty() {
+            return Ok(parts);
+        }
+    }
+    Ok(Vec::new())
+}
+
 /// Find the latest creation date among the image files
-fn find_latest_creation_date(image_files: &[PathBuf]) -> Result<DateTime<Local>, Box<dyn std::error::Error>> {
+fn find_latest_creation_date(image_files: &[PathBuf]) -> Result<DateTime<Local>, Error> {
     let mut latest_date: Option<DateTime<Local>> = None;
 
     for file_path in image_files {
@@ -93,63 +643,49 @@ fn find_latest_creation_date(image_files: &[PathBuf]) -> Result<DateTime<Local>,
         }
     }
 
-    latest_date.ok_or_else(|| "No valid dates found".into())
+    latest_date.ok_or(Error::NoImages)
 }
 
-/// Remove any existing merged files in the directory
-fn remove_existing_merged_files(directory: &Path) -> Result<(), Box<dyn std::error::Error>> {
+/// Find any existing merged files in the directory: picmrg outputs that still have a merged file name
+pub fn find_existing_merged_files(
+    directory: &Path,
+    matching: MergedFileMatching,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut merged_files = Vec::new();
     let entries = fs::read_dir(directory)?;
     
     for entry in entries {
         let entry = entry?;
         let path = entry.path();
         
-        if path.is_file() {
-            if let Some(file_name) = path.file_name() {
-                if let Some(name_str) = file_name.to_str() {
-                    if is_merged_file(name_str) {
-                        fs::remove_file(&path)?;
-                    }
-                }
-            }
+        if path.is_file() && provenance::is_replaceable_output(&path, matching) {
+            merged_files.push(path);
         }
     }
     
-    Ok(())
+    merged_files.sort();
+    Ok(merged_files)
 }
 
-/// Check if a filename is a merged file (merged.png or merged-yy-mm-dd.png)
-fn is_merged_file(filename: &str) -> bool {
-    if filename == "merged.png" {
-        return true;
-    }
+/// Remove any existing merged files in the directory, returning the removed paths
+pub fn remove_existing_merged_files(
+    directory: &Path,
+    matching: MergedFileMatching,
+) -> Result<Vec<PathBuf>, Error> {
+    let merged_files = find_existing_merged_files(directory, matching)?;
     
-    // Check for merged-yy-mm-dd.png pattern
-    if filename.starts_with("merged-") && filename.ends_with(".png") {
-        let date_part = &filename[7..filename.len()-4]; // Remove "merged-" and ".png"
-        
-        // Check if it matches yy-mm-dd pattern (8 characters with dashes at positions 2 and 5)
-        if date_part.len() == 8 {
-            let chars: Vec<char> = date_part.chars().collect();
-            if chars[2] == '-' && chars[5] == '-' {
-                // Check if other characters are digits
-                let year_part = &date_part[0..2];
-                let month_part = &date_part[3..5];
-                let day_part = &date_part[6..8];
-                
-                return year_part.chars().all(|c| c.is_ascii_digit()) &&
-                       month_part.chars().all(|c| c.is_ascii_digit()) &&
-                       day_part.chars().all(|c| c.is_ascii_digit());
-            }
-        }
+    for path in &merged_files {
+        fs::remove_file(path)?;
     }
     
-    false
+    Ok(merged_files)
 }
 
-/// Load an image and extract its information
-fn load_image_info(file_path: &Path) -> Result<ImageInfo, Box<dyn std::error::Error>> {
-    let image = image::open(file_path)?;
+/// Load an image, recognised by its content rather than its extension, and extract its information
+pub fn load_image_info(file_path: &Path) -> Result<ImageInfo, Error> {
+    let image = scanner::open_image(file_path)
+        .and_then(|reader| reader.decode())
+        .map_err(|e| Error::from_image(file_path.to_path_buf(), e))?;
     let width = image.width();
     let height = image.height();
     let is_vertical = height > width;
@@ -162,192 +698,19 @@ fn load_image_info(file_path: &Path) -> Result<ImageInfo, Box<dyn std::error::Er
     })
 }
 
-/// Determine merge orientation based on majority of image orientations
-fn determine_merge_orientation(image_infos: &[ImageInfo]) -> MergeOrientation {
-    let vertical_count = image_infos.iter().filter(|info| info.is_vertical).count();
-    let horizontal_count = image_infos.len() - vertical_count;
-
-    if vertical_count > horizontal_count {
-        MergeOrientation::Horizontal // Vertical images -> horizontal layout
-    } else {
-        MergeOrientation::Vertical // Horizontal images -> vertical layout
-    }
-}
-
-/// Merge images horizontally (side by side)
-fn merge_horizontally(image_infos: &[ImageInfo]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
-    // Find the tallest height
-    let target_height = image_infos.iter().map(|info| info.height).max().unwrap();
-    
-    // Calculate total width needed
-    let mut total_width = 0u32;
-    let mut resized_images = Vec::new();
-
-    for info in image_infos {
-        let resized = resize_to_height(&info.image, target_height);
-        total_width += resized.width();
-        resized_images.push(resized);
-    }
-
-    // Create the output image
-    let mut output: RgbaImage = ImageBuffer::new(total_width, target_height);
-    
-    let mut x_offset = 0;
-    for resized_image in resized_images {
-        let rgba_image = resized_image.to_rgba8();
-        
-        for (x, y, pixel) in rgba_image.enumerate_pixels() {
-            output.put_pixel(x_offset + x, y, *pixel);
-        }
-        
-        x_offset += resized_image.width();
-    }
-
-    Ok(DynamicImage::ImageRgba8(output))
-}
-
-/// Merge images vertically (stacked)
-fn merge_vertically(image_infos: &[ImageInfo]) -> Result<DynamicImage, Box<dyn std::error::Error>> {
-    // Find the widest width
-    let target_width = image_infos.iter().map(|info| info.width).max().unwrap();
-    
-    // Calculate total height needed
-    let mut total_height = 0u32;
-    let mut resized_images = Vec::new();
-
-    for info in image_infos {
-        let resized = resize_to_width(&info.image, target_width);
-        total_height += resized.height();
-        resized_images.push(resized);
-    }
-
-    // Create the output image
-    let mut output: RgbaImage = ImageBuffer::new(target_width, total_height);
-    
-    let mut y_offset = 0;
-    for resized_image in resized_images {
-        let rgba_image = resized_image.to_rgba8();
-        
-        for (x, y, pixel) in rgba_image.enumerate_pixels() {
-            output.put_pixel(x, y_offset + y, *pixel);
-        }
-        
-        y_offset += resized_image.height();
-    }
-
-    Ok(DynamicImage::ImageRgba8(output))
-}
-
-/// Resize image to match target height while maintaining aspect ratio
-fn resize_to_height(image: &DynamicImage, target_height: u32) -> DynamicImage {
-    if image.height() == target_height {
-        return image.clone();
-    }
-    
-    let aspect_ratio = image.width() as f32 / image.height() as f32;
-    let target_width = (target_height as f32 * aspect_ratio) as u32;
-    
-    image.resize(target_width, target_height, image::imageops::FilterType::Lanczos3)
-}
-
-/// Resize image to match target width while maintaining aspect ratio
-fn resize_to_width(image: &DynamicImage, target_width: u32) -> DynamicImage {
-    if image.width() == target_width {
-        return image.clone();
-    }
-    
-    let aspect_ratio = image.height() as f32 / image.width() as f32;
-    let target_height = (target_width as f32 * aspect_ratio) as u32;
-    
-    image.resize(target_width, target_height, image::imageops::FilterType::Lanczos3)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_utils::{setup_test_data_for_test, cleanup_test_data_for_test, generate_test_image};
+    use crate::caption::{CaptionPosition, Captions};
+    use crate::compositor::Style;
+    use crate::header::{Header, HeaderTitle};
+    use crate::layout::{Alignment, Horizontal, LayoutSettings, Scaling, Vertical};
+    use crate::pdf::{PageSize, PdfSettings};
+    use crate::print::PrintPage;
+    use crate::provenance::read_marker;
+    use crate::test_utils::{setup_test_data_for_test, cleanup_test_data_for_test, generate_test_image, save_test_image};
     use std::path::Path;
     
-    #[test]
-    fn test_determine_merge_orientation() {
-        // Create test image infos
-        let vertical_images = vec![
-            ImageInfo {
-                image: generate_test_image(100, 200, [255, 0, 0]),
-                width: 100,
-                height: 200,
-                is_vertical: true,
-            },
-            ImageInfo {
-                image: generate_test_image(150, 300, [0, 255, 0]),
-                width: 150,
-                height: 300,
-                is_vertical: true,
-            },
-        ];
-        
-        let horizontal_images = vec![
-            ImageInfo {
-                image: generate_test_image(300, 150, [255, 0, 0]),
-                width: 300,
-                height: 150,
-                is_vertical: false,
-            },
-            ImageInfo {
-                image: generate_test_image(400, 200, [0, 255, 0]),
-                width: 400,
-                height: 200,
-                is_vertical: false,
-            },
-        ];
-        
-        let mixed_images = vec![
-            ImageInfo {
-                image: generate_test_image(100, 200, [255, 0, 0]),
-                width: 100,
-                height: 200,
-                is_vertical: true,
-            },
-            ImageInfo {
-                image: generate_test_image(300, 150, [0, 255, 0]),
-                width: 300,
-                height: 150,
-                is_vertical: false,
-            },
-            ImageInfo {
-                image: generate_test_image(200, 100, [0, 0, 255]),
-                width: 200,
-                height: 100,
-                is_vertical: false,
-            },
-        ];
-        
-        // Test majority vertical -> horizontal merge
-        assert!(matches!(determine_merge_orientation(&vertical_images), MergeOrientation::Horizontal));
-        
-        // Test majority horizontal -> vertical merge
-        assert!(matches!(determine_merge_orientation(&horizontal_images), MergeOrientation::Vertical));
-        
-        // Test mixed with majority horizontal -> vertical merge
-        assert!(matches!(determine_merge_orientation(&mixed_images), MergeOrientation::Vertical));
-    }
-    
-    #[test]
-    fn test_is_merged_file() {
-        // Test basic merged file
-        assert!(is_merged_file("merged.png"));
-        
-        // Test dated merged files
-        assert!(is_merged_file("merged-23-12-25.png"));
-        assert!(is_merged_file("merged-24-01-15.png"));
-        
-        // Test invalid patterns
-        assert!(!is_merged_file("merged.jpg"));
-        assert!(!is_merged_file("merged-2023-12-25.png"));
-        assert!(!is_merged_file("merged-23-1-25.png"));
-        assert!(!is_merged_file("other.png"));
-    }
-    
     #[test]
     fn test_load_image_info() {
         let test_root = setup_test_data_for_test("load_info").expect("Failed to setup test data");
@@ -373,39 +736,15 @@ mod tests {
         assert_eq!(square_info.height, 200);
         assert!(!square_info.is_vertical); // height == width, so not vertical
         
-        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
-    }
-    
-    #[test]
-    fn test_resize_to_height() {
-        let image = generate_test_image(100, 200, [255, 0, 0]);
-        
-        // Test resizing to same height (should return clone)
-        let same_height = resize_to_height(&image, 200);
-        assert_eq!(same_height.width(), 100);
-        assert_eq!(same_height.height(), 200);
-        
-        // Test resizing to different height (should maintain aspect ratio)
-        let resized = resize_to_height(&image, 400);
-        assert_eq!(resized.height(), 400);
-        // Aspect ratio: 100/200 = 0.5, so new width should be 400 * 0.5 = 200
-        assert_eq!(resized.width(), 200);
-    }
-    
-    #[test]
-    fn test_resize_to_width() {
-        let image = generate_test_image(200, 100, [0, 255, 0]);
-        
-        // Test resizing to same width (should return clone)
-        let same_width = resize_to_width(&image, 200);
-        assert_eq!(same_width.width(), 200);
-        assert_eq!(same_width.height(), 100);
+        // Test loading images by their content, without or against their extension
+        let unnamed_path = Path::new(&test_root).join("vertical-images/screenshot");
+        fs::copy(&vertical_path, &unnamed_path).expect("Failed to copy file");
+        assert_eq!(load_image_info(&unnamed_path).expect("Failed to load unnamed image").width, 200);
+        let misnamed_path = Path::new(&test_root).join("vertical-images/green.png");
+        fs::copy(Path::new(&test_root).join("vertical-images/green.jpg"), &misnamed_path).expect("Failed to copy file");
+        assert_eq!(load_image_info(&misnamed_path).expect("Failed to load misnamed image").width, 150);
         
-        // Test resizing to different width (should maintain aspect ratio)
-        let resized = resize_to_width(&image, 400);
-        assert_eq!(resized.width(), 400);
-        // Aspect ratio: 100/200 = 0.5, so new height should be 400 * 0.5 = 200
-        assert_eq!(resized.height(), 200);
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
     }
     
     #[test]
@@ -425,7 +764,9 @@ mod tests {
             },
         ];
         
-        let merged = merge_horizontally(&image_infos).expect("Failed to merge horizontally");
+        let dimensions: Vec<(u32, u32)> = image_infos.iter().map(|info| (info.width, info.height)).collect();
+        let images: Vec<DynamicImage> = image_infos.into_iter().map(|info| info.image).collect();
+        let merged = compositor::render(&images, &Horizontal.arrange(&dimensions, &LayoutSettings::default()), &Style::default());
         
         // Should use the tallest height (300) and sum up widths proportionally
         assert_eq!(merged.height(), 300);
@@ -452,7 +793,9 @@ mod tests {
             },
         ];
         
-        let merged = merge_vertically(&image_infos).expect("Failed to merge vertically");
+        let dimensions: Vec<(u32, u32)> = image_infos.iter().map(|info| (info.width, info.height)).collect();
+        let images: Vec<DynamicImage> = image_infos.into_iter().map(|info| info.image).collect();
+        let merged = compositor::render(&images, &Vertical.arrange(&dimensions, &LayoutSettings::default()), &Style::default());
         
         // Should use the widest width (300) and sum up heights proportionally
         assert_eq!(merged.width(), 300);
@@ -462,6 +805,65 @@ mod tests {
         assert_eq!(merged.height(), 300);
     }
     
+    #[test]
+    fn test_arrangement_size_matches_merge() {
+        let image_infos = vec![
+            ImageInfo {
+                image: generate_test_image(100, 200, [255, 0, 0]),
+                width: 100,
+                height: 200,
+                is_vertical: true,
+            },
+            ImageInfo {
+                image: generate_test_image(170, 333, [0, 255, 0]),
+                width: 170,
+                height: 333,
+                is_vertical: true,
+            },
+        ];
+        let dimensions: Vec<(u32, u32)> = image_infos.iter().map(|info| (info.width, info.height)).collect();
+        
+        let images: Vec<DynamicImage> = image_infos.into_iter().map(|info| info.image).collect();
+        
+        let arrangement = Horizontal.arrange(&dimensions, &LayoutSettings::default());
+        let merged = compositor::render(&images, &arrangement, &Style::default());
+        assert_eq!((arrangement.width, arrangement.height), (merged.width(), merged.height()));
+        
+        let arrangement = Vertical.arrange(&dimensions, &LayoutSettings::default());
+        let merged = compositor::render(&images, &arrangement, &Style::default());
+        assert_eq!((arrangement.width, arrangement.height), (merged.width(), merged.height()));
+    }
+    
+    #[test]
+    fn test_plan_merge_does_not_touch_disk() {
+        let test_root = setup_test_data_for_test("plan").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![
+            vertical_dir.join("blue.jpeg"),
+            vertical_dir.join("green.jpg"),
+            vertical_dir.join("red.png"),
+        ];
+        
+        let options = MergeOptions::new().layout(Arc::new(Horizontal));
+        let plan = plan_merge(&vertical_dir, &image_files, &options).expect("Failed to plan merge");
+        
+        assert_eq!(plan.layout.name(), "horizontal");
+        assert_eq!(plan.sources.len(), 3);
+        assert_eq!(plan.sources[0].0, image_files[0]);
+        assert!(plan.unreadable.is_empty());
+        // All images scaled to the tallest height (420)
+        assert_eq!(plan.output_height, 420);
+        assert_eq!(plan.existing_merged_files, vec![vertical_dir.join("merged.png")]);
+        assert_eq!(plan.scores.len(), LayoutRegistry::default().layouts().count());
+        
+        // Nothing was deleted or written
+        assert!(vertical_dir.join("merged.png").exists());
+        assert!(!plan.output_path.exists());
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
     #[test]
     fn test_merge_images_in_directory_single_image() {
         let test_root = setup_test_data_for_test("single").expect("Failed to setup test data");
@@ -469,9 +871,8 @@ mod tests {
         let single_dir = Path::new(&test_root).join("single-image");
         let image_files = vec![single_dir.join("orange.png")];
         
-        let result = merge_images_in_directory(&single_dir, &image_files);
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("Only one image file"));
+        let result = merge_images_in_directory(&single_dir, &image_files, &MergeOptions::new()).result;
+        assert!(matches!(result, Ok(MergeOutcome::Skipped)));
         
         cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
     }
@@ -483,8 +884,8 @@ mod tests {
         let empty_dir = Path::new(&test_root).join("empty-dir");
         let image_files: Vec<PathBuf> = vec![];
         
-        let result = merge_images_in_directory(&empty_dir, &image_files);
-        assert!(result.is_err());
+        let result = merge_images_in_directory(&empty_dir, &image_files, &MergeOptions::new()).result;
+        assert!(matches!(result, Err(Error::NoImages)));
         assert!(result.unwrap_err().to_string().contains("No image files to merge"));
         
         cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
@@ -502,9 +903,9 @@ mod tests {
         ];
         
         // Remove any existing merged files first
-        let _ = remove_existing_merged_files(&vertical_dir);
+        let _ = remove_existing_merged_files(&vertical_dir, MergedFileMatching::Marker);
         
-        let result = merge_images_in_directory(&vertical_dir, &image_files);
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result;
         assert!(result.is_ok(), "Failed to merge images: {:?}", result);
         
         // Check that a merged file was created
@@ -532,6 +933,610 @@ mod tests {
         cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
     }
     
+    #[test]
+    fn test_merge_images_in_directory_failure_keeps_previous_merge() {
+        let test_root = setup_test_data_for_test("keep_previous").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        std::fs::write(vertical_dir.join("broken.png"), "not an image").expect("Failed to write file");
+        std::fs::write(vertical_dir.join("corrupt.png"), "not an image").expect("Failed to write file");
+        let image_files = vec![vertical_dir.join("broken.png"), vertical_dir.join("corrupt.png")];
+        
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result;
+        assert!(matches!(result, Err(Error::NoDecodableImages)));
+        
+        // The previous merged file survives a failed merge
+        assert!(vertical_dir.join("merged.png").exists());
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_replaces_previous_merge() {
+        let test_root = setup_test_data_for_test("replace_previous").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        
+        merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result.expect("Failed to merge images");
+        
+        let merged_files = find_existing_merged_files(&vertical_dir, MergedFileMatching::Marker).expect("Failed to list merged files");
+        assert_eq!(merged_files, vec![output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap()]);
+        
+        // Merging again over an existing output of the same name keeps exactly one file
+        merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result.expect("Failed to merge images again");
+        assert_eq!(find_existing_merged_files(&vertical_dir, MergedFileMatching::Marker).unwrap().len(), 1);
+        
+        // A merged file the user renamed is kept by later merges and `clean`, and is not a source
+        let output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+        let renamed = vertical_dir.join("evidence-for-audit.png");
+        fs::rename(&output, &renamed).expect("Failed to rename file");
+        merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result.expect("Failed to merge images again");
+        assert!(renamed.exists() && output.exists());
+        assert!(!scanner::find_image_files(&vertical_dir, &MergeOptions::new()).unwrap().contains(&renamed));
+        assert_eq!(remove_existing_merged_files(&vertical_dir, MergedFileMatching::Marker).unwrap(), vec![output]);
+        assert!(renamed.exists());
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_records_provenance() {
+        let test_root = setup_test_data_for_test("records_provenance").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("green.jpg"), vertical_dir.join("red.png")];
+        
+        let report = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new());
+        report.result.expect("Failed to merge images");
+        
+        let output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+        let marker = provenance::read_marker(&output).expect("Merged file should carry the picmrg marker");
+        assert_eq!(marker.layout, "horizontal");
+        assert_eq!(marker.sources, vec!["green.jpg".to_string(), "red.png".to_string()]);
+        
+        // The report describes the merge that was made, with the scores that chose its layout
+        let [plan] = report.plans.as_slice() else { panic!("Expected one plan, got {:?}", report.plans) };
+        assert_eq!(plan.output_path, output);
+        assert_eq!(plan.layout.name(), marker.layout);
+        assert_eq!(plan.sources.len(), 2);
+        assert_eq!(plan.scores[0].layout.name(), marker.layout);
+        assert_eq!(plan.existing_merged_files, vec![vertical_dir.join("merged.png")]);
+        let merged = image::open(&output).expect("Failed to open merged file");
+        assert_eq!((plan.output_width, plan.output_height), (merged.width(), merged.height()));
+        
+        // Nothing is laid out when the merge is kept
+        let kept = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new());
+        assert!(matches!(kept.result, Ok(MergeOutcome::Unchanged(_))) && kept.plans.is_empty());
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_spares_unmarked_files() {
+        let test_root = setup_test_data_for_test("spares_unmarked").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        
+        // A user's own file that happens to use a merged file name
+        let users_file = vertical_dir.join("merged-01-01-01.png");
+        std::fs::copy(vertical_dir.join("red.png"), &users_file).expect("Failed to copy file");
+        
+        merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result.expect("Failed to merge images");
+        assert!(users_file.exists(), "Unmarked file must not be deleted");
+        assert!(!vertical_dir.join("merged.png").exists(), "Marked merged file should be replaced");
+        
+        // Refuse to overwrite an unmarked file at the output path
+        let output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+        std::fs::copy(vertical_dir.join("red.png"), &output).expect("Failed to copy file");
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result;
+        assert!(matches!(result, Err(Error::ForeignOutput { .. })));
+        
+        // Legacy name matching treats both as ours
+        merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new().matching(MergedFileMatching::LegacyName)).result.expect("Failed to merge images");
+        assert!(!users_file.exists());
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_unchanged() {
+        let test_root = setup_test_data_for_test("unchanged").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        let output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+        
+        let first = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result;
+        assert_eq!(first.unwrap(), MergeOutcome::Merged(output.clone()));
+        
+        // Same sources and nothing modified since: keep the existing merge
+        let second = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result;
+        assert_eq!(second.unwrap(), MergeOutcome::Unchanged(output.clone()));
+        
+        // Forced or with a different source list: merge again
+        let forced = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new().force(true)).result;
+        assert_eq!(forced.unwrap(), MergeOutcome::Merged(output.clone()));
+        
+        // A different layout than the one recorded: merge again
+        let relaid = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new().layout(Arc::new(Vertical))).result;
+        assert_eq!(relaid.unwrap(), MergeOutcome::Merged(output.clone()));
+        
+        // Different rendering or encoding settings than the ones recorded: merge again, then keep it
+        let restyled = MergeOptions::new().layout(Arc::new(Vertical)).gutter(30).captions(Captions { file_name: true, ..Captions::default() }).watermark("J. Doe", 30);
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &restyled).result;
+        assert_eq!(result.unwrap(), MergeOutcome::Merged(output.clone()));
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &restyled).result;
+        assert_eq!(result.unwrap(), MergeOutcome::Unchanged(output.clone()));
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &restyled.clone().quality(50)).result;
+        assert_eq!(result.unwrap(), MergeOutcome::Unchanged(output.clone()), "PNG ignores the quality");
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &restyled.clone().dpi(300)).result;
+        assert_eq!(result.unwrap(), MergeOutcome::Merged(output.clone()));
+        
+        let more_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg"), vertical_dir.join("blue.jpeg")];
+        let output = output_path(&vertical_dir, &more_files, OutputFormat::Png).unwrap();
+        let changed = merge_images_in_directory(&vertical_dir, &more_files, &MergeOptions::new()).result;
+        assert_eq!(changed.unwrap(), MergeOutcome::Merged(output));
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_unchanged_with_damaged_source() {
+        let test_root = setup_test_data_for_test("unchanged_damaged").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let broken = vertical_dir.join("broken.png");
+        fs::write(&broken, b"\x89PNG\r\n\x1a\njunk").expect("Failed to write file");
+        let image_files = vec![broken.clone(), vertical_dir.join("green.jpg"), vertical_dir.join("red.png")];
+        let output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+        
+        // The damaged file is left out and recorded as skipped
+        let first = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new());
+        assert_eq!(first.result.unwrap(), MergeOutcome::Merged(output.clone()));
+        assert_eq!(first.unreadable.len(), 1);
+        assert_eq!(read_marker(&output).unwrap().skipped, vec!["broken.png".to_string()]);
+        
+        // Nothing changed, so the merge is kept even though a source still cannot be decoded
+        let second = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new());
+        assert_eq!(second.result.unwrap(), MergeOutcome::Unchanged(output.clone()));
+        
+        // Once the file is repaired and newer than the merge, it is merged again
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::copy(vertical_dir.join("red.png"), &broken).expect("Failed to copy file");
+        let repaired = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new());
+        assert_eq!(repaired.result.unwrap(), MergeOutcome::Merged(output.clone()));
+        assert!(read_marker(&output).unwrap().skipped.is_empty());
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_header_and_footer() {
+        let test_root = setup_test_data_for_test("header").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        let header = Header { title: HeaderTitle::Parsed, date: true, count: true };
+        let options = MergeOptions::new().header(header).footer("J. Doe – {title} – {date}");
+        
+        // The plan accounts for the banner above and the footer below the images
+        let plan = plan_merge(&vertical_dir, &image_files, &options).expect("Failed to plan merge");
+        let without_header = plan_merge(&vertical_dir, &image_files, &MergeOptions::new()).expect("Failed to plan merge");
+        assert_eq!(plan.output_width, without_header.output_width);
+        assert!(plan.output_height > without_header.output_height);
+        
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &options).result;
+        let Ok(MergeOutcome::Merged(path)) = result else { panic!("Expected a merge, got {:?}", result) };
+        let merged = image::open(&path).expect("Failed to open merged file");
+        assert_eq!((merged.width(), merged.height()), (plan.output_width, plan.output_height));
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_formats() {
+        let test_root = setup_test_data_for_test("formats").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        let jpeg = MergeOptions::new().format(OutputFormat::Jpeg).quality(70);
+        let jpeg_output = output_path(&vertical_dir, &image_files, OutputFormat::Jpeg).unwrap();
+        assert!(jpeg_output.to_string_lossy().ends_with(".jpg"));
+        
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &jpeg).result;
+        assert_eq!(result.unwrap(), MergeOutcome::Merged(jpeg_output.clone()));
+        assert!(matches!(merge_status(&vertical_dir, &image_files, MergedFileMatching::Marker).unwrap(), MergeStatus::Current(paths) if paths == vec![jpeg_output.clone()]));
+        assert_eq!(plan_merge(&vertical_dir, &image_files, &jpeg).unwrap().output_path, jpeg_output);
+        
+        // The JPEG output is recognised as ours: not a source, and kept while current
+        let sources = scanner::find_image_files(&vertical_dir, &MergeOptions::new()).unwrap();
+        assert!(!sources.contains(&jpeg_output));
+        let again = merge_images_in_directory(&vertical_dir, &image_files, &jpeg).result;
+        assert_eq!(again.unwrap(), MergeOutcome::Unchanged(jpeg_output.clone()));
+        
+        // Switching formats merges again and removes the output in the old format
+        let png_output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new()).result;
+        assert_eq!(result.unwrap(), MergeOutcome::Merged(png_output.clone()));
+        assert!(!jpeg_output.exists());
+        assert_eq!(find_existing_merged_files(&vertical_dir, MergedFileMatching::Marker).unwrap(), vec![png_output]);
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_images_in_directory_pdf() {
+        let test_root = setup_test_data_for_test("pdf").expect("Failed to setup test data");
+
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = scanner::find_image_files(&vertical_dir, &MergeOptions::new()).unwrap();
+        let pdf_output = output_path(&vertical_dir, &image_files, OutputFormat::Pdf).unwrap();
+        let options = MergeOptions::new()
+            .format(OutputFormat::Pdf)
+            .pdf(PdfSettings { include_sources: true, ..PdfSettings::default() });
+
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &options).result;
+        assert_eq!(result.unwrap(), MergeOutcome::Merged(pdf_output.clone()));
+        let pdf = fs::read(&pdf_output).unwrap();
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Count 4"), "Composite and three sources");
+        assert!(text.contains("/Title (vertical-images)"));
+        assert!(text.contains("/CreationDate (D:"));
+
+        // Recognised as ours and kept while current
+        assert_eq!(read_marker(&pdf_output).unwrap().sources.len(), image_files.len());
+        assert!(!scanner::find_image_files(&vertical_dir, &MergeOptions::new()).unwrap().contains(&pdf_output));
+        let again = merge_images_in_directory(&vertical_dir, &image_files, &options).result;
+        assert_eq!(again.unwrap(), MergeOutcome::Unchanged(pdf_output));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_merge_images_in_directory_max_bytes() {
+        let test_root = setup_test_data_for_test("max_bytes").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        let png_output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+        let jpeg_output = output_path(&vertical_dir, &image_files, OutputFormat::Jpeg).unwrap();
+        let unlimited = merge_images_in_directory(&vertical_dir, &image_files, &MergeOptions::new());
+        assert_eq!(unlimited.result.unwrap(), MergeOutcome::Merged(png_output.clone()));
+        assert!(unlimited.fitted.is_empty());
+        let png_size = fs::metadata(&png_output).unwrap().len();
+        
+        // A budget the PNG fits keeps it
+        let roomy = MergeOptions::new().max_bytes(png_size).force(true);
+        let report = merge_images_in_directory(&vertical_dir, &image_files, &roomy);
+        assert_eq!(report.result.unwrap(), MergeOutcome::Merged(png_output.clone()));
+        assert_eq!(report.fitted[0].encoding.format, OutputFormat::Png);
+        
+        // A tighter one falls back to JPEG, renames the output and removes the PNG
+        let tight = MergeOptions::new().max_bytes(png_size - 1);
+        let report = merge_images_in_directory(&vertical_dir, &image_files, &tight);
+        assert_eq!(report.result.unwrap(), MergeOutcome::Merged(jpeg_output.clone()));
+        assert_eq!(report.fitted[0].encoding.format, OutputFormat::Jpeg);
+        assert!(fs::metadata(&jpeg_output).unwrap().len() < png_size);
+        assert!(!png_output.exists());
+        
+        // The fitted JPEG is current for the same budget
+        let again = merge_images_in_directory(&vertical_dir, &image_files, &tight).result;
+        assert_eq!(again.unwrap(), MergeOutcome::Unchanged(jpeg_output));
+        
+        // A budget nothing fits fails
+        let impossible = MergeOptions::new().max_bytes(10);
+        let result = merge_images_in_directory(&vertical_dir, &image_files, &impossible).result;
+        assert!(matches!(result, Err(Error::OverBudget { max_bytes: 10, .. })));
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_split_sources() {
+        let files: Vec<PathBuf> = (1..=20).map(|index| PathBuf::from(format!("{:02}.png", index))).collect();
+        let sizes = |parts: Vec<&[PathBuf]>| parts.iter().map(|part| part.len()).collect::<Vec<_>>();
+
+        // Evenly sized groups rather than full ones and a small remainder
+        assert_eq!(sizes(split_sources(&files, Some(6))), vec![5, 5, 5, 5]);
+        assert_eq!(sizes(split_sources(&files[..7], Some(3))), vec![3, 2, 2]);
+        assert_eq!(split_sources(&files, Some(6))[1][0], files[5]);
+
+        // At most the maximum, or no maximum: a single group
+        assert_eq!(sizes(split_sources(&files, Some(20))), vec![20]);
+        assert_eq!(sizes(split_sources(&files, None)), vec![20]);
+    }
+
+    #[test]
+    fn test_merge_images_in_directory_split() {
+        let test_root = setup_test_data_for_test("split").expect("Failed to setup test data");
+
+        let many_dir = Path::new(&test_root).join("many-images");
+        for index in 1..=5 {
+            let image = generate_test_image(100, 200, [50 * index as u8, 0, 0]);
+            save_test_image(&image, &many_dir.join(format!("screen{}.png", index))).unwrap();
+        }
+        let image_files = scanner::find_image_files(&many_dir, &MergeOptions::new()).unwrap();
+        let single_output = output_path(&many_dir, &image_files, OutputFormat::Png).unwrap();
+        let merged_single = merge_images_in_directory(&many_dir, &image_files, &MergeOptions::new()).result;
+        assert_eq!(merged_single.unwrap(), MergeOutcome::Merged(single_output.clone()));
+
+        // Split into numbered outputs, replacing the single merge
+        let split = MergeOptions::new().max_per_output(3);
+        let parts: Vec<PathBuf> = (1..=2)
+            .map(|part| part_output_path(&many_dir, &image_files, OutputFormat::Png, Some(part)).unwrap())
+            .collect();
+        assert!(parts[0].to_string_lossy().ends_with("-1.png"));
+        let result = merge_images_in_directory(&many_dir, &image_files, &split).result.unwrap();
+        assert_eq!(result, MergeOutcome::Split(parts.iter().cloned().map(MergeOutcome::Merged).collect()));
+        assert_eq!(result.outputs(), parts);
+        assert!(!single_output.exists());
+        assert_eq!(read_marker(&parts[0]).unwrap().sources.len(), 3);
+        assert_eq!(read_marker(&parts[1]).unwrap().sources.len(), 2);
+
+        // The parts are not sources, are found by status and by name, and are kept while current
+        assert_eq!(scanner::find_image_files(&many_dir, &MergeOptions::new()).unwrap(), image_files);
+        assert!(matches!(merge_status(&many_dir, &image_files, MergedFileMatching::Marker).unwrap(), MergeStatus::Current(paths) if paths == parts));
+        assert_eq!(find_existing_merged_files(&many_dir, MergedFileMatching::LegacyName).unwrap(), parts);
+        let again = merge_images_in_directory(&many_dir, &image_files, &split).result.unwrap();
+        assert_eq!(again, MergeOutcome::Split(parts.iter().cloned().map(MergeOutcome::Unchanged).collect()));
+
+        // The dry run plans every part
+        let plans = plan_merge_parts(&many_dir, &image_files, &split).unwrap();
+        assert_eq!(plans.iter().map(|plan| plan.output_path.clone()).collect::<Vec<_>>(), parts);
+        assert_eq!(plans[1].sources.len(), 2);
+
+        // Merging without splitting removes the parts again
+        let merged_single = merge_images_in_directory(&many_dir, &image_files, &MergeOptions::new()).result;
+        assert_eq!(merged_single.unwrap(), MergeOutcome::Merged(single_output.clone()));
+        assert!(parts.iter().all(|part| !part.exists()));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_merge_images_in_directory_split_failure() {
+        let test_root = setup_test_data_for_test("split_failure").expect("Failed to setup test data");
+
+        let many_dir = Path::new(&test_root).join("many-images");
+        for index in 1..=4 {
+            let image = generate_test_image(100, 200, [50 * index as u8, 0, 0]);
+            save_test_image(&image, &many_dir.join(format!("screen{}.png", index))).unwrap();
+        }
+        let image_files = scanner::find_image_files(&many_dir, &MergeOptions::new()).unwrap();
+        let single_output = output_path(&many_dir, &image_files, OutputFormat::Png).unwrap();
+        merge_images_in_directory(&many_dir, &image_files, &MergeOptions::new()).result.expect("Failed to merge images");
+
+        // The second part has no decodable sources: the first part is never moved into place
+        for name in ["screen3.png", "screen4.png"] {
+            fs::write(many_dir.join(name), b"\x89PNG\r\n\x1a\ntruncated").expect("Failed to write file");
+        }
+        let report = merge_images_in_directory(&many_dir, &image_files, &MergeOptions::new().max_per_output(2));
+        assert!(matches!(report.result, Err(Error::NoDecodableImages)), "{:?}", report.result);
+        assert_eq!(report.unreadable.len(), 2);
+        let first_part = part_output_path(&many_dir, &image_files, OutputFormat::Png, Some(1)).unwrap();
+        assert!(!first_part.exists(), "Parts of a failed split must not be left behind");
+        assert!(single_output.exists(), "The previous merge must be kept");
+        assert_eq!(find_existing_merged_files(&many_dir, MergedFileMatching::Marker).unwrap(), vec![single_output]);
+
+        // A failed re-split keeps the previous parts with the same names as they were
+        for index in 3..=4 {
+            let image = generate_test_image(100, 200, [50 * index as u8, 0, 0]);
+            save_test_image(&image, &many_dir.join(format!("screen{}.png", index))).unwrap();
+        }
+        let split = merge_images_in_directory(&many_dir, &image_files, &MergeOptions::new().max_per_output(2));
+        assert!(matches!(split.result, Ok(MergeOutcome::Split(_))), "{:?}", split.result);
+        let previous_part = fs::read(&first_part).expect("Failed to read first part");
+        for name in ["screen3.png", "screen4.png"] {
+            fs::write(many_dir.join(name), b"\x89PNG\r\n\x1a\ntruncated").expect("Failed to write file");
+        }
+        let options = MergeOptions::new().max_per_output(2).gutter(10).force(true);
+        assert!(merge_images_in_directory(&many_dir, &image_files, &options).result.is_err());
+        assert_eq!(fs::read(&first_part).expect("Failed to read first part"), previous_part);
+        let second_part = part_output_path(&many_dir, &image_files, OutputFormat::Png, Some(2)).unwrap();
+        assert!(second_part.exists(), "The previous second part must be kept");
+        let leftovers: Vec<_> = fs::read_dir(&many_dir)
+            .expect("Failed to read directory")
+            .filter_map(|entry| entry.ok()?.file_name().to_str().map(String::from))
+            .filter(|name| output::is_temp_file(name))
+            .collect();
+        assert!(leftovers.is_empty(), "Unexpected temporary files: {:?}", leftovers);
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_merge_status_ignores_foreign_output() {
+        let test_root = setup_test_data_for_test("status_foreign").expect("Failed to setup test data");
+
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        let output = output_path(&vertical_dir, &image_files, OutputFormat::Png).unwrap();
+
+        // A user's own file with the merged name is not a merge, unless legacy names are used
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::copy(vertical_dir.join("red.png"), &output).expect("Failed to copy file");
+        assert!(matches!(merge_status(&vertical_dir, &image_files, MergedFileMatching::Marker).unwrap(), MergeStatus::Missing));
+        assert!(matches!(
+            merge_status(&vertical_dir, &image_files, MergedFileMatching::LegacyName).unwrap(),
+            MergeStatus::Current(paths) if paths == vec![output.clone()]
+        ));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_plan_merge_too_few_images() {
+        let test_root = setup_test_data_for_test("plan_single").expect("Failed to setup test data");
+        
+        let single_dir = Path::new(&test_root).join("single-image");
+        let image_files = vec![single_dir.join("orange.png")];
+        
+        let result = plan_merge(&single_dir, &image_files, &MergeOptions::new());
+        assert!(matches!(result, Err(Error::TooFewImages { found: 1 })));
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_merge_in_memory() {
+        let images = vec![
+            generate_test_image(100, 200, [255, 0, 0]),
+            generate_test_image(150, 300, [0, 255, 0]),
+        ];
+        
+        // Majority vertical -> side by side at the tallest height
+        let merged = merge(&images, &MergeOptions::new());
+        assert_eq!((merged.width(), merged.height()), (300, 300));
+        
+        // An explicitly selected layout overrides the automatic choice
+        let merged = merge(&images, &MergeOptions::new().layout(Arc::new(Vertical)));
+        assert_eq!((merged.width(), merged.height()), (150, 600));
+
+        // The scaling policy applies to the chosen layout
+        let merged = merge(&images, &MergeOptions::new().scaling(Scaling::ToMin));
+        assert_eq!((merged.width(), merged.height()), (200, 200));
+
+        // Native size, top-aligned on a white background
+        let options = MergeOptions::new()
+            .layout(Arc::new(Horizontal))
+            .scaling(Scaling::Native)
+            .alignment(Alignment::Start)
+            .background(image::Rgba([255, 255, 255, 255]));
+        let merged = merge(&images, &options).to_rgba8();
+        assert_eq!(merged.dimensions(), (250, 300));
+        assert_eq!(merged.get_pixel(50, 250).0, [255, 255, 255, 255]);
+        assert_eq!(merged.get_pixel(50, 50).0, [255, 0, 0, 255]);
+
+        // Gutter, border and shadow widen the gap between images; the margin surrounds them
+        let options = MergeOptions::new()
+            .layout(Arc::new(Horizontal))
+            .gutter(4)
+            .margin(10)
+            .border(1, image::Rgba([0, 0, 0, 255]))
+            .shadow(2);
+        let merged = merge(&images, &options);
+        // 150 + 150 wide, 4 + 2 + 2 * 1 between them, 2 * 11 + 2 around them
+        assert_eq!((merged.width(), merged.height()), (300 + 8 + 24, 300 + 24));
+
+        // Captions below the images make the composite taller, overlaid ones do not
+        let captions = Captions { index: true, ..Captions::default() };
+        let options = MergeOptions::new().layout(Arc::new(Horizontal)).captions(captions);
+        assert!(merge(&images, &options).height() > 300);
+        let options = options.captions(Captions { position: CaptionPosition::Overlay, ..captions });
+        assert_eq!(merge(&images, &options).height(), 300);
+
+        // A banner adds height above the composite
+        let header = Header { count: true, ..Header::default() };
+        let merged = merge(&images, &MergeOptions::new().header(header));
+        assert_eq!(merged.width(), 300);
+        assert_eq!(merged.height(), 300 + header::height(&["2 images".to_string()], 300));
+
+        // A footer adds height below it, a watermark none
+        let options = MergeOptions::new().layout(Arc::new(Horizontal)).footer("J. Doe – {date}").watermark("J. Doe", 30);
+        let merged = merge(&images, &options);
+        assert_eq!(merged.height(), 300 + stamp::footer_height(Some("J. Doe"), 300));
+
+        let empty = merge(&[], &MergeOptions::new());
+        assert_eq!((empty.width(), empty.height()), (0, 0));
+    }
+    
+    #[test]
+    fn test_merge_size_limits() {
+        // Eight portrait screenshots side by side would be 800 pixels wide
+        let images: Vec<DynamicImage> = (0..8).map(|_| generate_test_image(100, 200, [255, 0, 0])).collect();
+        let horizontal = MergeOptions::new().layout(Arc::new(Horizontal));
+        let merged = merge(&images, &horizontal.clone().max_width(400));
+        assert_eq!((merged.width(), merged.height()), (400, 100));
+
+        let merged = merge(&images, &horizontal.clone().max_height(50).max_pixels(1_000_000));
+        assert_eq!((merged.width(), merged.height()), (200, 50));
+        let merged = merge(&images, &horizontal.clone().max_pixels(40_000));
+        assert!(merged.width() * merged.height() <= 40_000 && merged.width() >= 280, "{}x{}", merged.width(), merged.height());
+
+        // Margins and a banner that do not shrink with the images are downscaled with the output
+        let options = horizontal.clone().margin(20).header(Header { count: true, ..Header::default() }).max_width(400);
+        let merged = merge(&images, &options);
+        assert_eq!(merged.width(), 400);
+        assert!(merged.height() > 100);
+
+        // Within the limits nothing changes
+        let merged = merge(&images, &horizontal.max_width(1000));
+        assert_eq!((merged.width(), merged.height()), (800, 200));
+    }
+
+    #[test]
+    fn test_plan_merge_size_limits() {
+        let test_root = setup_test_data_for_test("plan_limits").expect("Failed to setup test data");
+
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        let unlimited = plan_merge(&vertical_dir, &image_files, &MergeOptions::new()).unwrap();
+        let max_width = unlimited.output_width / 2;
+        let options = MergeOptions::new().max_width(max_width);
+        let plan = plan_merge(&vertical_dir, &image_files, &options).unwrap();
+        // Shrinking the sources rounds down, which may leave the output a few pixels narrower
+        assert!(plan.output_width <= max_width && plan.output_width + 4 >= max_width, "{}", plan.output_width);
+        assert!(plan.output_height < unlimited.output_height);
+
+        let merged = merge_images_in_directory(&vertical_dir, &image_files, &options).result.unwrap();
+        let MergeOutcome::Merged(path) = merged else { panic!("Expected a merge") };
+        assert_eq!(image::image_dimensions(&path).unwrap(), (plan.output_width, plan.output_height));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_merge_print_page() {
+        // 800x200 side by side, shrunk into landscape Letter at 50 dpi: 550x425 with 20 pixel margins
+        let images: Vec<DynamicImage> = (0..8).map(|_| generate_test_image(100, 200, [255, 0, 0])).collect();
+        let page = PrintPage { page_size: PageSize::Letter, margin: 10 };
+        let options = MergeOptions::new().layout(Arc::new(Horizontal)).print_page(page).dpi(50);
+        let merged = merge(&images, &options).to_rgba8();
+        assert_eq!(merged.dimensions(), (550, 425));
+        assert_eq!(merged.get_pixel(275, 212).0, [255, 0, 0, 255]);
+        assert_eq!(merged.get_pixel(10, 212).0, [0, 0, 0, 0], "Margin should be background");
+        assert_eq!(merged.get_pixel(275, 100).0, [0, 0, 0, 0], "Composite should be shrunk, not stretched");
+
+        // The written page has the planned size and records its resolution
+        let test_root = setup_test_data_for_test("print_page").expect("Failed to setup test data");
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        let image_files = vec![vertical_dir.join("red.png"), vertical_dir.join("green.jpg")];
+        let options = MergeOptions::new().print_page(PrintPage::default()).dpi(72);
+        let plan = plan_merge(&vertical_dir, &image_files, &options).unwrap();
+        assert_eq!((plan.output_width, plan.output_height), (595, 842));
+
+        let merged = merge_images_in_directory(&vertical_dir, &image_files, &options).result.unwrap();
+        let MergeOutcome::Merged(path) = merged else { panic!("Expected a merge") };
+        assert_eq!(image::image_dimensions(&path).unwrap(), (595, 842));
+        let reader = png::Decoder::new(fs::File::open(&path).unwrap()).read_info().unwrap();
+        assert_eq!(reader.info().pixel_dims.map(|dims| dims.xppu), Some(2835));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_merge_directory_report() {
+        let test_root = setup_test_data_for_test("report").expect("Failed to setup test data");
+        
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        // A PNG cut off after its header passes the scan but cannot be decoded
+        let png = std::fs::read(vertical_dir.join("red.png")).expect("Failed to read file");
+        std::fs::write(vertical_dir.join("broken.png"), &png[..png.len() / 2]).expect("Failed to write file");
+        
+        let report = merge_directory(&vertical_dir, &MergeOptions::new());
+        assert_eq!(report.directory, vertical_dir);
+        assert!(matches!(report.result, Ok(MergeOutcome::Merged(_))));
+        assert_eq!(report.unreadable.len(), 1);
+        assert!(matches!(&report.unreadable[0], Error::Decode { path, .. } if path.ends_with("broken.png")));
+        
+        let report = merge_directory(&Path::new(&test_root).join("missing-dir"), &MergeOptions::new());
+        assert!(matches!(report.result, Err(Error::Io(_))));
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
     #[test]
     fn test_find_latest_creation_date() {
         let test_root = setup_test_data_for_test("date").expect("Failed to setup test data");
@@ -557,7 +1562,7 @@ mod tests {
         assert!(test_dir.join("merged.png").exists());
         
         // Remove merged files
-        let result = remove_existing_merged_files(&test_dir);
+        let result = remove_existing_merged_files(&test_dir, MergedFileMatching::Marker);
         assert!(result.is_ok(), "Should successfully remove merged files");
         
         // Verify merged files are gone
@@ -0,0 +1,439 @@
+use image::Rgba;
+use std::fmt::Display;
+use std::sync::Arc;
+use crate::caption::{CaptionPosition, Captions};
+use crate::filter::FileFilter;
+use crate::compositor::Style;
+use crate::format::{Encoding, OutputFormat};
+use crate::header::{Header, HeaderTitle};
+use crate::layout::{self, Alignment, Arrangement, Layout, LayoutSettings, Scaling};
+use crate::limit::{self, SizeLimit};
+use crate::pdf::{PageSize, PdfSettings};
+use crate::print::{PrintPage, DEFAULT_DPI};
+use crate::provenance::MergedFileMatching;
+use crate::scanner::{GroupSelection, ScanSettings};
+use crate::stamp::Stamp;
+
+/// FNV-1a offset basis and prime, for a fingerprint that stays the same from run to run
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Times the images are shrunk towards the size limit before the rendered output is downscaled instead
+const MAX_SHRINK_ATTEMPTS: usize = 8;
+
+/// FNV-1a hash of named settings, for a fingerprint that stays the same from run to run
+struct Fingerprint(u64);
+
+impl Fingerprint {
+    fn new() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+
+    /// Add a setting as `name=value`, ended by a NUL byte so neighbouring values cannot run together
+    fn field(&mut self, name: &str, value: impl Display) -> &mut Self {
+        for byte in format!("{}={}\0", name, value).bytes() {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+        self
+    }
+
+    fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// A setting that may be unset, written as `none` in a fingerprint
+fn optional(value: Option<impl Display>) -> String {
+    value.map_or_else(|| "none".to_string(), |value| value.to_string())
+}
+
+fn color_hex(color: Rgba<u8>) -> String {
+    let [red, green, blue, alpha] = color.0;
+    format!("{:02x}{:02x}{:02x}{:02x}", red, green, blue, alpha)
+}
+
+fn scaling_name(scaling: Scaling) -> String {
+    match scaling {
+        Scaling::ToMax => "max".to_string(),
+        Scaling::ToMin => "min".to_string(),
+        Scaling::ToMedian => "median".to_string(),
+        Scaling::NeverUpscale => "never-upscale".to_string(),
+        Scaling::MaxUpscale(factor) => format!("max-upscale:{}", factor),
+        Scaling::Native => "native".to_string(),
+    }
+}
+
+fn page_size_name(page_size: PageSize) -> &'static str {
+    match page_size {
+        PageSize::A4 => "a4",
+        PageSize::Letter => "letter",
+        PageSize::Fit => "fit",
+    }
+}
+
+/// Settings for scanning directories and merging images, built with chained setters
+///
+/// ```
+/// use picmrg::{LayoutRegistry, MergeOptions, MergedFileMatching, Scaling};
+///
+/// let options = MergeOptions::new()
+///     .matching(MergedFileMatching::LegacyName)
+///     .layout(LayoutRegistry::default().get("vertical").unwrap())
+///     .scaling(Scaling::MaxUpscale(2.0))
+///     .force(true);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    pub(crate) matching: MergedFileMatching,
+    pub(crate) scan: ScanSettings,
+    pub(crate) filter: FileFilter,
+    pub(crate) force: bool,
+    pub(crate) layout: Option<Arc<dyn Layout>>,
+    pub(crate) layout_settings: LayoutSettings,
+    pub(crate) style: Style,
+    pub(crate) stamp: Stamp,
+    pub(crate) encoding: Encoding,
+    pub(crate) max_bytes: Option<u64>,
+    pub(crate) size_limit: SizeLimit,
+    pub(crate) max_per_output: Option<usize>,
+    pub(crate) pdf: PdfSettings,
+    pub(crate) print: Option<PrintPage>,
+}
+
+impl MergeOptions {
+    /// Default options: marker-based detection of merged files, each of the root's direct
+    /// subdirectories with images merged on its own, keep current merges,
+    /// choose the layout from the images' sizes, scale every image to the largest and centre it,
+    /// no spacing, decoration, captions, banner or stamps on a transparent background, written as PNG
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How previously generated merged files are recognised
+    pub fn matching(mut self, matching: MergedFileMatching) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    /// Levels below the root scanned for image directories, at least 1 (only its direct subdirectories)
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.scan.max_depth = max_depth.max(1);
+        self
+    }
+
+    /// Which of the scanned directories with images are merged
+    pub fn groups(mut self, groups: GroupSelection) -> Self {
+        self.scan.groups = groups;
+        self
+    }
+
+    /// Only merge files whose name matches this glob, or one of the other included globs
+    pub fn include(mut self, pattern: impl Into<String>) -> Self {
+        self.filter.include.push(pattern.into());
+        self
+    }
+
+    /// Pass over files and directories whose name matches this glob
+    pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+        self.filter.exclude.push(pattern.into());
+        self
+    }
+
+    /// Merge again even if the existing merged file is current
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Place images with this layout instead of choosing one automatically
+    pub fn layout(mut self, layout: Arc<dyn Layout>) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    /// How images are scaled towards a common size in every layout
+    pub fn scaling(mut self, scaling: Scaling) -> Self {
+        self.layout_settings.scaling = scaling;
+        self
+    }
+
+    /// Where images smaller than their slot are placed in it
+    pub fn alignment(mut self, alignment: Alignment) -> Self {
+        self.layout_settings.alignment = alignment;
+        self
+    }
+
+    /// Colour of the canvas not covered by any image
+    pub fn background(mut self, background: Rgba<u8>) -> Self {
+        self.style.background = background;
+        self
+    }
+
+    /// Empty space between neighbouring images, in pixels
+    pub fn gutter(mut self, gutter: u32) -> Self {
+        self.layout_settings.spacing = gutter;
+        self
+    }
+
+    /// Empty space around the composite, in pixels
+    pub fn margin(mut self, margin: u32) -> Self {
+        self.style.margin = margin;
+        self
+    }
+
+    /// Frame of the given width and colour around every image
+    pub fn border(mut self, width: u32, color: Rgba<u8>) -> Self {
+        self.style.border_width = width;
+        self.style.border_color = color;
+        self
+    }
+
+    /// Drop shadow offset by the given pixels below and right of every image (0 for none)
+    pub fn shadow(mut self, offset: u32) -> Self {
+        self.style.shadow = offset;
+        self
+    }
+
+    /// Details shown in a caption with every image
+    pub fn captions(mut self, captions: Captions) -> Self {
+        self.style.captions = captions;
+        self
+    }
+
+    /// Details shown in a banner above the composite
+    pub fn header(mut self, header: Header) -> Self {
+        self.style.header = header;
+        self
+    }
+
+    /// Line below the composite, from a template with `{name}`, `{title}` and `{date}` placeholders
+    pub fn footer(mut self, template: impl Into<String>) -> Self {
+        self.stamp.footer = Some(template.into());
+        self
+    }
+
+    /// Text drawn diagonally across the output at the given opacity (percent), from a template
+    /// like the footer's
+    pub fn watermark(mut self, template: impl Into<String>, opacity: u8) -> Self {
+        self.stamp.watermark = Some(template.into());
+        self.stamp.watermark_opacity = opacity.min(100);
+        self
+    }
+
+    /// File format of merged outputs, which also sets their extension
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.encoding.format = format;
+        self
+    }
+
+    /// Quality of lossy output formats, from 1 (smallest) to 100 (best)
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.encoding.quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Resolution recorded in merged files, in dots per inch, so they print at a known size
+    pub fn dpi(mut self, dpi: u32) -> Self {
+        self.encoding.dpi = Some(dpi.max(1));
+        self
+    }
+
+    /// Lay merged outputs out for printing on this paper: the layout is chosen for the shape of
+    /// the printable area, the composite is shrunk to fit inside the margin at the resolution
+    /// (`DEFAULT_DPI` unless `dpi` is set) and centred on a page of the paper's size
+    pub fn print_page(mut self, page: PrintPage) -> Self {
+        self.print = Some(page);
+        self.encoding.dpi.get_or_insert(DEFAULT_DPI);
+        self
+    }
+
+    /// Page size, margin and pages of PDF output (see `format`)
+    pub fn pdf(mut self, pdf: PdfSettings) -> Self {
+        self.pdf = pdf;
+        self
+    }
+
+    /// Largest size of a merged file in bytes, reached by changing format, quality and dimensions
+    /// as needed (see `fit_to_budget`)
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Largest width of a merged output in pixels; wider outputs are shrunk, keeping their aspect ratio
+    pub fn max_width(mut self, max_width: u32) -> Self {
+        self.size_limit.max_width = Some(max_width.max(1));
+        self
+    }
+
+    /// Largest height of a merged output in pixels; taller outputs are shrunk, keeping their aspect ratio
+    pub fn max_height(mut self, max_height: u32) -> Self {
+        self.size_limit.max_height = Some(max_height.max(1));
+        self
+    }
+
+    /// Largest number of pixels (width times height) of a merged output; larger outputs are shrunk,
+    /// keeping their aspect ratio
+    pub fn max_pixels(mut self, max_pixels: u64) -> Self {
+        self.size_limit.max_pixels = Some(max_pixels.max(1));
+        self
+    }
+
+    /// Largest number of images in one merged file; directories with more are split into evenly
+    /// sized groups written as numbered files (merged-yy-mm-dd-1.png, -2.png, ...)
+    pub fn max_per_output(mut self, max_per_output: usize) -> Self {
+        self.max_per_output = Some(max_per_output.max(2));
+        self
+    }
+
+    /// Fingerprint of every option that changes how a merged file looks or is encoded, recorded in
+    /// its marker so a merge made with other settings is not kept as current
+    ///
+    /// The layout is compared by name instead, and options that only choose which files are
+    /// merged or whether to merge at all are left out.
+    pub(crate) fn settings_fingerprint(&self) -> String {
+        let LayoutSettings { scaling, alignment, spacing, caption_space, page_aspect_ratio } = self.layout_settings;
+        let Style { background, margin, border_width, border_color, shadow, captions, header } = self.style;
+        let Encoding { format, quality, dpi } = self.encoding;
+        let mut fingerprint = Fingerprint::new();
+        fingerprint
+            .field("scaling", scaling_name(scaling))
+            .field("alignment", match alignment {
+                Alignment::Start => "start",
+                Alignment::Center => "center",
+                Alignment::End => "end",
+            })
+            .field("spacing", spacing)
+            .field("caption-space", caption_space)
+            .field("page-aspect-ratio", optional(page_aspect_ratio))
+            .field("background", color_hex(background))
+            .field("margin", margin)
+            .field("border-width", border_width)
+            .field("border-color", color_hex(border_color))
+            .field("shadow", shadow)
+            .field("caption-index", captions.index)
+            .field("caption-file-name", captions.file_name)
+            .field("caption-date", captions.date)
+            .field("caption-position", match captions.position {
+                CaptionPosition::Below => "below",
+                CaptionPosition::Overlay => "overlay",
+            })
+            .field("header-title", match header.title {
+                HeaderTitle::None => "none",
+                HeaderTitle::DirectoryName => "directory-name",
+                HeaderTitle::Parsed => "parsed",
+            })
+            .field("header-date", header.date)
+            .field("header-count", header.count)
+            .field("footer", optional(self.stamp.footer.as_deref()))
+            .field("watermark", optional(self.stamp.watermark.as_deref()))
+            .field("watermark-opacity", self.stamp.watermark_opacity)
+            .field("format", format.extension())
+            // Lossless formats ignore the quality unless a size budget falls back to JPEG, so it
+            // must not make their merges outdated
+            .field("quality", optional((format.is_lossy() || self.max_bytes.is_some()).then_some(quality)))
+            .field("dpi", optional(dpi))
+            .field("max-bytes", optional(self.max_bytes))
+            .field("max-width", optional(self.size_limit.max_width))
+            .field("max-height", optional(self.size_limit.max_height))
+            .field("max-pixels", optional(self.size_limit.max_pixels))
+            .field("pdf-page-size", page_size_name(self.pdf.page_size))
+            .field("pdf-margin", self.pdf.margin)
+            .field("pdf-include-sources", self.pdf.include_sources)
+            .field("print-page-size", optional(self.print.map(|page| page_size_name(page.page_size))))
+            .field("print-margin", optional(self.print.map(|page| page.margin)));
+        fingerprint.finish()
+    }
+
+    /// Layout settings with the spacing widened to make room for borders and shadows, aiming
+    /// for the shape of the print page's printable area if there is one
+    pub(crate) fn layout_settings(&self) -> LayoutSettings {
+        LayoutSettings {
+            spacing: self.layout_settings.spacing + self.style.tile_padding(),
+            page_aspect_ratio: self.print.and_then(|page| page.aspect_ratio()),
+            ..self.layout_settings
+        }
+    }
+
+    /// Size limit of an output of the given size: the configured limit, tightened to the
+    /// printable area of the print page
+    pub(crate) fn size_limit_for(&self, width: u32, height: u32) -> SizeLimit {
+        match (self.print, self.encoding.dpi) {
+            (Some(page), Some(dpi)) => self.size_limit.within(&page.printable_area(width, height, dpi)),
+            _ => self.size_limit,
+        }
+    }
+
+    /// Final size of an output rendered at the given size: shrunk to the size limit and placed on
+    /// the print page
+    pub(crate) fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = self.size_limit_for(width, height).fit(width, height);
+        match (self.print, self.encoding.dpi) {
+            (Some(page), Some(dpi)) => page.page_dimensions(width, height, dpi),
+            _ => (width, height),
+        }
+    }
+
+    /// Arrange images of the given sizes with a layout, reserving room for captions below them and
+    /// shrinking the images until the canvas fits the size limit
+    ///
+    /// Shrinking the placements rather than the rendered canvas keeps huge merges from ever being
+    /// drawn at full size. Margins, borders and spacing keep their size, so a canvas may still be
+    /// slightly over the limit if they alone take up most of it.
+    pub(crate) fn arrange(&self, layout: &dyn Layout, dimensions: &[(u32, u32)]) -> Arrangement {
+        let mut arrangement = self.arrange_unlimited(layout, dimensions);
+        let mut dimensions = dimensions.to_vec();
+        for _ in 0..MAX_SHRINK_ATTEMPTS {
+            let (width, height) = self.style.canvas_size(&arrangement);
+            let factor = self.size_limit_for(width, height).factor(width, height);
+            if factor >= 1.0 {
+                break;
+            }
+            for (width, height) in &mut dimensions {
+                (*width, *height) = (limit::scale(*width, factor), limit::scale(*height, factor));
+            }
+            arrangement = self.arrange_unlimited(layout, &dimensions);
+        }
+        arrangement
+    }
+
+    /// Arrange images like `arrange`, at whatever size the layout gives them
+    fn arrange_unlimited(&self, layout: &dyn Layout, dimensions: &[(u32, u32)]) -> Arrangement {
+        let settings = self.layout_settings();
+        let arrangement = layout.arrange(dimensions, &settings);
+
+        // Caption size follows the image sizes, which reserving space below them does not change
+        match self.style.captions.space_below(&arrangement) {
+            0 => arrangement,
+            caption_space => layout.arrange(dimensions, &LayoutSettings { caption_space, ..settings }),
+        }
+    }
+
+    /// The selected layout, or one chosen for images of the given (width, height) sizes
+    pub(crate) fn layout_for(&self, dimensions: &[(u32, u32)]) -> Arc<dyn Layout> {
+        self.layout.clone().unwrap_or_else(|| layout::choose_layout(dimensions, &self.layout_settings()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_fingerprint() {
+        let png = MergeOptions::new().settings_fingerprint();
+        assert_eq!(png.len(), 16);
+        assert_eq!(png, MergeOptions::new().force(true).max_per_output(4).settings_fingerprint());
+        assert_ne!(png, MergeOptions::new().gutter(4).settings_fingerprint());
+        assert_ne!(png, MergeOptions::new().footer("{count}").settings_fingerprint());
+
+        // The quality only counts for lossy formats
+        assert_eq!(png, MergeOptions::new().quality(50).settings_fingerprint());
+        let webp = MergeOptions::new().format(OutputFormat::Webp);
+        assert_eq!(webp.settings_fingerprint(), webp.clone().quality(50).settings_fingerprint());
+        let budget = webp.clone().max_bytes(1_000_000);
+        assert_ne!(budget.settings_fingerprint(), budget.clone().quality(50).settings_fingerprint());
+        let jpeg = MergeOptions::new().format(OutputFormat::Jpeg);
+        assert_ne!(jpeg.settings_fingerprint(), jpeg.clone().quality(50).settings_fingerprint());
+    }
+}
@@ -0,0 +1,526 @@
+use image::codecs::jpeg::{JpegEncoder, PixelDensity};
+use image::codecs::webp::WebPEncoder;
+use image::{ColorType, DynamicImage, ImageEncoder, Rgb, RgbImage};
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tiff::encoder::{colortype, compression::Lzw, TiffEncoder};
+use tiff::encoder::Rational;
+use tiff::tags::{ResolutionUnit, Tag};
+use crate::error::Error;
+use crate::format::{Encoding, OutputFormat};
+use crate::pdf::{self, PdfMetadata, PdfSettings};
+use crate::provenance::{self, Provenance};
+
+/// Temporary files currently being written, removed by the interrupt handler
+static PENDING_TEMP_FILES: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Suffix of temporary files, which scans pass over whatever they contain
+const TEMP_SUFFIX: &str = "picmrg-tmp";
+
+/// Encoder speed of AVIF output, from 1 (smallest files) to 10 (fastest)
+const AVIF_SPEED: u8 = 6;
+
+/// Millimetres per inch, for the metres PNG records resolutions in
+const MM_PER_INCH: f64 = 25.4;
+
+/// VP8X flags announcing transparency and XMP metadata in extended WebP files
+const WEBP_ALPHA_FLAG: u8 = 0x10;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Write an encoded image to a fsynced temporary file next to `output_path`, to be renamed into
+/// place later with `StagedOutput::commit`. Either the complete new file ends up at `output_path`
+/// or the previous file is left untouched.
+pub(crate) fn stage_encoded(encoded: &[u8], output_path: &Path) -> Result<StagedOutput, Error> {
+    let temp = TempFile::new(temp_path_for(output_path));
+
+    let file = File::create(temp.path())?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(encoded)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    Ok(StagedOutput { temp, output_path: output_path.to_path_buf() })
+}
+
+/// An output written to a temporary file; dropping it without committing removes the temporary
+/// file and leaves any previous file at the output path untouched
+pub(crate) struct StagedOutput {
+    temp: TempFile,
+    output_path: PathBuf,
+}
+
+impl StagedOutput {
+    /// Rename the temporary file into place, returning the output path
+    pub(crate) fn commit(self) -> Result<PathBuf, Error> {
+        fs::rename(self.temp.path(), &self.output_path)?;
+        self.temp.persist();
+        sync_parent_directory(&self.output_path)?;
+        Ok(self.output_path)
+    }
+}
+
+/// Encode an image in the selected format, embedding the picmrg provenance marker and, in formats
+/// that have a field for it, the resolution
+pub(crate) fn encode(image: &DynamicImage, encoding: &Encoding, provenance: &Provenance) -> Result<Vec<u8>, Error> {
+    match encoding.format {
+        OutputFormat::Png => {
+            let mut encoded = Vec::new();
+            encode_png(image, encoding.dpi, provenance, &mut encoded)?;
+            Ok(encoded)
+        }
+        OutputFormat::Jpeg => encode_jpeg(image, encoding.quality, encoding.dpi, provenance),
+        OutputFormat::Webp => encode_webp(image, provenance),
+        OutputFormat::Tiff => encode_tiff(image, encoding.dpi, provenance),
+        OutputFormat::Avif => encode_avif(image, encoding.quality, provenance),
+        // Without sources or a directory to describe, the PDF holds just the image
+        OutputFormat::Pdf => Ok(pdf::encode_pdf(
+            image,
+            &[],
+            &PdfSettings::default(),
+            encoding.dpi,
+            &PdfMetadata::default(),
+            provenance,
+        )),
+    }
+}
+
+/// Wrap an encoder's error
+fn encode_error(e: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::Encode(Box::new(e))
+}
+
+/// Encode an image as RGBA PNG with the picmrg provenance marker in an iTXt chunk and the
+/// resolution, if any, in a pHYs chunk
+fn encode_png<W: Write>(
+    image: &DynamicImage,
+    dpi: Option<u32>,
+    provenance: &Provenance,
+    writer: W,
+) -> Result<(), Error> {
+    let rgba_image = image.to_rgba8();
+
+    let mut encoder = png::Encoder::new(writer, rgba_image.width(), rgba_image.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_itxt_chunk(provenance::MARKER_KEYWORD.to_string(), provenance.to_text())?;
+    if let Some(dpi) = dpi {
+        let pixels_per_metre = (dpi as f64 * 1000.0 / MM_PER_INCH).round() as u32;
+        encoder.set_pixel_dims(Some(png::PixelDimensions {
+            xppu: pixels_per_metre,
+            yppu: pixels_per_metre,
+            unit: png::Unit::Meter,
+        }));
+    }
+
+    let mut png_writer = encoder.write_header()?;
+    png_writer.write_image_data(rgba_image.as_raw())?;
+    png_writer.finish()?;
+
+    Ok(())
+}
+
+/// Encode an image as JPEG on a white background, with the marker in an XMP APP1 segment and the
+/// resolution, if any, in the JFIF header
+fn encode_jpeg(image: &DynamicImage, quality: u8, dpi: Option<u32>, provenance: &Provenance) -> Result<Vec<u8>, Error> {
+    let rgb_image = flatten(image, Rgb([255, 255, 255]));
+    let mut jpeg = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut jpeg, quality);
+    if let Some(dpi) = dpi {
+        encoder.set_pixel_density(PixelDensity::dpi(dpi.min(u16::MAX as u32) as u16));
+    }
+    encoder
+        .write_image(rgb_image.as_raw(), rgb_image.width(), rgb_image.height(), ColorType::Rgb8)
+        .map_err(encode_error)?;
+
+    let segment = [provenance::JPEG_XMP_HEADER, provenance.to_xmp().as_bytes()].concat();
+    let length = u16::try_from(segment.len() + 2)
+        .map_err(|_| Error::Encode("too many source files to record in a JPEG file".into()))?;
+
+    // Metadata goes after the JFIF header, which must directly follow the start of image
+    let mut insert_at = 2;
+    if jpeg[2..4] == [0xFF, 0xE0] {
+        insert_at += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+    let mut output = Vec::with_capacity(jpeg.len() + segment.len() + 4);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&length.to_be_bytes());
+    output.extend_from_slice(&segment);
+    output.extend_from_slice(&jpeg[insert_at..]);
+    Ok(output)
+}
+
+/// Blend an image over an opaque background colour
+fn flatten(image: &DynamicImage, background: Rgb<u8>) -> RgbImage {
+    let rgba_image = image.to_rgba8();
+    RgbImage::from_fn(rgba_image.width(), rgba_image.height(), |x, y| {
+        let pixel = rgba_image.get_pixel(x, y).0;
+        let alpha = pixel[3] as u32;
+        Rgb([0, 1, 2].map(|channel| {
+            ((pixel[channel] as u32 * alpha + background.0[channel] as u32 * (255 - alpha) + 127) / 255) as u8
+        }))
+    })
+}
+
+/// Encode an image as lossless WebP in the extended format, with the marker in an XMP chunk
+fn encode_webp(image: &DynamicImage, provenance: &Provenance) -> Result<Vec<u8>, Error> {
+    let rgba_image = image.to_rgba8();
+    let (width, height) = rgba_image.dimensions();
+    let mut webp = Vec::new();
+    WebPEncoder::new_lossless(&mut webp)
+        .encode(rgba_image.as_raw(), width, height, ColorType::Rgba8)
+        .map_err(encode_error)?;
+
+    // The simple format has no room for metadata: announce it in a VP8X chunk before the image
+    let mut flags = WEBP_XMP_FLAG;
+    if rgba_image.pixels().any(|pixel| pixel.0[3] < 255) {
+        flags |= WEBP_ALPHA_FLAG;
+    }
+    let mut header = vec![flags, 0, 0, 0];
+    header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+    header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+
+    let mut output = b"RIFF\0\0\0\0WEBP".to_vec();
+    push_riff_chunk(&mut output, b"VP8X", &header);
+    output.extend_from_slice(&webp[12..]);
+    push_riff_chunk(&mut output, b"XMP ", provenance.to_xmp().as_bytes());
+    let riff_size = u32::try_from(output.len() - 8)
+        .map_err(|_| Error::Encode("merged image is too large for a WebP file".into()))?;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(output)
+}
+
+/// Append a RIFF chunk, padded to an even size
+fn push_riff_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    output.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+/// Encode an image as LZW compressed RGBA TIFF, with the marker in the XMP tag and the resolution,
+/// if any, in the resolution tags
+fn encode_tiff(image: &DynamicImage, dpi: Option<u32>, provenance: &Provenance) -> Result<Vec<u8>, Error> {
+    let rgba_image = image.to_rgba8();
+    let mut output = Cursor::new(Vec::new());
+
+    let mut encoder = TiffEncoder::new(&mut output)?;
+    let mut tiff_image = encoder.new_image_with_compression::<colortype::RGBA8, _>(
+        rgba_image.width(),
+        rgba_image.height(),
+        Lzw,
+    )?;
+    tiff_image.encoder().write_tag(Tag::Unknown(provenance::TIFF_XMP_TAG), provenance.to_xmp().as_bytes())?;
+    if let Some(dpi) = dpi {
+        tiff_image.resolution(ResolutionUnit::Inch, Rational { n: dpi, d: 1 });
+    }
+    tiff_image.write_data(rgba_image.as_raw())?;
+
+    Ok(output.into_inner())
+}
+
+/// Encode an image as AVIF, with the marker in a top-level box after the image
+fn encode_avif(image: &DynamicImage, quality: u8, provenance: &Provenance) -> Result<Vec<u8>, Error> {
+    let rgba_image = image.to_rgba8();
+    let pixels: Vec<ravif::RGBA8> = rgba_image.pixels()
+        .map(|pixel| ravif::RGBA8::new(pixel.0[0], pixel.0[1], pixel.0[2], pixel.0[3]))
+        .collect();
+    let encoded = ravif::Encoder::new()
+        .with_quality(quality as f32)
+        .with_alpha_quality(quality as f32)
+        .with_speed(AVIF_SPEED)
+        .encode_rgba(ravif::Img::new(&pixels[..], rgba_image.width() as usize, rgba_image.height() as usize))
+        .map_err(encode_error)?;
+
+    let xmp = provenance.to_xmp();
+    let box_size = u32::try_from(8 + xmp.len())
+        .map_err(|_| Error::Encode("too many source files to record in an AVIF file".into()))?;
+    let mut output = encoded.avif_file;
+    output.extend_from_slice(&box_size.to_be_bytes());
+    output.extend_from_slice(provenance::AVIF_MARKER_BOX);
+    output.extend_from_slice(xmp.as_bytes());
+    Ok(output)
+}
+
+/// Install a Ctrl-C handler that removes in-flight temporary files before exiting
+pub fn install_interrupt_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        remove_pending_temp_files();
+        eprintln!("\nInterrupted, temporary files removed.");
+        std::process::exit(130);
+    })
+}
+
+/// Remove temporary files left behind by a picmrg run that was killed, returning the removed paths
+pub fn remove_leftover_temp_files(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut removed = Vec::new();
+
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_file()
+            && let Some(name_str) = path.file_name().and_then(|name| name.to_str())
+            && is_temp_file(name_str)
+        {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Check if a filename is a temporary file written by picmrg
+pub(crate) fn is_temp_file(filename: &str) -> bool {
+    filename.starts_with('.') && filename.ends_with(TEMP_SUFFIX)
+}
+
+/// Build a hidden temporary path in the same directory, so the final rename stays on one filesystem
+fn temp_path_for(output_path: &Path) -> PathBuf {
+    let file_name = output_path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("merged");
+    let temp_name = format!(".{}.{}.{}", file_name, std::process::id(), TEMP_SUFFIX);
+
+    match output_path.parent() {
+        Some(parent) => parent.join(temp_name),
+        None => PathBuf::from(temp_name),
+    }
+}
+
+/// Remove every temporary file that is still registered
+fn remove_pending_temp_files() {
+    let pending = match PENDING_TEMP_FILES.lock() {
+        Ok(pending) => pending,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    for path in pending.iter() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Flush the directory entry of a renamed file to disk
+#[cfg(unix)]
+fn sync_parent_directory(path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Directories cannot be opened for syncing on this platform
+#[cfg(not(unix))]
+fn sync_parent_directory(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+/// A registered temporary file that is removed on drop unless persisted
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    fn new(path: PathBuf) -> Self {
+        if let Ok(mut pending) = PENDING_TEMP_FILES.lock() {
+            pending.push(path.clone());
+        }
+        TempFile { path, persisted: false }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Mark the file as renamed into place so it is no longer cleaned up
+    fn persist(mut self) {
+        self.persisted = true;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+        if let Ok(mut pending) = PENDING_TEMP_FILES.lock() {
+            pending.retain(|path| path != &self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{setup_test_data_for_test, cleanup_test_data_for_test, generate_test_image};
+
+    /// Encode an image, stage it and commit it, like a merge of a single output
+    fn write_atomically(
+        image: &DynamicImage,
+        output_path: &Path,
+        encoding: &Encoding,
+        provenance: &Provenance,
+    ) -> Result<(), Error> {
+        stage_encoded(&encode(image, encoding, provenance)?, output_path)?.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_temp_file() {
+        assert!(is_temp_file(".merged-24-01-15.png.1234.picmrg-tmp"));
+        assert!(!is_temp_file("merged-24-01-15.png"));
+        assert!(!is_temp_file(".hidden.png"));
+    }
+
+    #[test]
+    fn test_remove_leftover_temp_files() {
+        let test_root = setup_test_data_for_test("leftover").expect("Failed to setup test data");
+
+        let dir = Path::new(&test_root).join("vertical-images");
+        fs::write(dir.join(".merged-24-01-15.png.99.picmrg-tmp"), "partial").expect("Failed to write file");
+
+        let removed = remove_leftover_temp_files(&dir).expect("Failed to remove temporary files");
+        assert_eq!(removed.len(), 1);
+        assert!(!dir.join(".merged-24-01-15.png.99.picmrg-tmp").exists());
+        assert!(dir.join("red.png").exists());
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_write_atomically_replaces_file() {
+        let test_root = setup_test_data_for_test("atomic").expect("Failed to setup test data");
+
+        let output_path = Path::new(&test_root).join("vertical-images/merged.png");
+        let image = generate_test_image(30, 20, [1, 2, 3]);
+        let provenance = Provenance::new("vertical", &[PathBuf::from("a.png"), PathBuf::from("b.png")]);
+        write_atomically(&image, &output_path, &Encoding::default(), &provenance).expect("Failed to write atomically");
+
+        let written = image::open(&output_path).expect("Output should be a valid image");
+        assert_eq!((written.width(), written.height()), (30, 20));
+        assert_eq!(provenance::read_marker(&output_path), Some(provenance));
+
+        // No temporary files are left behind
+        let leftovers: Vec<_> = fs::read_dir(output_path.parent().unwrap())
+            .expect("Failed to read directory")
+            .filter_map(|entry| entry.ok()?.file_name().to_str().map(String::from))
+            .filter(|name| is_temp_file(name))
+            .collect();
+        assert!(leftovers.is_empty(), "Unexpected temporary files: {:?}", leftovers);
+        assert!(PENDING_TEMP_FILES.lock().unwrap().iter().all(|path| !path.starts_with(&test_root)));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_encode_every_format() {
+        let test_root = setup_test_data_for_test("encode_formats").expect("Failed to setup test data");
+
+        // Half transparent, so formats without transparency have something to flatten
+        let mut image = generate_test_image(40, 20, [200, 0, 0]).to_rgba8();
+        for x in 0..20 {
+            for y in 0..20 {
+                image.put_pixel(x, y, image::Rgba([0, 0, 0, 0]));
+            }
+        }
+        let image = DynamicImage::ImageRgba8(image);
+        let provenance = Provenance::new("grid", &[PathBuf::from("a \"quoted\" <name>.png"), PathBuf::from("Ünïcode.jpg")]);
+
+        for format in OutputFormat::ALL {
+            let output_path = Path::new(&test_root).join(format!("merged.{}", format.extension()));
+            let encoding = Encoding { format, quality: 80, dpi: None };
+            write_atomically(&image, &output_path, &encoding, &provenance).expect("Failed to write");
+
+            assert_eq!(provenance::read_marker(&output_path), Some(provenance.clone()), "{:?} marker", format);
+            if format.is_decodable() {
+                let written = image::open(&output_path).expect("Output should be a valid image").to_rgba8();
+                assert_eq!(written.dimensions(), (40, 20), "{:?}", format);
+                let expected_corner = if format == OutputFormat::Jpeg { [255, 255, 255] } else { [0, 0, 0] };
+                let corner = written.get_pixel(0, 0).0;
+                assert!(corner[..3].iter().zip(expected_corner).all(|(a, b)| a.abs_diff(b) < 8), "{:?}: {:?}", format, corner);
+            }
+        }
+
+        // Lower quality makes lossy formats smaller
+        let photo = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8])));
+        let size = |quality| encode(&photo, &Encoding { format: OutputFormat::Jpeg, quality, dpi: None }, &provenance).unwrap().len();
+        assert!(size(20) < size(95));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_encode_resolution() {
+        let image = generate_test_image(40, 20, [200, 0, 0]);
+        let provenance = Provenance::new("grid", &[]);
+        let encoding = |format| Encoding { format, quality: 80, dpi: Some(300) };
+
+        // PNG: pHYs in pixels per metre
+        let png = encode(&image, &encoding(OutputFormat::Png), &provenance).unwrap();
+        let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let dims = reader.info().pixel_dims.expect("PNG should have a pHYs chunk");
+        assert_eq!((dims.xppu, dims.yppu, dims.unit), (11811, 11811, png::Unit::Meter));
+
+        // JPEG: dots per inch in the JFIF header
+        let jpeg = encode(&image, &encoding(OutputFormat::Jpeg), &provenance).unwrap();
+        assert_eq!(&jpeg[6..11], b"JFIF\0");
+        assert_eq!(jpeg[13], 1, "Density unit should be inches");
+        assert_eq!((u16::from_be_bytes([jpeg[14], jpeg[15]]), u16::from_be_bytes([jpeg[16], jpeg[17]])), (300, 300));
+
+        // TIFF: resolution tags in inches
+        let tiff = encode(&image, &encoding(OutputFormat::Tiff), &provenance).unwrap();
+        let mut decoder = tiff::decoder::Decoder::new(Cursor::new(tiff)).unwrap();
+        assert_eq!(decoder.get_tag_u32(Tag::ResolutionUnit).unwrap(), ResolutionUnit::Inch.to_u16() as u32);
+        assert_eq!(decoder.get_tag_u32_vec(Tag::XResolution).unwrap(), vec![300, 1]);
+
+        // Without a resolution, PNG has no pHYs chunk
+        let png = encode(&image, &Encoding::default(), &provenance).unwrap();
+        assert!(png::Decoder::new(png.as_slice()).read_info().unwrap().info().pixel_dims.is_none());
+    }
+
+    #[test]
+    fn test_write_atomically_keeps_previous_file_on_failure() {
+        let test_root = setup_test_data_for_test("atomic_fail").expect("Failed to setup test data");
+
+        // Renaming a file onto a non-empty directory fails after the image was encoded
+        let output_path = Path::new(&test_root).join("vertical-images");
+        let image = generate_test_image(30, 20, [1, 2, 3]);
+        let provenance = Provenance::new("vertical", &[]);
+        assert!(write_atomically(&image, &output_path, &Encoding::default(), &provenance).is_err());
+
+        assert!(output_path.join("red.png").exists());
+        let leftovers: Vec<_> = fs::read_dir(&test_root)
+            .expect("Failed to read directory")
+            .filter_map(|entry| entry.ok()?.file_name().to_str().map(String::from))
+            .filter(|name| is_temp_file(name))
+            .collect();
+        assert!(leftovers.is_empty(), "Unexpected temporary files: {:?}", leftovers);
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
+    #[test]
+    fn test_staged_output_dropped_without_commit() {
+        let test_root = setup_test_data_for_test("staged_drop").expect("Failed to setup test data");
+
+        let output_path = Path::new(&test_root).join("vertical-images").join("red.png");
+        let previous = fs::read(&output_path).expect("Failed to read file");
+        let staged = stage_encoded(b"replacement", &output_path).expect("Failed to stage output");
+        assert!(staged.temp.path().exists());
+        drop(staged);
+
+        assert_eq!(fs::read(&output_path).expect("Failed to read file"), previous);
+        let leftovers: Vec<_> = fs::read_dir(output_path.parent().unwrap())
+            .expect("Failed to read directory")
+            .filter_map(|entry| entry.ok()?.file_name().to_str().map(String::from))
+            .filter(|name| is_temp_file(name))
+            .collect();
+        assert!(leftovers.is_empty(), "Unexpected temporary files: {:?}", leftovers);
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+}
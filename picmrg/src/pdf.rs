@@ -0,0 +1,253 @@
+use chrono::{DateTime, Datelike, Local, Offset, Timelike};
+use image::DynamicImage;
+use miniz_oxide::deflate::{compress_to_vec_zlib, CompressionLevel};
+use pdf_writer::{Content, Date, Filter, Finish, Name, Pdf, Rect, Ref, TextStr};
+use crate::provenance::Provenance;
+
+/// Points per millimetre
+const POINTS_PER_MM: f32 = 72.0 / 25.4;
+/// Resolution of images on pages sized to them when none is given, treating pixels as 1/96 inch
+/// like browsers do
+const DEFAULT_PAGE_DPI: u32 = 96;
+/// Default space between the page edges and the image, in millimetres
+pub const DEFAULT_PDF_MARGIN: u32 = 10;
+/// Name of the image on every page's resources
+const IMAGE_NAME: Name = Name(b"Im1");
+
+/// Page size of PDF output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PageSize {
+    /// 210 x 297 mm
+    #[default]
+    A4,
+    /// 8.5 x 11 inches
+    Letter,
+    /// Each page just large enough for its image plus the margin
+    Fit,
+}
+
+impl PageSize {
+    /// Width and height of a portrait page in points, or None for pages sized to their image
+    pub(crate) fn portrait(self) -> Option<(f32, f32)> {
+        match self {
+            PageSize::A4 => Some((595.28, 841.89)),
+            PageSize::Letter => Some((612.0, 792.0)),
+            PageSize::Fit => None,
+        }
+    }
+}
+
+/// How PDF output is laid out: the composite on the first page and optionally every source on a
+/// page of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PdfSettings {
+    pub page_size: PageSize,
+    pub margin: u32,           // Millimetres between the page edges and the image
+    pub include_sources: bool, // Add every source image at full resolution after the composite
+}
+
+impl Default for PdfSettings {
+    /// A4 pages with the default margin, composite only
+    fn default() -> Self {
+        PdfSettings {
+            page_size: PageSize::default(),
+            margin: DEFAULT_PDF_MARGIN,
+            include_sources: false,
+        }
+    }
+}
+
+/// Document information of PDF output
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PdfMetadata {
+    pub title: Option<String>,         // Usually the activity title from the directory name
+    pub date: Option<DateTime<Local>>, // Creation date, usually the evidence date
+}
+
+/// Write a PDF with the composite on the first page, followed by the given sources on pages of
+/// their own if the settings include them
+///
+/// Images are embedded losslessly at their full resolution and scaled to fit the page inside the
+/// margin; fixed-size pages turn to landscape for images wider than they are tall, pages sized to
+/// their image show it at `dpi`. The picmrg marker is the document's XMP metadata, written first
+/// so it is found without reading the images.
+pub fn encode_pdf(
+    composite: &DynamicImage,
+    sources: &[DynamicImage],
+    settings: &PdfSettings,
+    dpi: Option<u32>,
+    metadata: &PdfMetadata,
+    provenance: &Provenance,
+) -> Vec<u8> {
+    let mut pdf = Pdf::new();
+    let mut next_ref = Ref::new(1);
+    let metadata_id = next_ref.bump();
+    let catalog_id = next_ref.bump();
+    let info_id = next_ref.bump();
+    let page_tree_id = next_ref.bump();
+
+    let xmp = provenance.to_xmp();
+    pdf.metadata(metadata_id, xmp.as_bytes());
+    pdf.catalog(catalog_id).pages(page_tree_id).metadata(metadata_id);
+
+    let mut info = pdf.document_info(info_id);
+    if let Some(title) = &metadata.title {
+        info.title(TextStr(title));
+    }
+    if let Some(date) = metadata.date {
+        info.creation_date(pdf_date(date));
+    }
+    info.producer(TextStr(concat!("picmrg ", env!("CARGO_PKG_VERSION"))));
+    info.finish();
+
+    let sources = if settings.include_sources { sources } else { &[] };
+    let images: Vec<&DynamicImage> = std::iter::once(composite).chain(sources).collect();
+    let page_ids: Vec<Ref> = images.iter().map(|_| next_ref.bump()).collect();
+    pdf.pages(page_tree_id).kids(page_ids.iter().copied()).count(page_ids.len() as i32);
+
+    for (image, page_id) in images.into_iter().zip(page_ids) {
+        let (content_id, image_id, mask_id) = (next_ref.bump(), next_ref.bump(), next_ref.bump());
+        let (page_width, page_height) = page_dimensions(image, settings, dpi);
+        let mut page = pdf.page(page_id);
+        page.media_box(Rect::new(0.0, 0.0, page_width, page_height));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        page.resources().x_objects().pair(IMAGE_NAME, image_id);
+        page.finish();
+
+        write_image(&mut pdf, image, image_id, mask_id);
+
+        // Centre the image, as large as fits inside the margin
+        let margin = settings.margin as f32 * POINTS_PER_MM;
+        let (width, height) = (image.width().max(1) as f32, image.height().max(1) as f32);
+        let scale = ((page_width - 2.0 * margin) / width).min((page_height - 2.0 * margin) / height).max(0.0);
+        let (drawn_width, drawn_height) = (width * scale, height * scale);
+        let mut content = Content::new();
+        content.save_state();
+        content.transform([
+            drawn_width,
+            0.0,
+            0.0,
+            drawn_height,
+            (page_width - drawn_width) / 2.0,
+            (page_height - drawn_height) / 2.0,
+        ]);
+        content.x_object(IMAGE_NAME);
+        content.restore_state();
+        pdf.stream(content_id, &content.finish());
+    }
+
+    pdf.finish()
+}
+
+/// Size of the page showing an image, in points
+fn page_dimensions(image: &DynamicImage, settings: &PdfSettings, dpi: Option<u32>) -> (f32, f32) {
+    let margin = settings.margin as f32 * POINTS_PER_MM;
+    let points_per_pixel = 72.0 / dpi.unwrap_or(DEFAULT_PAGE_DPI).max(1) as f32;
+    match settings.page_size.portrait() {
+        Some((width, height)) if image.width() > image.height() => (height, width),
+        Some((width, height)) => (width, height),
+        None => (
+            image.width() as f32 * points_per_pixel + 2.0 * margin,
+            image.height() as f32 * points_per_pixel + 2.0 * margin,
+        ),
+    }
+}
+
+/// Embed an image as a Flate-compressed RGB image, with a soft mask if any pixel is transparent
+fn write_image(pdf: &mut Pdf, image: &DynamicImage, image_id: Ref, mask_id: Ref) {
+    let level = CompressionLevel::DefaultLevel as u8;
+    let rgba = image.to_rgba8();
+    let alphas: Vec<u8> = rgba.pixels().map(|pixel| pixel.0[3]).collect();
+    let has_transparency = alphas.iter().any(|alpha| *alpha < u8::MAX);
+    let rgb: Vec<u8> = rgba.pixels().flat_map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]]).collect();
+
+    let encoded = compress_to_vec_zlib(&rgb, level);
+    let mut xobject = pdf.image_xobject(image_id, &encoded);
+    xobject.filter(Filter::FlateDecode);
+    xobject.width(image.width() as i32);
+    xobject.height(image.height() as i32);
+    xobject.color_space().device_rgb();
+    xobject.bits_per_component(8);
+    if has_transparency {
+        xobject.s_mask(mask_id);
+    }
+    xobject.finish();
+
+    if has_transparency {
+        let encoded = compress_to_vec_zlib(&alphas, level);
+        let mut mask = pdf.image_xobject(mask_id, &encoded);
+        mask.filter(Filter::FlateDecode);
+        mask.width(image.width() as i32);
+        mask.height(image.height() as i32);
+        mask.color_space().device_gray();
+        mask.bits_per_component(8);
+    }
+}
+
+/// A local date and time with its UTC offset, as PDF document information records it
+fn pdf_date(date: DateTime<Local>) -> Date {
+    let offset_minutes = date.offset().fix().local_minus_utc() / 60;
+    Date::new(date.year().clamp(0, 9999) as u16)
+        .month(date.month() as u8)
+        .day(date.day() as u8)
+        .hour(date.hour() as u8)
+        .minute(date.minute() as u8)
+        .second(date.second().min(59) as u8)
+        .utc_offset_hour((offset_minutes / 60) as i8)
+        .utc_offset_minute((offset_minutes % 60).unsigned_abs() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::test_utils::generate_test_image;
+
+    /// Number of pages and the media boxes of a PDF written by `encode_pdf`
+    fn media_boxes(pdf: &[u8]) -> Vec<String> {
+        let text = String::from_utf8_lossy(pdf);
+        text.match_indices("/MediaBox [")
+            .map(|(index, _)| {
+                let start = index + "/MediaBox [".len();
+                text[start..start + text[start..].find(']').unwrap()].to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_pdf() {
+        let composite = generate_test_image(400, 200, [255, 0, 0]);
+        let sources = vec![generate_test_image(200, 400, [0, 255, 0]), generate_test_image(200, 200, [0, 0, 255])];
+        let provenance = Provenance::new("horizontal", &[]);
+        let metadata = PdfMetadata {
+            title: Some("ISC2 Webinar - Zero Trust".to_string()),
+            date: Local.with_ymd_and_hms(2024, 1, 16, 9, 30, 0).single(),
+        };
+
+        let settings = PdfSettings { include_sources: true, ..PdfSettings::default() };
+        let pdf = encode_pdf(&composite, &sources, &settings, None, &metadata, &provenance);
+        assert!(pdf.starts_with(b"%PDF-"));
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Title (ISC2 Webinar - Zero Trust)"));
+        assert!(text.contains("/CreationDate (D:20240116093000"));
+        assert!(text.contains("/Count 3"));
+        assert!(text.find("picmrg:provenance").unwrap() < text.find("/MediaBox").unwrap(), "Marker should come first");
+
+        // A4 pages, landscape for the wide composite
+        let boxes = media_boxes(&pdf);
+        assert_eq!(boxes.len(), 3);
+        assert!(boxes[0].starts_with("0 0 841.89 595.28"), "{:?}", boxes);
+        assert!(boxes[1].starts_with("0 0 595.28 841.89"), "{:?}", boxes);
+
+        // Composite only, on a page fitted to it
+        let settings = PdfSettings { page_size: PageSize::Fit, margin: 0, include_sources: false };
+        let pdf = encode_pdf(&composite, &sources, &settings, None, &PdfMetadata::default(), &provenance);
+        assert_eq!(media_boxes(&pdf), vec!["0 0 300 150"]);
+        assert!(!String::from_utf8_lossy(&pdf).contains("/Title"));
+
+        // At 144 dpi, half the size
+        let pdf = encode_pdf(&composite, &sources, &settings, Some(144), &PdfMetadata::default(), &provenance);
+        assert_eq!(media_boxes(&pdf), vec!["0 0 200 100"]);
+    }
+}
@@ -0,0 +1,129 @@
+use image::{imageops, DynamicImage, ImageBuffer, Rgba, RgbaImage};
+use crate::limit::SizeLimit;
+use crate::pdf::PageSize;
+
+/// Resolution of print-ready output when none is given, in dots per inch
+pub const DEFAULT_DPI: u32 = 300;
+/// Default space between the paper edges and the composite, in millimetres
+pub const DEFAULT_PRINT_MARGIN: u32 = 10;
+/// Millimetres and points per inch
+const MM_PER_INCH: f64 = 25.4;
+const POINTS_PER_INCH: f64 = 72.0;
+
+/// Paper a merged output is laid out for, printed at the output's resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrintPage {
+    pub page_size: PageSize, // `Fit` surrounds the composite with the margin and nothing more
+    pub margin: u32,         // Millimetres between the paper edges and the composite
+}
+
+impl Default for PrintPage {
+    /// A4 with the default margin
+    fn default() -> Self {
+        PrintPage {
+            page_size: PageSize::default(),
+            margin: DEFAULT_PRINT_MARGIN,
+        }
+    }
+}
+
+impl PrintPage {
+    /// Width and height of the portrait paper in pixels at a resolution, or None for `Fit`
+    fn paper(&self, dpi: u32) -> Option<(u32, u32)> {
+        let to_pixels = |points: f32| (points as f64 / POINTS_PER_INCH * dpi as f64).round() as u32;
+        self.page_size.portrait().map(|(width, height)| (to_pixels(width), to_pixels(height)))
+    }
+
+    /// Width of the margin in pixels at a resolution
+    pub fn margin_pixels(&self, dpi: u32) -> u32 {
+        (self.margin as f64 / MM_PER_INCH * dpi as f64).round() as u32
+    }
+
+    /// Width / height of the portrait printable area, or None if the page follows the composite
+    pub fn aspect_ratio(&self) -> Option<f64> {
+        let (width, height) = self.page_size.portrait()?;
+        let margin = 2.0 * self.margin as f64 / MM_PER_INCH * POINTS_PER_INCH;
+        Some((width as f64 - margin).max(1.0) / (height as f64 - margin).max(1.0))
+    }
+
+    /// Largest size of a composite of the given size at a resolution: the area inside the margin,
+    /// turned to landscape for composites wider than they are tall; no limit for `Fit`
+    pub fn printable_area(&self, width: u32, height: u32, dpi: u32) -> SizeLimit {
+        let Some((paper_width, paper_height)) = self.oriented_paper(width, height, dpi) else {
+            return SizeLimit::default();
+        };
+        let margins = 2 * self.margin_pixels(dpi);
+        SizeLimit {
+            max_width: Some(paper_width.saturating_sub(margins).max(1)),
+            max_height: Some(paper_height.saturating_sub(margins).max(1)),
+            max_pixels: None,
+        }
+    }
+
+    /// Size in pixels of the page holding a composite of the given size at a resolution
+    pub fn page_dimensions(&self, width: u32, height: u32, dpi: u32) -> (u32, u32) {
+        let margins = 2 * self.margin_pixels(dpi);
+        self.oriented_paper(width, height, dpi)
+            .unwrap_or((width + margins, height + margins))
+    }
+
+    /// Paper size in pixels, landscape for composites wider than they are tall, or None for `Fit`
+    fn oriented_paper(&self, width: u32, height: u32, dpi: u32) -> Option<(u32, u32)> {
+        let (paper_width, paper_height) = self.paper(dpi)?;
+        Some(if width > height { (paper_height, paper_width) } else { (paper_width, paper_height) })
+    }
+}
+
+/// Centre a composite on a page of the paper size at a resolution, filled with the background
+///
+/// The composite should already fit the printable area (see `PrintPage::printable_area`); it is
+/// never scaled here, so printing the page at its full size prints the composite at the resolution.
+pub fn place_on_page(image: DynamicImage, page: &PrintPage, dpi: u32, background: Rgba<u8>) -> DynamicImage {
+    let (width, height) = page.page_dimensions(image.width(), image.height(), dpi);
+    let mut output: RgbaImage = ImageBuffer::from_pixel(width, height, background);
+    let x = width.saturating_sub(image.width()) / 2;
+    let y = height.saturating_sub(image.height()) / 2;
+    imageops::overlay(&mut output, &image.to_rgba8(), x as i64, y as i64);
+    DynamicImage::ImageRgba8(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::generate_test_image;
+
+    #[test]
+    fn test_print_page_dimensions() {
+        // A4 at 300 dpi is 2480 x 3508 pixels, with 10 mm (118 pixel) margins
+        let page = PrintPage::default();
+        assert_eq!(page.margin_pixels(300), 118);
+        assert_eq!(page.page_dimensions(1000, 2000, 300), (2480, 3508));
+        assert_eq!(page.page_dimensions(2000, 1000, 300), (3508, 2480));
+        let area = page.printable_area(2000, 1000, 300);
+        assert_eq!((area.max_width, area.max_height), (Some(3272), Some(2244)));
+        assert!((page.aspect_ratio().unwrap() - 190.0 / 277.0).abs() < 0.01);
+
+        // Letter at 100 dpi, no margin
+        let page = PrintPage { page_size: PageSize::Letter, margin: 0 };
+        assert_eq!(page.page_dimensions(10, 10, 100), (850, 1100));
+
+        // Pages fitted to the composite only add the margin
+        let page = PrintPage { page_size: PageSize::Fit, margin: 10 };
+        assert_eq!(page.page_dimensions(1000, 2000, 254), (1200, 2200));
+        assert!(!page.printable_area(1000, 2000, 254).is_set());
+        assert_eq!(page.aspect_ratio(), None);
+    }
+
+    #[test]
+    fn test_place_on_page() {
+        let image = generate_test_image(300, 100, [255, 0, 0]);
+        let page = PrintPage { page_size: PageSize::Letter, margin: 10 };
+        let output = place_on_page(image, &page, 100, Rgba([255, 255, 255, 255]));
+        assert_eq!((output.width(), output.height()), (1100, 850));
+
+        let output = output.to_rgba8();
+        assert_eq!(output.get_pixel(550, 425).0, [255, 0, 0, 255]);
+        assert_eq!(output.get_pixel(390, 425).0, [255, 255, 255, 255]);
+        assert_eq!(output.get_pixel(550, 370).0, [255, 255, 255, 255]);
+    }
+}
@@ -0,0 +1,466 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use crate::format::OutputFormat;
+
+/// Keyword of the PNG iTXt chunk that marks a file as picmrg output
+pub const MARKER_KEYWORD: &str = "picmrg";
+
+/// XMP namespace of the provenance property that marks other formats as picmrg output
+const XMP_NAMESPACE: &str = "https://github.com/ZivatarOSS/isc2-image-merger/ns/1.0/";
+/// Attribute holding the provenance text in the XMP packet
+const XMP_ATTRIBUTE: &str = "picmrg:provenance";
+/// Identifier of the APP1 segment carrying XMP in JPEG files
+pub const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// TIFF tag carrying an XMP packet (XMLPacket)
+pub const TIFF_XMP_TAG: u16 = 700;
+/// Type of the top-level box carrying the XMP packet in AVIF files, ignored by readers
+pub const AVIF_MARKER_BOX: &[u8; 4] = b"free";
+/// Bytes at the start of a PDF searched for its XMP metadata, which picmrg writes first
+const PDF_MARKER_WINDOW: u64 = 1024 * 1024;
+/// Largest marker picmrg reads, so a huge metadata block in a source image is skipped cheaply
+const MAX_MARKER_LENGTH: u64 = 16 * 1024 * 1024;
+
+/// How previously generated merged files are recognised
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergedFileMatching {
+    #[default]
+    Marker,     // Only files carrying the embedded picmrg marker
+    LegacyName, // Any file named merged.png or merged-yy-mm-dd.png (picmrg 1.0 behaviour)
+}
+
+/// Provenance recorded in every merged file picmrg writes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub version: String,
+    pub layout: String,
+    pub settings: String, // Fingerprint of the options the file was rendered and encoded with, empty if unknown
+    pub sources: Vec<String>,
+    pub skipped: Vec<String>, // Sources that could not be decoded and were left out
+}
+
+impl Provenance {
+    /// Describe a merge made by this version of picmrg from the given source files
+    pub fn new(layout: &str, source_files: &[PathBuf]) -> Self {
+        Provenance {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            layout: layout.to_string(),
+            settings: String::new(),
+            sources: file_names(source_files),
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Record the sources that were left out of the merge because they could not be decoded
+    pub fn skipped(mut self, skipped_files: &[PathBuf]) -> Self {
+        self.skipped = file_names(skipped_files);
+        self
+    }
+
+    /// Record the fingerprint of the options the merge was made with
+    pub fn settings(mut self, settings: impl Into<String>) -> Self {
+        self.settings = settings.into();
+        self
+    }
+
+    /// Serialize as "key: value" lines, one `source` line per source file and one `skipped` line
+    /// per source left out
+    pub fn to_text(&self) -> String {
+        let mut text = format!("version: {}\nlayout: {}\n", self.version, self.layout);
+        if !self.settings.is_empty() {
+            text.push_str(&format!("settings: {}\n", self.settings));
+        }
+        for source in &self.sources {
+            text.push_str(&format!("source: {}\n", source));
+        }
+        for source in &self.skipped {
+            text.push_str(&format!("skipped: {}\n", source));
+        }
+        text
+    }
+
+    /// Serialize as an XMP packet with the `to_text` form in a picmrg attribute
+    pub fn to_xmp(&self) -> String {
+        format!(
+            concat!(
+                "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                "<rdf:Description rdf:about=\"\" xmlns:picmrg=\"{}\" {}=\"{}\"/>",
+                "</rdf:RDF></x:xmpmeta><?xpacket end=\"r\"?>",
+            ),
+            XMP_NAMESPACE,
+            XMP_ATTRIBUTE,
+            escape_xml(&self.to_text()),
+        )
+    }
+
+    /// Parse an XMP packet written by `to_xmp`; returns None if it has no picmrg attribute
+    pub fn from_xmp(xmp: &str) -> Option<Self> {
+        let start = xmp.find(&format!("{}=\"", XMP_ATTRIBUTE))? + XMP_ATTRIBUTE.len() + 2;
+        let length = xmp[start..].find('"')?;
+        Self::parse(&unescape_xml(&xmp[start..start + length]))
+    }
+
+    /// Parse text written by `to_text`; returns None if the version line is missing
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut version = None;
+        let mut layout = String::new();
+        let mut settings = String::new();
+        let mut sources = Vec::new();
+        let mut skipped = Vec::new();
+
+        for line in text.lines() {
+            match line.split_once(": ") {
+                Some(("version", value)) => version = Some(value.to_string()),
+                Some(("layout", value)) => layout = value.to_string(),
+                Some(("settings", value)) => settings = value.to_string(),
+                Some(("source", value)) => sources.push(value.to_string()),
+                Some(("skipped", value)) => skipped.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Some(Provenance {
+            version: version?,
+            layout,
+            settings,
+            sources,
+            skipped,
+        })
+    }
+}
+
+/// Names of files as recorded in the marker
+fn file_names(files: &[PathBuf]) -> Vec<String> {
+    files.iter()
+        .map(|path| path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.display().to_string()))
+        .collect()
+}
+
+/// Escape text for an XML attribute value
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\n', "&#10;")
+}
+
+/// Reverse `escape_xml`
+fn unescape_xml(text: &str) -> String {
+    text.replace("&#10;", "\n")
+        .replace("&quot;", "\"")
+        .replace("&gt;", ">")
+        .replace("&lt;", "<")
+        .replace("&amp;", "&")
+}
+
+/// Read the picmrg marker from a merged file in any output format, if it has one
+///
+/// The format is recognised by the file's signature, not its extension.
+pub fn read_marker(path: &Path) -> Option<Provenance> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut signature = [0; 12];
+    reader.read_exact(&mut signature).ok()?;
+    reader.rewind().ok()?;
+
+    match sniff_format(&signature)? {
+        OutputFormat::Png => read_png_marker(reader),
+        OutputFormat::Jpeg => read_jpeg_marker(reader),
+        OutputFormat::Webp => read_webp_marker(reader),
+        OutputFormat::Tiff => read_tiff_marker(reader),
+        OutputFormat::Avif => read_avif_marker(reader),
+        OutputFormat::Pdf => read_pdf_marker(reader),
+    }
+}
+
+/// Output format of a file from its first 12 bytes
+fn sniff_format(signature: &[u8; 12]) -> Option<OutputFormat> {
+    match signature {
+        [0x89, b'P', b'N', b'G', ..] => Some(OutputFormat::Png),
+        [0xFF, 0xD8, ..] => Some(OutputFormat::Jpeg),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P'] => Some(OutputFormat::Webp),
+        [b'I', b'I', 42, 0, ..] | [b'M', b'M', 0, 42, ..] => Some(OutputFormat::Tiff),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(OutputFormat::Avif),
+        [b'%', b'P', b'D', b'F', b'-', ..] => Some(OutputFormat::Pdf),
+        _ => None,
+    }
+}
+
+/// Read `length` bytes of a marker, refusing implausibly large blocks
+fn read_block<R: Read>(reader: &mut R, length: u64) -> Option<Vec<u8>> {
+    if length > MAX_MARKER_LENGTH {
+        return None;
+    }
+    let mut block = vec![0; length as usize];
+    reader.read_exact(&mut block).ok()?;
+    Some(block)
+}
+
+/// The marker in a PNG iTXt chunk
+fn read_png_marker<R: Read>(reader: R) -> Option<Provenance> {
+    let reader = png::Decoder::new(reader).read_info().ok()?;
+
+    reader.info().utf8_text.iter()
+        .find(|chunk| chunk.keyword == MARKER_KEYWORD)
+        .and_then(|chunk| chunk.get_text().ok())
+        .and_then(|text| Provenance::parse(&text))
+}
+
+/// The marker in the XMP APP1 segment of a JPEG file, looking only at the segments before the image data
+fn read_jpeg_marker<R: Read + Seek>(mut reader: R) -> Option<Provenance> {
+    reader.seek(SeekFrom::Start(2)).ok()?; // SOI
+
+    loop {
+        let mut marker = [0; 2];
+        reader.read_exact(&mut marker).ok()?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        match marker[1] {
+            // Fill byte before the actual marker
+            0xFF => {
+                reader.seek(SeekFrom::Current(-1)).ok()?;
+            }
+            // Markers without a length
+            0xD0..=0xD7 | 0x01 => {}
+            // Image data or end of image: no marker in the header
+            0xDA | 0xD9 => return None,
+            kind => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length).ok()?;
+                let length = u16::from_be_bytes(length).checked_sub(2)? as u64;
+                if kind == 0xE1 {
+                    let segment = read_block(&mut reader, length)?;
+                    if let Some(xmp) = segment.strip_prefix(JPEG_XMP_HEADER)
+                        && let Some(provenance) = Provenance::from_xmp(&String::from_utf8_lossy(xmp))
+                    {
+                        return Some(provenance);
+                    }
+                } else {
+                    reader.seek(SeekFrom::Current(length as i64)).ok()?;
+                }
+            }
+        }
+    }
+}
+
+/// The marker in the XMP chunk of a WebP file
+fn read_webp_marker<R: Read + Seek>(mut reader: R) -> Option<Provenance> {
+    reader.seek(SeekFrom::Start(12)).ok()?; // RIFF header
+
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).ok()?;
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+        if &header[..4] == b"XMP " {
+            return Provenance::from_xmp(&String::from_utf8_lossy(&read_block(&mut reader, length)?));
+        }
+        reader.seek(SeekFrom::Current((length + length % 2) as i64)).ok()?; // Chunks are padded to even sizes
+    }
+}
+
+/// The marker in the XMP tag of a TIFF file
+fn read_tiff_marker<R: Read + Seek>(reader: R) -> Option<Provenance> {
+    let mut decoder = tiff::decoder::Decoder::new(reader).ok()?;
+    let values = decoder.find_tag(tiff::tags::Tag::Unknown(TIFF_XMP_TAG)).ok()??.into_u32_vec().ok()?;
+    let xmp: Vec<u8> = values.into_iter().map(|value| value as u8).collect(); // BYTE values are decoded as integers
+    Provenance::from_xmp(&String::from_utf8_lossy(&xmp))
+}
+
+/// The marker in a top-level box of an AVIF file
+fn read_avif_marker<R: Read + Seek>(mut reader: R) -> Option<Provenance> {
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).ok()?;
+        let (size, header_length) = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+            0 => return None, // Last box, extending to the end of the file
+            1 => {
+                let mut large_size = [0; 8];
+                reader.read_exact(&mut large_size).ok()?;
+                (u64::from_be_bytes(large_size), 16)
+            }
+            size => (size as u64, 8),
+        };
+        let length = size.checked_sub(header_length)?;
+        if &header[4..] == AVIF_MARKER_BOX {
+            let block = read_block(&mut reader, length)?;
+            if let Some(provenance) = Provenance::from_xmp(&String::from_utf8_lossy(&block)) {
+                return Some(provenance);
+            }
+        } else {
+            reader.seek(SeekFrom::Current(length as i64)).ok()?;
+        }
+    }
+}
+
+/// The marker in the XMP metadata stream near the start of a PDF file
+fn read_pdf_marker<R: Read>(reader: R) -> Option<Provenance> {
+    let mut window = Vec::new();
+    reader.take(PDF_MARKER_WINDOW).read_to_end(&mut window).ok()?;
+    let text = String::from_utf8_lossy(&window);
+    let start = text.find("<x:xmpmeta")?;
+    let end = start + text[start..].find("</x:xmpmeta>")?;
+    Provenance::from_xmp(&text[start..end])
+}
+
+/// Check whether a file is a merged file previously generated by picmrg
+pub fn is_picmrg_output(path: &Path, matching: MergedFileMatching) -> bool {
+    match matching {
+        MergedFileMatching::Marker => read_marker(path).is_some(),
+        MergedFileMatching::LegacyName => path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(is_merged_file_name),
+    }
+}
+
+/// Check whether a file is a merged file picmrg may replace or delete: its output under a merged
+/// file name, so an output the user renamed to keep is left alone
+pub fn is_replaceable_output(path: &Path, matching: MergedFileMatching) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(is_merged_file_name)
+        && is_picmrg_output(path, matching)
+}
+
+/// Check if a filename is a merged file name (merged.png, merged-yy-mm-dd.png or a numbered part
+/// such as merged-yy-mm-dd-2.png, or the same with the extension of another output format)
+pub fn is_merged_file_name(filename: &str) -> bool {
+    let Some((stem, extension)) = filename.rsplit_once('.') else {
+        return false;
+    };
+    if !OutputFormat::ALL.iter().any(|format| format.extension() == extension) {
+        return false;
+    }
+
+    if stem == "merged" {
+        return true;
+    }
+
+    // Check for merged-yy-mm-dd pattern, with an optional -N part number
+    if let Some(date_part) = stem.strip_prefix("merged-") {
+        let date_part = match date_part.get(8..).and_then(|rest| rest.strip_prefix('-')) {
+            Some(part) if !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()) => &date_part[..8],
+            _ => date_part,
+        };
+        // Check if it matches yy-mm-dd pattern (8 characters with dashes at positions 2 and 5)
+        if date_part.len() == 8 && date_part.is_ascii() {
+            let chars: Vec<char> = date_part.chars().collect();
+            if chars[2] == '-' && chars[5] == '-' {
+                // Check if other characters are digits
+                let year_part = &date_part[0..2];
+                let month_part = &date_part[3..5];
+                let day_part = &date_part[6..8];
+
+                return year_part.chars().all(|c| c.is_ascii_digit()) &&
+                       month_part.chars().all(|c| c.is_ascii_digit()) &&
+                       day_part.chars().all(|c| c.is_ascii_digit());
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{setup_test_data_for_test, cleanup_test_data_for_test};
+
+    #[test]
+    fn test_is_merged_file_name() {
+        // Test basic merged file
+        assert!(is_merged_file_name("merged.png"));
+
+        // Test dated merged files
+        assert!(is_merged_file_name("merged-23-12-25.png"));
+        assert!(is_merged_file_name("merged-24-01-15.png"));
+        assert!(is_merged_file_name("merged-99-99-99.png")); // Edge case with high numbers
+
+        // Test other output formats
+        assert!(is_merged_file_name("merged.jpg"));
+        assert!(is_merged_file_name("merged-24-01-15.webp"));
+        assert!(is_merged_file_name("merged-24-01-15.tiff"));
+        assert!(is_merged_file_name("merged-24-01-15.avif"));
+
+        // Numbered parts of split merges
+        assert!(is_merged_file_name("merged-24-01-16-1.png"));
+        assert!(is_merged_file_name("merged-24-01-16-12.jpg"));
+        assert!(!is_merged_file_name("merged-24-01-16-.png"));
+        assert!(!is_merged_file_name("merged-24-01-16-a.png"));
+        assert!(!is_merged_file_name("merged-24-01-16_1.png"));
+
+        // Test invalid patterns
+        assert!(!is_merged_file_name("merged.gif")); // Not an output format
+        assert!(!is_merged_file_name("merged-24-01-15.jpeg")); // Not the extension picmrg writes
+        assert!(!is_merged_file_name("merged-2023-12-25.png")); // 4-digit year
+        assert!(!is_merged_file_name("merged-23-1-25.png")); // Single digit month
+        assert!(!is_merged_file_name("merged-23-12-5.png")); // Single digit day
+        assert!(!is_merged_file_name("merged-ab-cd-ef.png")); // Non-numeric
+        assert!(!is_merged_file_name("merged-23.12.25.png")); // Wrong separators
+        assert!(!is_merged_file_name("other.png")); // Regular file
+        assert!(!is_merged_file_name("merged-extra-23-12-25.png")); // Extra parts
+
+        // Test empty and edge cases
+        assert!(!is_merged_file_name(""));
+        assert!(!is_merged_file_name("merged"));
+        assert!(!is_merged_file_name("merged-"));
+        assert!(!is_merged_file_name("merged-.png"));
+    }
+
+    #[test]
+    fn test_provenance_round_trip() {
+        let provenance = Provenance::new("horizontal", &[PathBuf::from("dir/a.png"), PathBuf::from("dir/Ünïcode b.jpg")]);
+        assert_eq!(provenance.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(provenance.sources, vec!["a.png".to_string(), "Ünïcode b.jpg".to_string()]);
+
+        let parsed = Provenance::parse(&provenance.to_text()).expect("Failed to parse provenance");
+        assert_eq!(parsed, provenance);
+        let provenance = provenance.settings("0123456789abcdef").skipped(&[PathBuf::from("dir/broken.png")]);
+        let parsed = Provenance::parse(&provenance.to_text()).expect("Failed to parse provenance");
+        assert_eq!(parsed.settings, "0123456789abcdef");
+        assert_eq!(parsed.skipped, vec!["broken.png".to_string()]);
+
+        assert!(Provenance::parse("layout: vertical\n").is_none());
+
+        // The XMP form used by formats other than PNG survives special characters
+        let provenance = Provenance::new("grid", &[PathBuf::from("a & \"b\" <c>.png")]);
+        assert_eq!(Provenance::from_xmp(&provenance.to_xmp()), Some(provenance));
+        assert!(Provenance::from_xmp("<x:xmpmeta/>").is_none());
+    }
+
+    #[test]
+    fn test_is_picmrg_output() {
+        let test_root = setup_test_data_for_test("provenance").expect("Failed to setup test data");
+
+        // Fixture merged files are written with the marker, source images are not
+        let merged = Path::new(&test_root).join("vertical-images/merged.png");
+        let source = Path::new(&test_root).join("vertical-images/red.png");
+        let not_png = Path::new(&test_root).join("vertical-images/green.jpg");
+
+        assert!(read_marker(&merged).is_some());
+        assert!(is_picmrg_output(&merged, MergedFileMatching::Marker));
+        assert!(!is_picmrg_output(&source, MergedFileMatching::Marker));
+        assert!(!is_picmrg_output(&not_png, MergedFileMatching::Marker));
+
+        // A user's own file with a merged name is only ours under legacy matching
+        let imposter = Path::new(&test_root).join("vertical-images/merged-24-01-01.png");
+        std::fs::copy(&source, &imposter).expect("Failed to copy file");
+        assert!(!is_picmrg_output(&imposter, MergedFileMatching::Marker));
+        assert!(is_picmrg_output(&imposter, MergedFileMatching::LegacyName));
+
+        // A renamed merged file is still ours, but no longer replaced or removed
+        let renamed = Path::new(&test_root).join("vertical-images/evidence-for-audit.png");
+        std::fs::copy(&merged, &renamed).expect("Failed to copy file");
+        assert!(is_picmrg_output(&renamed, MergedFileMatching::Marker));
+        assert!(!is_replaceable_output(&renamed, MergedFileMatching::Marker));
+        assert!(is_replaceable_output(&merged, MergedFileMatching::Marker));
+        assert!(!is_replaceable_output(&imposter, MergedFileMatching::Marker));
+        assert!(is_replaceable_output(&imposter, MergedFileMatching::LegacyName));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+}
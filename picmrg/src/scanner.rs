@@ -1,16 +1,221 @@
-use std::fs;
+use image::ImageFormat;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
+use crate::error::Error;
+use crate::filter::{CompiledFilter, IgnoreRules};
+use crate::options::MergeOptions;
+use crate::output;
+use crate::provenance;
+
+/// Levels below the root scanned for images by default: only its direct subdirectories
+pub const DEFAULT_MAX_DEPTH: usize = 1;
+
+/// Which directories with images become merge groups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GroupSelection {
+    /// Only the deepest: directories with images none of whose scanned subdirectories have any
+    #[default]
+    Leaves,
+    /// Every directory with images, including those with image subdirectories of their own
+    All,
+}
+
+/// How deep directory trees are scanned and which directories in them are merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanSettings {
+    pub max_depth: usize, // Levels below the root; 1 scans only its direct subdirectories
+    pub groups: GroupSelection,
+}
+
+impl Default for ScanSettings {
+    /// The root's direct subdirectories, each one a group
+    fn default() -> Self {
+        ScanSettings {
+            max_depth: DEFAULT_MAX_DEPTH,
+            groups: GroupSelection::default(),
+        }
+    }
+}
+
+/// Formats of images that are merged, recognised by their content
+const SOURCE_FORMATS: [ImageFormat; 6] = [
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Gif,
+    ImageFormat::Bmp,
+    ImageFormat::Tiff,
+    ImageFormat::WebP,
+];
+
+/// Extensions of files expected to be images, reported when their content is not a mergeable image
+const IMAGE_EXTENSIONS: [&str; 9] = ["jpg", "jpeg", "png", "gif", "bmp", "tiff", "tif", "webp", "avif"];
+
+/// Bytes read from the start of a file to recognise its format
+const SNIFF_LENGTH: usize = 32;
+
+/// Files that operating systems leave next to images: exact names and name prefixes
+const JUNK_NAMES: [&str; 3] = ["Thumbs.db", ".DS_Store", "desktop.ini"];
+const JUNK_PREFIXES: [&str; 1] = ["._"]; // macOS AppleDouble files
+
+/// Why a file in an image directory is not merged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Metadata left by an operating system, such as `._shot.png` or `Thumbs.db`
+    Junk,
+    /// A file without any content
+    Empty,
+    /// Named like an image, but its content is not one (a renamed PDF, for instance)
+    NotAnImage,
+    /// An image in a format picmrg does not merge, such as AVIF or ICO
+    Unsupported(String),
+    /// Starts like an image, but its header cannot be read, as in a truncated download
+    Damaged(String),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Junk => write!(f, "system metadata file"),
+            SkipReason::Empty => write!(f, "empty file"),
+            SkipReason::NotAnImage => write!(f, "not an image despite its extension"),
+            SkipReason::Unsupported(format) => write!(f, "{} images are not supported", format),
+            SkipReason::Damaged(error) => write!(f, "damaged or incomplete image: {}", error),
+        }
+    }
+}
+
+/// A file in an image directory that is not merged, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
 
 /// Represents the result of scanning directories for image files
 #[derive(Debug)]
 pub struct ScanResult {
-    pub directories: HashMap<String, Vec<PathBuf>>,
+    pub directories: HashMap<PathBuf, Vec<PathBuf>>, // Keyed by path relative to the root
+    pub skipped: Vec<SkippedFile>,                   // In scanned directories, sorted by path
+}
+
+/// Find the directories down to the maximum depth below the root path that become merge groups,
+/// and collect image files within them
+///
+/// The root itself is never a group. Directories and files excluded by the options' filter or a
+/// `.picmrgignore` file are passed over. Symbolic links to directories are scanned but not
+/// descended into, so links back up the tree cannot loop.
+pub fn scan_for_images(
+    root_path: &Path,
+    options: &MergeOptions,
+) -> Result<ScanResult, Error> {
+    let mut scan = TreeScan {
+        root_path,
+        options,
+        filter: options.filter.compile()?,
+        directories: HashMap::new(),
+        skipped: Vec::new(),
+    };
+    let rules = IgnoreRules::for_directory(root_path)?;
+    for (path, rules) in scanned_subdirectories(root_path, &scan.filter, &rules)? {
+        scan.scan_directory(&path, 1, &rules)?;
+    }
+    scan.skipped.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(ScanResult {
+        directories: scan.directories,
+        skipped: scan.skipped,
+    })
 }
 
-/// Find all directories one level down from the root path and collect image files within them
-pub fn scan_for_images(root_path: &Path) -> Result<ScanResult, Box<dyn std::error::Error>> {
-    let mut directories = HashMap::new();
+/// A scan of the tree below a root path, collecting groups and skipped files as it goes
+struct TreeScan<'a> {
+    root_path: &'a Path,
+    options: &'a MergeOptions,
+    filter: CompiledFilter,
+    directories: HashMap<PathBuf, Vec<PathBuf>>,
+    skipped: Vec<SkippedFile>,
+}
+
+impl TreeScan<'_> {
+    /// Scan a directory at some depth below the root and its subdirectories, adding those that
+    /// are groups and the files skipped in them; returns whether the directory or any scanned
+    /// subdirectory has images
+    fn scan_directory(&mut self, path: &Path, depth: usize, rules: &IgnoreRules) -> Result<bool, Error> {
+        let mut nested_images = false;
+        if depth < self.options.scan.max_depth && !is_symlink(path) {
+            for (subdirectory, rules) in scanned_subdirectories(path, &self.filter, rules)? {
+                nested_images |= self.scan_directory(&subdirectory, depth + 1, &rules)?;
+            }
+        }
+
+        let image_files = filter_image_files(path, self.options, &self.filter, rules, &mut self.skipped)?;
+        let has_images = !image_files.is_empty();
+        let is_group = match self.options.scan.groups {
+            GroupSelection::Leaves => !nested_images,
+            GroupSelection::All => true,
+        };
+        if has_images && is_group {
+            let relative_path = path.strip_prefix(self.root_path).unwrap_or(path).to_path_buf();
+            self.directories.insert(relative_path, image_files);
+        }
+
+        Ok(has_images || nested_images)
+    }
+}
+
+/// The subdirectories of a directory that are neither excluded nor ignored, with their ignore rules
+fn scanned_subdirectories(
+    path: &Path,
+    filter: &CompiledFilter,
+    rules: &IgnoreRules,
+) -> Result<Vec<(PathBuf, IgnoreRules)>, Error> {
+    let mut subdirectories = Vec::new();
+    for subdirectory in find_subdirectories(path)? {
+        let Some(name) = subdirectory.file_name().map(|name| name.to_os_string()) else {
+            continue;
+        };
+        if filter.accepts_directory(&name) && !rules.is_ignored(&name, true) {
+            let rules = rules.enter(&name)?;
+            subdirectories.push((subdirectory, rules));
+        }
+    }
+    Ok(subdirectories)
+}
+
+/// Whether a path is a symbolic link, rather than what it points to
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
+
+/// Find every directory from one level down to the maximum depth below the root path that is
+/// neither excluded nor ignored, sorted by path; the directories `scan_for_images` looks for
+/// images in
+pub fn find_directories(root_path: &Path, options: &MergeOptions) -> Result<Vec<PathBuf>, Error> {
+    let filter = options.filter.compile()?;
+    let mut directories = Vec::new();
+    let mut level = vec![(root_path.to_path_buf(), IgnoreRules::for_directory(root_path)?)];
+    for depth in 1..=options.scan.max_depth {
+        let mut next_level = Vec::new();
+        for (path, rules) in &level {
+            if depth > 1 && is_symlink(path) {
+                continue;
+            }
+            next_level.extend(scanned_subdirectories(path, &filter, rules)?);
+        }
+        directories.extend(next_level.iter().map(|(path, _)| path.clone()));
+        level = next_level;
+    }
+
+    directories.sort();
+    Ok(directories)
+}
+
+/// Find all directories one level down from the root path, sorted by name
+pub fn find_subdirectories(root_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut subdirectories = Vec::new();
 
     // Read the root directory
     let entries = fs::read_dir(root_path)?;
@@ -21,26 +226,49 @@ pub fn scan_for_images(root_path: &Path) -> Result<ScanResult, Box<dyn std::erro
 
         // Only process directories (one level down)
         if path.is_dir() {
-            let dir_name = path.file_name()
-                .and_then(|name| name.to_str())
-                .unwrap_or("unknown")
-                .to_string();
-
-            let image_files = find_image_files(&path)?;
-            
-            if !image_files.is_empty() {
-                directories.insert(dir_name, image_files);
-            }
+            subdirectories.push(path);
         }
     }
 
-    Ok(ScanResult {
-        directories,
-    })
+    subdirectories.sort();
+    Ok(subdirectories)
+}
+
+/// Find all image files in a given directory, recognised by their content and selected by the
+/// options' filter and the `.picmrgignore` files of the directory and those above it
+pub fn find_image_files(
+    dir_path: &Path,
+    options: &MergeOptions,
+) -> Result<Vec<PathBuf>, Error> {
+    collect_image_files(dir_path, options, &mut Vec::new())
 }
 
-/// Find all image files in a given directory
-fn find_image_files(dir_path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+/// Find all image files in a given directory like `find_image_files`, adding the files that look
+/// like images but are not merged to `skipped`
+///
+/// Files are recognised by their first bytes, whatever their name, so images without an extension
+/// are found. Junk files are always reported; other files only if they are named like images, so
+/// notes and documents next to the screenshots are passed over quietly. Files excluded by the
+/// filter or ignored are passed over without a report.
+pub fn collect_image_files(
+    dir_path: &Path,
+    options: &MergeOptions,
+    skipped: &mut Vec<SkippedFile>,
+) -> Result<Vec<PathBuf>, Error> {
+    let filter = options.filter.compile()?;
+    let rules = IgnoreRules::for_directory(dir_path)?;
+    filter_image_files(dir_path, options, &filter, &rules, skipped)
+}
+
+/// Find the image files in a directory like `collect_image_files`, with a compiled filter and the
+/// directory's ignore rules
+fn filter_image_files(
+    dir_path: &Path,
+    options: &MergeOptions,
+    filter: &CompiledFilter,
+    rules: &IgnoreRules,
+    skipped: &mut Vec<SkippedFile>,
+) -> Result<Vec<PathBuf>, Error> {
     let mut image_files = Vec::new();
     
     let entries = fs::read_dir(dir_path)?;
@@ -49,20 +277,28 @@ fn find_image_files(dir_path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error:
         let entry = entry?;
         let path = entry.path();
 
-        // Only process files (not subdirectories)
-        if path.is_file() {
-            // Skip merged files to avoid including them in new merges
-            if let Some(file_name) = path.file_name() {
-                if let Some(name_str) = file_name.to_str() {
-                    if is_merged_file(name_str) {
-                        continue;
-                    }
+        // Only process files (not subdirectories), leaving half-written outputs to `clean`
+        let name = entry.file_name();
+        let is_temp_file = name.to_str().is_some_and(output::is_temp_file);
+        if !path.is_file() || is_temp_file {
+            continue;
+        }
+
+        // Pass over files the user left out
+        if !filter.accepts_file(&name) || rules.is_ignored(&name, false) {
+            continue;
+        }
+
+        // Skip merged files to avoid including them in new merges
+        match check_image_file(&path) {
+            Ok(()) if provenance::is_picmrg_output(&path, options.matching) => {}
+            Ok(()) => image_files.push(path),
+            Err(SkipReason::Junk) => skipped.push(SkippedFile { path, reason: SkipReason::Junk }),
+            Err(reason) => {
+                if has_image_extension(&path) && !provenance::is_picmrg_output(&path, options.matching) {
+                    skipped.push(SkippedFile { path, reason });
                 }
             }
-            
-            if is_image_file(&path) {
-                image_files.push(path);
-            }
         }
     }
 
@@ -71,109 +307,129 @@ fn find_image_files(dir_path: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error:
     Ok(image_files)
 }
 
-/// Check if a filename is a merged file (merged.png or merged-yy-mm-dd.png)
-fn is_merged_file(filename: &str) -> bool {
-    if filename == "merged.png" {
-        return true;
+/// Check that a file is an image in a format that is merged, judging by its name and content
+fn check_image_file(path: &Path) -> Result<(), SkipReason> {
+    if is_junk_file(path) {
+        return Err(SkipReason::Junk);
     }
-    
-    // Check for merged-yy-mm-dd.png pattern
-    if filename.starts_with("merged-") && filename.ends_with(".png") {
-        let date_part = &filename[7..filename.len()-4]; // Remove "merged-" and ".png"
-        
-        // Check if it matches yy-mm-dd pattern (8 characters with dashes at positions 2 and 5)
-        if date_part.len() == 8 {
-            let chars: Vec<char> = date_part.chars().collect();
-            if chars[2] == '-' && chars[5] == '-' {
-                // Check if other characters are digits
-                let year_part = &date_part[0..2];
-                let month_part = &date_part[3..5];
-                let day_part = &date_part[6..8];
-                
-                return year_part.chars().all(|c| c.is_ascii_digit()) &&
-                       month_part.chars().all(|c| c.is_ascii_digit()) &&
-                       day_part.chars().all(|c| c.is_ascii_digit());
-            }
-        }
+
+    let damaged = |e: &dyn fmt::Display| SkipReason::Damaged(e.to_string());
+    let mut header = Vec::with_capacity(SNIFF_LENGTH);
+    File::open(path)
+        .and_then(|file| file.take(SNIFF_LENGTH as u64).read_to_end(&mut header))
+        .map_err(|e| damaged(&e))?;
+    if header.is_empty() {
+        return Err(SkipReason::Empty);
     }
-    
-    false
-}
 
-/// Check if a file is an image based on its extension
-fn is_image_file(path: &Path) -> bool {
-    if let Some(extension) = path.extension() {
-        if let Some(ext_str) = extension.to_str() {
-            let ext_lower = ext_str.to_lowercase();
-            matches!(ext_lower.as_str(), 
-                "jpg" | "jpeg" | "png" | "gif" | "bmp" | "tiff" | "tif" | "webp"
-            )
-        } else {
-            false
-        }
-    } else {
-        false
+    let format = image::guess_format(&header).map_err(|_| SkipReason::NotAnImage)?;
+    if !SOURCE_FORMATS.contains(&format) {
+        return Err(SkipReason::Unsupported(format!("{:?}", format).to_uppercase()));
     }
+
+    // Reading the dimensions catches files cut off before the image data
+    let file = File::open(path).map_err(|e| damaged(&e))?;
+    image::io::Reader::with_format(BufReader::new(file), format)
+        .into_dimensions()
+        .map_err(|e| damaged(&e))?;
+    Ok(())
+}
+
+/// Open an image for reading in the format its content shows, whatever its name says
+pub(crate) fn open_image(path: &Path) -> image::ImageResult<image::io::Reader<BufReader<File>>> {
+    Ok(image::io::Reader::open(path)?.with_guessed_format()?)
+}
+
+/// Check if a file is metadata left by an operating system, judging by its name
+fn is_junk_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            JUNK_NAMES.iter().any(|junk| name.eq_ignore_ascii_case(junk))
+                || JUNK_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+        })
+}
+
+/// Check if a file is named like an image
+fn has_image_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_utils::{setup_test_data_for_test, cleanup_test_data_for_test};
+    use crate::filter::IGNORE_FILE_NAME;
+    use crate::provenance::MergedFileMatching;
+    use crate::test_utils::{setup_test_data_for_test, cleanup_test_data_for_test, generate_test_image, save_test_image};
     
     #[test]
-    fn test_is_image_file() {
+    fn test_has_image_extension() {
         // Test various image extensions
-        assert!(is_image_file(Path::new("test.jpg")));
-        assert!(is_image_file(Path::new("test.jpeg")));
-        assert!(is_image_file(Path::new("test.png")));
-        assert!(is_image_file(Path::new("test.gif")));
-        assert!(is_image_file(Path::new("test.bmp")));
-        assert!(is_image_file(Path::new("test.tiff")));
-        assert!(is_image_file(Path::new("test.tif")));
-        assert!(is_image_file(Path::new("test.webp")));
+        assert!(has_image_extension(Path::new("test.jpg")));
+        assert!(has_image_extension(Path::new("test.jpeg")));
+        assert!(has_image_extension(Path::new("test.png")));
+        assert!(has_image_extension(Path::new("test.gif")));
+        assert!(has_image_extension(Path::new("test.bmp")));
+        assert!(has_image_extension(Path::new("test.tiff")));
+        assert!(has_image_extension(Path::new("test.tif")));
+        assert!(has_image_extension(Path::new("test.webp")));
         
         // Test case insensitivity
-        assert!(is_image_file(Path::new("test.JPG")));
-        assert!(is_image_file(Path::new("test.PNG")));
-        assert!(is_image_file(Path::new("test.JPEG")));
+        assert!(has_image_extension(Path::new("test.JPG")));
+        assert!(has_image_extension(Path::new("test.PNG")));
+        assert!(has_image_extension(Path::new("test.JPEG")));
         
         // Test non-image files
-        assert!(!is_image_file(Path::new("test.txt")));
-        assert!(!is_image_file(Path::new("test.pdf")));
-        assert!(!is_image_file(Path::new("test.doc")));
-        assert!(!is_image_file(Path::new("test")));
-        assert!(!is_image_file(Path::new("")));
+        assert!(!has_image_extension(Path::new("test.txt")));
+        assert!(!has_image_extension(Path::new("test.pdf")));
+        assert!(!has_image_extension(Path::new("test.doc")));
+        assert!(!has_image_extension(Path::new("test")));
+        assert!(!has_image_extension(Path::new("")));
         
         // Test files without extensions
-        assert!(!is_image_file(Path::new("no_extension")));
+        assert!(!has_image_extension(Path::new("no_extension")));
     }
     
     #[test]
-    fn test_is_merged_file() {
-        // Test basic merged file
-        assert!(is_merged_file("merged.png"));
-        
-        // Test dated merged files
-        assert!(is_merged_file("merged-23-12-25.png"));
-        assert!(is_merged_file("merged-24-01-15.png"));
-        assert!(is_merged_file("merged-99-99-99.png")); // Edge case with high numbers
-        
-        // Test invalid patterns
-        assert!(!is_merged_file("merged.jpg")); // Wrong extension
-        assert!(!is_merged_file("merged-2023-12-25.png")); // 4-digit year
-        assert!(!is_merged_file("merged-23-1-25.png")); // Single digit month
-        assert!(!is_merged_file("merged-23-12-5.png")); // Single digit day
-        assert!(!is_merged_file("merged-ab-cd-ef.png")); // Non-numeric
-        assert!(!is_merged_file("merged-23.12.25.png")); // Wrong separators
-        assert!(!is_merged_file("other.png")); // Regular file
-        assert!(!is_merged_file("merged-extra-23-12-25.png")); // Extra parts
-        
-        // Test empty and edge cases
-        assert!(!is_merged_file(""));
-        assert!(!is_merged_file("merged"));
-        assert!(!is_merged_file("merged-"));
-        assert!(!is_merged_file("merged-.png"));
+    fn test_collect_image_files_sniffs_content() {
+        let test_root = setup_test_data_for_test("sniff").expect("Failed to setup test data");
+        let dir = Path::new(&test_root).join("vertical-images");
+        
+        // An image without extension is found; junk, renamed, empty and truncated files are not
+        fs::copy(dir.join("red.png"), dir.join("screenshot")).expect("Failed to copy file");
+        fs::write(dir.join("._red.png"), b"\0\x05\x16\x07\0\x02\0\0Mac OS X").expect("Failed to write file");
+        fs::write(dir.join(".DS_Store"), b"\0\0\0\x01Bud1").expect("Failed to write file");
+        fs::write(dir.join("report.png"), b"%PDF-1.7\n").expect("Failed to write file");
+        fs::write(dir.join("empty.jpg"), b"").expect("Failed to write file");
+        let png = fs::read(dir.join("red.png")).expect("Failed to read file");
+        fs::write(dir.join("download.png"), &png[..20]).expect("Failed to write file");
+        fs::write(dir.join("notes.txt"), b"webinar notes").expect("Failed to write file");
+        
+        let mut skipped = Vec::new();
+        let image_files = collect_image_files(&dir, &MergeOptions::new(), &mut skipped).unwrap();
+        let names: Vec<&str> = image_files.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, vec!["blue.jpeg", "green.jpg", "red.png", "screenshot"]);
+        
+        skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        let reasons: Vec<(&str, &SkipReason)> = skipped.iter()
+            .map(|file| (file.path.file_name().unwrap().to_str().unwrap(), &file.reason))
+            .collect();
+        assert_eq!(reasons.len(), 5, "{:?}", reasons);
+        assert_eq!(reasons[0], (".DS_Store", &SkipReason::Junk));
+        assert_eq!(reasons[1], ("._red.png", &SkipReason::Junk));
+        assert!(matches!(reasons[2], ("download.png", SkipReason::Damaged(_))), "{:?}", reasons[2]);
+        assert_eq!(reasons[3], ("empty.jpg", &SkipReason::Empty));
+        assert_eq!(reasons[4], ("report.png", &SkipReason::NotAnImage));
+        assert_eq!(SkipReason::Junk.to_string(), "system metadata file");
+        
+        // The scan reports them too, by full path
+        let result = scan_for_images(Path::new(&test_root), &MergeOptions::new()).unwrap();
+        assert_eq!(result.skipped.len(), 5);
+        assert!(result.skipped[0].path.starts_with(&dir));
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
     }
     
     #[test]
@@ -182,20 +438,20 @@ mod tests {
         let test_root = setup_test_data_for_test("scan").expect("Failed to setup test data");
         
         // Scan the test directory
-        let result = scan_for_images(Path::new(&test_root)).expect("Failed to scan test data");
+        let result = scan_for_images(Path::new(&test_root), &MergeOptions::new()).expect("Failed to scan test data");
         
         // Verify we found the expected directories with images
-        assert!(result.directories.contains_key("vertical-images"));
-        assert!(result.directories.contains_key("horizontal-images"));
-        assert!(result.directories.contains_key("mixed-images"));
-        assert!(result.directories.contains_key("single-image"));
+        assert!(result.directories.contains_key(Path::new("vertical-images")));
+        assert!(result.directories.contains_key(Path::new("horizontal-images")));
+        assert!(result.directories.contains_key(Path::new("mixed-images")));
+        assert!(result.directories.contains_key(Path::new("single-image")));
         
         // Verify we didn't include empty directories or directories with no images
-        assert!(!result.directories.contains_key("empty-dir"));
-        assert!(!result.directories.contains_key("no-images"));
+        assert!(!result.directories.contains_key(Path::new("empty-dir")));
+        assert!(!result.directories.contains_key(Path::new("no-images")));
         
         // Check vertical images directory (should have 3 images, excluding merged.png)
-        let vertical_images = &result.directories["vertical-images"];
+        let vertical_images = &result.directories[Path::new("vertical-images")];
         assert_eq!(vertical_images.len(), 3);
         let vertical_names: Vec<String> = vertical_images.iter()
             .map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
@@ -207,7 +463,7 @@ mod tests {
         assert!(!vertical_names.contains(&"merged.png".to_string()));
         
         // Check horizontal images directory (should have 3 images, excluding merged-23-12-25.png)
-        let horizontal_images = &result.directories["horizontal-images"];
+        let horizontal_images = &result.directories[Path::new("horizontal-images")];
         assert_eq!(horizontal_images.len(), 3);
         let horizontal_names: Vec<String> = horizontal_images.iter()
             .map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
@@ -219,7 +475,7 @@ mod tests {
         assert!(!horizontal_names.contains(&"merged-23-12-25.png".to_string()));
         
         // Check mixed images directory
-        let mixed_images = &result.directories["mixed-images"];
+        let mixed_images = &result.directories[Path::new("mixed-images")];
         assert_eq!(mixed_images.len(), 3);
         let mixed_names: Vec<String> = mixed_images.iter()
             .map(|p| p.file_name().unwrap().to_str().unwrap().to_string())
@@ -229,7 +485,7 @@ mod tests {
         assert!(mixed_names.contains(&"gray.webp".to_string()));
         
         // Check single image directory
-        let single_images = &result.directories["single-image"];
+        let single_images = &result.directories[Path::new("single-image")];
         assert_eq!(single_images.len(), 1);
         assert_eq!(single_images[0].file_name().unwrap().to_str().unwrap(), "orange.png");
         
@@ -237,13 +493,100 @@ mod tests {
         cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
     }
     
+    #[test]
+    fn test_scan_for_images_recursive() {
+        let test_root = setup_test_data_for_test("scan_recursive").expect("Failed to setup test data");
+        let root = Path::new(&test_root);
+        
+        // 2024/Q1/2024-01-15-webinar holds images, and so does 2024/Q1 itself
+        let webinar = root.join("2024/Q1/2024-01-15-webinar");
+        fs::create_dir_all(&webinar).expect("Failed to create directories");
+        for (index, name) in ["a.png", "b.png"].iter().enumerate() {
+            save_test_image(&generate_test_image(20, 10, [index as u8, 0, 0]), &webinar.join(name)).expect("Failed to save image");
+        }
+        save_test_image(&generate_test_image(20, 10, [0, 0, 0]), &root.join("2024/Q1/overview.png")).expect("Failed to save image");
+        let keys = |result: ScanResult| -> Vec<PathBuf> {
+            let mut keys: Vec<PathBuf> = result.directories.into_keys().collect();
+            keys.sort();
+            keys
+        };
+        
+        // By default only the root's children are scanned; 2024 has no images of its own
+        let flat = keys(scan_for_images(root, &MergeOptions::new()).unwrap());
+        assert!(!flat.iter().any(|path| path.starts_with("2024")), "{:?}", flat);
+        
+        // Deep enough, the leaf is found by its relative path; the directory above it is not a leaf
+        let options = MergeOptions::new().max_depth(3);
+        let result = scan_for_images(root, &options).unwrap();
+        assert_eq!(result.directories[&PathBuf::from("2024/Q1/2024-01-15-webinar")].len(), 2);
+        assert!(!result.directories.contains_key(Path::new("2024/Q1")));
+        assert!(result.directories.contains_key(Path::new("vertical-images")));
+        
+        // Every directory with images
+        let all = keys(scan_for_images(root, &options.clone().groups(GroupSelection::All)).unwrap());
+        assert!(all.contains(&PathBuf::from("2024/Q1")) && all.contains(&PathBuf::from("2024/Q1/2024-01-15-webinar")));
+        
+        // Two levels down, Q1 is as deep as the scan goes, so it is the leaf
+        let shallow = keys(scan_for_images(root, &MergeOptions::new().max_depth(2)).unwrap());
+        assert!(shallow.contains(&PathBuf::from("2024/Q1")), "{:?}", shallow);
+        assert!(!shallow.iter().any(|path| path.ends_with("2024-01-15-webinar")));
+        
+        // Every scanned directory, with or without images
+        let directories = find_directories(root, &options).unwrap();
+        assert!(directories.contains(&root.join("2024")) && directories.contains(&webinar));
+        assert!(directories.contains(&root.join("empty-dir")));
+        assert_eq!(find_directories(root, &MergeOptions::new()).unwrap(), find_subdirectories(root).unwrap());
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
+    #[test]
+    fn test_scan_for_images_filtered() {
+        let test_root = setup_test_data_for_test("scan_filtered").expect("Failed to setup test data");
+        let root = Path::new(&test_root);
+        let names = |files: &[PathBuf]| -> Vec<String> {
+            files.iter().map(|path| path.file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+
+        // Included names select files; excluded names drop files and whole directories
+        let options = MergeOptions::new().include("*.png").exclude("mixed-*");
+        let result = scan_for_images(root, &options).unwrap();
+        assert!(!result.directories.contains_key(Path::new("mixed-images")));
+        assert_eq!(names(&result.directories[Path::new("horizontal-images")]), vec!["yellow.png".to_string()]);
+        assert!(!names(&result.directories[Path::new("vertical-images")]).contains(&"green.jpg".to_string()));
+        assert!(!find_directories(root, &options).unwrap().contains(&root.join("mixed-images")));
+
+        // A .picmrgignore at the root applies to every directory below it, and one deeper
+        // re-includes a name; ignored files are not reported as skipped
+        fs::write(root.join(IGNORE_FILE_NAME), "green.*\nsingle-image/\n").expect("Failed to write file");
+        fs::write(root.join("vertical-images").join(IGNORE_FILE_NAME), "!green.jpg\n").expect("Failed to write file");
+        fs::write(root.join("horizontal-images/green.png"), b"not an image").expect("Failed to write file");
+        let result = scan_for_images(root, &MergeOptions::new()).unwrap();
+        assert!(!result.directories.contains_key(Path::new("single-image")));
+        assert!(names(&result.directories[Path::new("vertical-images")]).contains(&"green.jpg".to_string()));
+        assert!(!result.skipped.iter().any(|file| file.path.ends_with("horizontal-images/green.png")));
+        assert!(!find_directories(root, &MergeOptions::new()).unwrap().contains(&root.join("single-image")));
+
+        // Files in a directory honour the ignore files above it
+        fs::write(root.join("mixed-images").join(IGNORE_FILE_NAME), "*.webp\n").expect("Failed to write file");
+        let mut skipped = Vec::new();
+        let files = collect_image_files(&root.join("mixed-images"), &MergeOptions::new(), &mut skipped).unwrap();
+        assert_eq!(names(&files).len(), 2, "{:?}", files);
+        assert!(skipped.is_empty());
+
+        // Invalid patterns are errors rather than silently matching nothing
+        assert!(matches!(scan_for_images(root, &MergeOptions::new().exclude("[")), Err(Error::InvalidGlob { .. })));
+
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+
     #[test]
     fn test_find_image_files_ordering() {
         let test_root = setup_test_data_for_test("ordering").expect("Failed to setup test data");
         
         // Test that files are returned in sorted order
         let vertical_dir = Path::new(&test_root).join("vertical-images");
-        let image_files = find_image_files(&vertical_dir).expect("Failed to find image files");
+        let image_files = find_image_files(&vertical_dir, &MergeOptions::new()).expect("Failed to find image files");
         
         // Convert to filenames and verify sorting
         let filenames: Vec<String> = image_files.iter()
@@ -258,9 +601,35 @@ mod tests {
         cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
     }
     
+    #[test]
+    fn test_find_image_files_keeps_unmarked_merged_names() {
+        let test_root = setup_test_data_for_test("unmarked").expect("Failed to setup test data");
+        
+        // A user's own file that merely looks like a merged file
+        let vertical_dir = Path::new(&test_root).join("vertical-images");
+        fs::copy(vertical_dir.join("red.png"), vertical_dir.join("merged-24-01-01.png")).expect("Failed to copy file");
+        
+        let names = |files: Vec<PathBuf>| -> Vec<String> {
+            files.iter().map(|p| p.file_name().unwrap().to_str().unwrap().to_string()).collect()
+        };
+        
+        // Marker matching only skips the real (marked) merged.png
+        let marker_files = names(find_image_files(&vertical_dir, &MergeOptions::new()).unwrap());
+        assert!(marker_files.contains(&"merged-24-01-01.png".to_string()));
+        assert!(!marker_files.contains(&"merged.png".to_string()));
+        
+        // Legacy matching skips both by name
+        let legacy_files = names(find_image_files(&vertical_dir, &MergeOptions::new().matching(MergedFileMatching::LegacyName)).unwrap());
+        assert!(!legacy_files.contains(&"merged-24-01-01.png".to_string()));
+        assert!(!legacy_files.contains(&"merged.png".to_string()));
+        assert_eq!(legacy_files.len(), 3);
+        
+        cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
+    }
+    
     #[test]
     fn test_scan_nonexistent_directory() {
-        let result = scan_for_images(Path::new("nonexistent-directory"));
+        let result = scan_for_images(Path::new("nonexistent-directory"), &MergeOptions::new());
         assert!(result.is_err());
     }
     
@@ -269,7 +638,7 @@ mod tests {
         let test_root = setup_test_data_for_test("empty").expect("Failed to setup test data");
         
         let empty_dir = Path::new(&test_root).join("empty-dir");
-        let image_files = find_image_files(&empty_dir).expect("Failed to scan empty directory");
+        let image_files = find_image_files(&empty_dir, &MergeOptions::new()).expect("Failed to scan empty directory");
         assert!(image_files.is_empty());
         
         cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
@@ -280,7 +649,7 @@ mod tests {
         let test_root = setup_test_data_for_test("scanner_no_images").expect("Failed to setup test data");
         
         let no_images_dir = Path::new(&test_root).join("no-images");
-        let image_files = find_image_files(&no_images_dir).expect("Failed to scan no-images directory");
+        let image_files = find_image_files(&no_images_dir, &MergeOptions::new()).expect("Failed to scan no-images directory");
         assert!(image_files.is_empty());
         
         cleanup_test_data_for_test(&test_root).expect("Failed to cleanup test data");
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::layout::{Arrangement, Layout, LayoutRegistry, LayoutSettings};
+
+/// Weight of the output size relative to the sources' total pixels (either way)
+const PIXELS_WEIGHT: f64 = 1.0;
+/// Weight of the share of the canvas not covered by any image
+const EMPTY_WEIGHT: f64 = 2.0;
+/// Weight of the largest enlargement beyond native size
+const UPSCALE_WEIGHT: f64 = 1.0;
+/// Weight of the distance from a screen-shaped output
+const ASPECT_WEIGHT: f64 = 1.0;
+/// Output aspect ratio (width / height) that costs nothing, unless the output is printed on a page
+const TARGET_ASPECT_RATIO: f64 = 4.0 / 3.0;
+
+/// How well a layout presents a set of images; lower cost is better
+#[derive(Debug, Clone)]
+pub struct LayoutScore {
+    pub layout: Arc<dyn Layout>,
+    pub total_pixels: u64,   // Pixels in the output canvas
+    pub empty_fraction: f64, // Share of the canvas not covered by any image
+    pub max_upscale: f64,    // Largest factor any image is enlarged by (1.0 if none is)
+    pub aspect_ratio: f64,   // Output width / height
+    pub cost: f64,
+}
+
+impl LayoutScore {
+    /// Score the arrangement a layout produces for images of the given (width, height) sizes
+    pub fn new(layout: Arc<dyn Layout>, dimensions: &[(u32, u32)], settings: &LayoutSettings) -> Self {
+        let arrangement = layout.arrange(dimensions, settings);
+        let total_pixels = arrangement.width as u64 * arrangement.height as u64;
+        let empty_fraction = empty_fraction(&arrangement);
+        let max_upscale = max_upscale(&arrangement, dimensions);
+        let aspect_ratio = if arrangement.height == 0 {
+            TARGET_ASPECT_RATIO
+        } else {
+            arrangement.width as f64 / arrangement.height as f64
+        };
+
+        let source_pixels: u64 = dimensions.iter().map(|(width, height)| *width as u64 * *height as u64).sum();
+        let pixels_cost = if total_pixels == 0 || source_pixels == 0 {
+            0.0
+        } else {
+            (total_pixels as f64 / source_pixels as f64).ln().abs()
+        };
+        let cost = PIXELS_WEIGHT * pixels_cost
+            + EMPTY_WEIGHT * empty_fraction
+            + UPSCALE_WEIGHT * (max_upscale - 1.0)
+            + ASPECT_WEIGHT * (aspect_ratio / target_aspect_ratio(aspect_ratio, settings)).ln().abs();
+
+        LayoutScore { layout, total_pixels, empty_fraction, max_upscale, aspect_ratio, cost }
+    }
+}
+
+impl fmt::Display for LayoutScore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<12} cost {:>5.2}  {:>10} px  {:>3.0}% empty  {:>4.2}x upscale  {:.2}:1 aspect",
+            self.layout.name(),
+            self.cost,
+            self.total_pixels,
+            self.empty_fraction * 100.0,
+            self.max_upscale,
+            self.aspect_ratio,
+        )
+    }
+}
+
+/// Score every registered layout for images of the given sizes, best first
+///
+/// Ties go to the layout registered first.
+pub fn rank_layouts(
+    registry: &LayoutRegistry,
+    dimensions: &[(u32, u32)],
+    settings: &LayoutSettings,
+) -> Vec<LayoutScore> {
+    let mut scores: Vec<LayoutScore> = registry.layouts()
+        .map(|layout| LayoutScore::new(layout.clone(), dimensions, settings))
+        .collect();
+    scores.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+    scores
+}
+
+/// Share of the canvas not covered by any placement (placements never overlap)
+fn empty_fraction(arrangement: &Arrangement) -> f64 {
+    let canvas = arrangement.width as u64 * arrangement.height as u64;
+    if canvas == 0 {
+        return 0.0;
+    }
+
+    let covered: u64 = arrangement.placements.iter()
+        .map(|placement| placement.width as u64 * placement.height as u64)
+        .sum();
+    1.0 - (covered.min(canvas) as f64 / canvas as f64)
+}
+
+/// Aspect ratio an output should have: the page's in the orientation closer to the output's, so
+/// that it fills the printable area, or a screen's
+fn target_aspect_ratio(aspect_ratio: f64, settings: &LayoutSettings) -> f64 {
+    match settings.page_aspect_ratio {
+        Some(portrait) => {
+            let landscape = 1.0 / portrait;
+            if (aspect_ratio / portrait).ln().abs() <= (aspect_ratio / landscape).ln().abs() { portrait } else { landscape }
+        }
+        None => TARGET_ASPECT_RATIO,
+    }
+}
+
+/// Largest factor by which any image is enlarged, at least 1.0
+fn max_upscale(arrangement: &Arrangement, dimensions: &[(u32, u32)]) -> f64 {
+    dimensions.iter().zip(&arrangement.placements)
+        .map(|((width, height), placement)| {
+            let horizontal = placement.width as f64 / (*width).max(1) as f64;
+            let vertical = placement.height as f64 / (*height).max(1) as f64;
+            horizontal.max(vertical)
+        })
+        .fold(1.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{Grid, Horizontal, Vertical};
+
+    #[test]
+    fn test_layout_score_metrics() {
+        // Two 100x100 images side by side: no waste, no scaling, 2:1
+        let score = LayoutScore::new(Arc::new(Horizontal), &[(100, 100), (100, 100)], &LayoutSettings::default());
+        assert_eq!(score.total_pixels, 20000);
+        assert_eq!(score.empty_fraction, 0.0);
+        assert_eq!(score.max_upscale, 1.0);
+        assert_eq!(score.aspect_ratio, 2.0);
+
+        // Three images in a 2x2 grid leave a quarter of the canvas empty
+        let score = LayoutScore::new(Arc::new(Grid::new()), &[(100, 100); 3], &LayoutSettings::default());
+        assert_eq!(score.empty_fraction, 0.25);
+
+        // The 100px wide image is stacked at 300px wide
+        let score = LayoutScore::new(Arc::new(Vertical), &[(100, 100), (300, 100)], &LayoutSettings::default());
+        assert_eq!(score.max_upscale, 3.0);
+    }
+
+    #[test]
+    fn test_rank_layouts() {
+        let registry = LayoutRegistry::default();
+        let dimensions = [(1920, 1080); 8];
+
+        let scores = rank_layouts(&registry, &dimensions, &LayoutSettings::default());
+        assert_eq!(scores.len(), registry.layouts().count());
+        assert!(scores.windows(2).all(|pair| pair[0].cost <= pair[1].cost));
+
+        // Eight screenshots are not stacked into a tall strip or laid out in a long row
+        let best = scores[0].layout.name();
+        assert!(best != "vertical" && best != "horizontal", "picked {}", best);
+    }
+
+    #[test]
+    fn test_layout_score_page_aspect_ratio() {
+        // Two squares stacked are 1:2, far from a screen but exactly a page of that shape, in
+        // either orientation
+        let dimensions = [(100, 100); 2];
+        let screen = LayoutScore::new(Arc::new(Vertical), &dimensions, &LayoutSettings::default());
+        assert!(screen.cost > 0.9);
+
+        let page = LayoutSettings { page_aspect_ratio: Some(0.5), ..LayoutSettings::default() };
+        assert_eq!(LayoutScore::new(Arc::new(Vertical), &dimensions, &page).cost, 0.0);
+        assert_eq!(LayoutScore::new(Arc::new(Horizontal), &dimensions, &page).cost, 0.0);
+    }
+
+    #[test]
+    fn test_rank_layouts_empty() {
+        let scores = rank_layouts(&LayoutRegistry::default(), &[], &LayoutSettings::default());
+        assert!(scores.iter().all(|score| score.cost == 0.0));
+    }
+}
@@ -0,0 +1,210 @@
+use chrono::{DateTime, Local};
+use image::{DynamicImage, ImageBuffer, Pixel, Rgba, RgbaImage};
+use std::path::Path;
+use crate::header;
+use crate::text;
+
+/// Smallest and largest font size of the footer line, in pixels
+const MIN_FOOTER_SIZE: f32 = 12.0;
+const MAX_FOOTER_SIZE: f32 = 36.0;
+/// Canvas width per pixel of footer font size
+const CANVAS_WIDTH_PER_FONT_PIXEL: f32 = 60.0;
+/// Share of the output's diagonal the watermark text spans
+const WATERMARK_SPAN: f32 = 0.8;
+/// Largest watermark font size relative to the output's shorter side
+const WATERMARK_MAX_HEIGHT: f32 = 0.25;
+/// Colour of the watermark text before its opacity is applied, visible on light and dark images
+const WATERMARK_COLOR: Rgba<u8> = Rgba([128, 128, 128, 255]);
+/// Default watermark opacity, in percent
+pub const DEFAULT_WATERMARK_OPACITY: u8 = 20;
+
+/// Text stamped onto every merged output to attribute it to the member; nothing by default
+///
+/// Templates may contain `{name}` (directory name), `{title}` (directory name without a leading
+/// date) and `{date}` (the merge date, YYYY-MM-DD).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    pub footer: Option<String>,    // Template of a line below the composite
+    pub watermark: Option<String>, // Template of text drawn diagonally across the output
+    pub watermark_opacity: u8,     // Percent, 0 to 100
+}
+
+impl Default for Stamp {
+    fn default() -> Self {
+        Stamp {
+            footer: None,
+            watermark: None,
+            watermark_opacity: DEFAULT_WATERMARK_OPACITY,
+        }
+    }
+}
+
+impl Stamp {
+    /// Footer line for a directory merged at `merge_date`, if a footer is configured
+    pub fn footer_text(&self, directory: Option<&Path>, merge_date: DateTime<Local>) -> Option<String> {
+        self.footer.as_deref().map(|template| fill_template(template, directory, merge_date))
+    }
+
+    /// Watermark text for a directory merged at `merge_date`, if a watermark is configured
+    pub fn watermark_text(&self, directory: Option<&Path>, merge_date: DateTime<Local>) -> Option<String> {
+        self.watermark.as_deref().map(|template| fill_template(template, directory, merge_date))
+    }
+}
+
+/// Replace the placeholders of a stamp template; placeholders without a value (such as `{name}`
+/// for images merged in memory) become empty, unknown ones are kept as they are
+pub fn fill_template(template: &str, directory: Option<&Path>, merge_date: DateTime<Local>) -> String {
+    let name = directory
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let title = if name.is_empty() { String::new() } else { header::parse_title(&name) };
+
+    template
+        .replace("{name}", &name)
+        .replace("{title}", &title)
+        .replace("{date}", &merge_date.format("%Y-%m-%d").to_string())
+}
+
+/// Font size of the footer on a canvas of the given width
+fn footer_size(canvas_width: u32) -> f32 {
+    (canvas_width as f32 / CANVAS_WIDTH_PER_FONT_PIXEL).clamp(MIN_FOOTER_SIZE, MAX_FOOTER_SIZE)
+}
+
+/// Space between the footer line and the edges of its band
+fn footer_padding(canvas_width: u32) -> u32 {
+    (footer_size(canvas_width) / 2.0).ceil() as u32
+}
+
+/// Height of the footer band below a composite of the given width (0 without a footer)
+pub fn footer_height(footer: Option<&str>, canvas_width: u32) -> u32 {
+    match footer {
+        Some(_) => text::line_height(footer_size(canvas_width)) + 2 * footer_padding(canvas_width),
+        None => 0,
+    }
+}
+
+/// Put a footer line on the given background below a composite
+pub fn add_footer(composite: DynamicImage, footer: Option<&str>, background: Rgba<u8>) -> DynamicImage {
+    let Some(footer) = footer else {
+        return composite;
+    };
+
+    let width = composite.width();
+    let band_height = footer_height(Some(footer), width);
+    let mut output: RgbaImage = ImageBuffer::from_pixel(width, composite.height() + band_height, background);
+    image::imageops::replace(&mut output, &composite.to_rgba8(), 0, 0);
+
+    let size = footer_size(width);
+    let padding = footer_padding(width);
+    let fitted = text::fit_text(footer, size, width.saturating_sub(2 * padding));
+    let x = (width - text::text_width(&fitted, size)) / 2;
+    let y = composite.height() + padding;
+    text::draw_text(&mut output, &fitted, size, x as i64, y as i64, text::contrasting_color(background));
+
+    DynamicImage::ImageRgba8(output)
+}
+
+/// Draw semi-transparent text diagonally across an image, rising from bottom left to top right
+pub fn add_watermark(image: DynamicImage, watermark: Option<&str>, opacity: u8) -> DynamicImage {
+    let Some(watermark) = watermark.filter(|watermark| !watermark.trim().is_empty()) else {
+        return image;
+    };
+    let mut output = image.to_rgba8();
+    let (width, height) = output.dimensions();
+    if width == 0 || height == 0 || opacity == 0 {
+        return DynamicImage::ImageRgba8(output);
+    }
+
+    // Render the text upright, sized to span most of the diagonal without crowding narrow outputs
+    let diagonal = (width as f32).hypot(height as f32);
+    let size = (100.0 * WATERMARK_SPAN * diagonal / text::text_width(watermark, 100.0).max(1) as f32)
+        .min(width.min(height) as f32 * WATERMARK_MAX_HEIGHT);
+    let mut upright = RgbaImage::new(text::text_width(watermark, size).max(1), text::line_height(size).max(1));
+    let mut color = WATERMARK_COLOR;
+    color.0[3] = (255.0 * opacity.min(100) as f32 / 100.0).round() as u8;
+    text::draw_text(&mut upright, watermark, size, 0, 0, color);
+
+    // Map every output pixel back onto the upright text, rotated about the output's centre
+    let angle = (height as f32).atan2(width as f32);
+    let (sin, cos) = angle.sin_cos();
+    let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    let (text_center_x, text_center_y) = (upright.width() as f32 / 2.0, upright.height() as f32 / 2.0);
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) = (x as f32 + 0.5 - center_x, y as f32 + 0.5 - center_y);
+            let text_x = dx * cos - dy * sin + text_center_x;
+            let text_y = dx * sin + dy * cos + text_center_y;
+            if text_x < 0.0 || text_y < 0.0 {
+                continue;
+            }
+            let (text_x, text_y) = (text_x as u32, text_y as u32);
+            if text_x < upright.width() && text_y < upright.height() {
+                let ink = *upright.get_pixel(text_x, text_y);
+                if ink.0[3] > 0 {
+                    output.get_pixel_mut(x, y).blend(&ink);
+                }
+            }
+        }
+    }
+
+    DynamicImage::ImageRgba8(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::test_utils::generate_test_image;
+
+    #[test]
+    fn test_fill_template() {
+        let directory = Path::new("evidence/2024-01-15 ISC2 Webinar - Zero Trust");
+        let date = Local.with_ymd_and_hms(2024, 1, 16, 9, 30, 0).unwrap();
+
+        assert_eq!(
+            fill_template("J. Doe – ISC2 #123456 – {title} – submitted {date}", Some(directory), date),
+            "J. Doe – ISC2 #123456 – ISC2 Webinar - Zero Trust – submitted 2024-01-16"
+        );
+        assert_eq!(fill_template("{name}", Some(directory), date), "2024-01-15 ISC2 Webinar - Zero Trust");
+        assert_eq!(fill_template("{name}|{member}", None, date), "|{member}");
+
+        let stamp = Stamp { footer: Some("{date}".to_string()), ..Stamp::default() };
+        assert_eq!(stamp.footer_text(None, date).as_deref(), Some("2024-01-16"));
+        assert_eq!(stamp.watermark_text(None, date), None);
+    }
+
+    #[test]
+    fn test_add_footer() {
+        let composite = generate_test_image(400, 100, [255, 0, 0]);
+        let white = Rgba([255, 255, 255, 255]);
+
+        let output = add_footer(composite.clone(), Some("J. Doe – ISC2 #123456"), white).to_rgba8();
+        let band_height = footer_height(Some("J. Doe"), 400);
+        assert_eq!(output.dimensions(), (400, 100 + band_height));
+        assert_eq!(output.get_pixel(0, 99).0, [255, 0, 0, 255]);
+        assert!((100..100 + band_height).any(|y| (0..400).any(|x| output.get_pixel(x, y).0[0] < 128)), "No footer text");
+
+        assert_eq!(footer_height(None, 400), 0);
+        assert_eq!(add_footer(composite, None, white).height(), 100);
+    }
+
+    #[test]
+    fn test_add_watermark() {
+        let image = generate_test_image(400, 200, [255, 255, 255]);
+
+        let output = add_watermark(image.clone(), Some("J. Doe – ISC2 #123456"), 50).to_rgba8();
+        assert_eq!(output.dimensions(), (400, 200));
+        // Semi-transparent text across the centre, corners untouched
+        let marked: Vec<_> = output.pixels().filter(|pixel| pixel.0 != [255, 255, 255, 255]).collect();
+        assert!(!marked.is_empty(), "No watermark drawn");
+        assert!(marked.iter().all(|pixel| pixel.0[0] >= 128), "Watermark should be translucent");
+        assert_eq!(output.get_pixel(0, 0).0, [255, 255, 255, 255]);
+        assert_eq!(output.get_pixel(399, 199).0, [255, 255, 255, 255]);
+
+        // Nothing configured, blank or fully transparent: unchanged
+        assert_eq!(add_watermark(image.clone(), None, 50).to_rgba8(), image.to_rgba8());
+        assert_eq!(add_watermark(image.clone(), Some(" "), 50).to_rgba8(), image.to_rgba8());
+        assert_eq!(add_watermark(image.clone(), Some("x"), 0).to_rgba8(), image.to_rgba8());
+    }
+}
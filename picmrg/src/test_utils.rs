@@ -22,6 +22,15 @@ pub fn save_test_image(image: &DynamicImage, path: &Path) -> Result<(), Box<dyn
     Ok(())
 }
 
+/// Save an image the way picmrg saves its merged output, including the provenance marker
+#[cfg(test)]
+pub fn save_test_merged_image(image: &DynamicImage, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let provenance = crate::provenance::Provenance::new("vertical", &[]);
+    let encoded = crate::output::encode(image, &crate::format::Encoding::default(), &provenance)?;
+    crate::output::stage_encoded(&encoded, path)?.commit()?;
+    Ok(())
+}
+
 #[cfg(test)]
 pub fn setup_test_data_for_test(test_name: &str) -> Result<String, Box<dyn std::error::Error>> {
     let test_root = format!("test-data-{}", test_name);
@@ -77,8 +86,8 @@ pub fn setup_test_data_for_test(test_name: &str) -> Result<String, Box<dyn std::
 
     // Create some merged files that should be ignored
     let old_merged = generate_test_image(100, 100, [50, 50, 50]);
-    save_test_image(&old_merged, &test_path.join("vertical-images/merged.png"))?;
-    save_test_image(&old_merged, &test_path.join("horizontal-images/merged-23-12-25.png"))?;
+    save_test_merged_image(&old_merged, &test_path.join("vertical-images/merged.png"))?;
+    save_test_merged_image(&old_merged, &test_path.join("horizontal-images/merged-23-12-25.png"))?;
 
     Ok(test_root)
 }
@@ -96,7 +105,7 @@ pub fn cleanup_test_data_for_test(test_root: &str) -> Result<(), Box<dyn std::er
                         return Ok(()); // Already removed
                     }
                     // Try manual cleanup
-                    if let Err(_) = recursive_remove_dir(test_path) {
+                    if recursive_remove_dir(test_path).is_err() {
                         std::thread::sleep(std::time::Duration::from_millis(10));
                         continue;
                     }
@@ -0,0 +1,134 @@
+use ab_glyph::{point, Font, FontRef, Glyph, PxScale, ScaleFont};
+use image::{Pixel, Rgba, RgbaImage};
+use std::sync::OnceLock;
+
+/// DejaVu Sans, embedded so text renders the same on every platform (see assets/LICENSE-DejaVu.txt)
+static FONT_DATA: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+
+/// Appended to text shortened to fit
+const ELLIPSIS: char = '…';
+
+/// The embedded font, parsed on first use
+fn font() -> &'static FontRef<'static> {
+    static FONT: OnceLock<FontRef<'static>> = OnceLock::new();
+    FONT.get_or_init(|| FontRef::try_from_slice(FONT_DATA).expect("Embedded font should be valid"))
+}
+
+/// Glyphs of a line of text at the given pixel size, positioned with the baseline at `ascent`
+fn layout_glyphs(text: &str, size: f32) -> (Vec<Glyph>, f32) {
+    let font = font().as_scaled(PxScale::from(size));
+    let mut glyphs = Vec::new();
+    let mut caret = 0.0;
+    let mut previous = None;
+
+    for character in text.chars() {
+        let id = font.glyph_id(character);
+        if let Some(previous) = previous {
+            caret += font.kern(previous, id);
+        }
+        glyphs.push(id.with_scale_and_position(size, point(caret, font.ascent())));
+        caret += font.h_advance(id);
+        previous = Some(id);
+    }
+
+    (glyphs, caret)
+}
+
+/// Width of a line of text at the given pixel size
+pub fn text_width(text: &str, size: f32) -> u32 {
+    layout_glyphs(text, size).1.ceil() as u32
+}
+
+/// Height of a line of text at the given pixel size, from ascent to descent
+pub fn line_height(size: f32) -> u32 {
+    let font = font().as_scaled(PxScale::from(size));
+    (font.ascent() - font.descent()).ceil() as u32
+}
+
+/// Shorten text with an ellipsis until it is at most `max_width` pixels wide
+pub fn fit_text(text: &str, size: f32, max_width: u32) -> String {
+    if text_width(text, size) <= max_width {
+        return text.to_string();
+    }
+
+    let mut characters: Vec<char> = text.chars().collect();
+    while !characters.is_empty() {
+        characters.pop();
+        let shortened: String = characters.iter().chain(std::iter::once(&ELLIPSIS)).collect();
+        if text_width(&shortened, size) <= max_width {
+            return shortened;
+        }
+    }
+    String::new()
+}
+
+/// Draw a line of text with the top left corner of its line box at (x, y), clipped to the canvas
+pub fn draw_text(canvas: &mut RgbaImage, text: &str, size: f32, x: i64, y: i64, color: Rgba<u8>) {
+    let (glyphs, _) = layout_glyphs(text, size);
+
+    for glyph in glyphs {
+        let Some(outline) = font().outline_glyph(glyph) else {
+            continue; // Whitespace
+        };
+        let bounds = outline.px_bounds();
+        outline.draw(|glyph_x, glyph_y, coverage| {
+            let pixel_x = x + bounds.min.x as i64 + glyph_x as i64;
+            let pixel_y = y + bounds.min.y as i64 + glyph_y as i64;
+            if pixel_x < 0 || pixel_y < 0 || pixel_x >= canvas.width() as i64 || pixel_y >= canvas.height() as i64 {
+                return;
+            }
+            let mut ink = color;
+            ink.0[3] = (color.0[3] as f32 * coverage.min(1.0)).round() as u8;
+            canvas.get_pixel_mut(pixel_x as u32, pixel_y as u32).blend(&ink);
+        });
+    }
+}
+
+/// Text colour that stands out on the given background (dark unless the background is dark)
+pub fn contrasting_color(background: Rgba<u8>) -> Rgba<u8> {
+    let [red, green, blue, alpha] = background.0;
+    let luminance = 0.299 * red as f32 + 0.587 * green as f32 + 0.114 * blue as f32;
+    if alpha > 0 && luminance < 128.0 {
+        Rgba([255, 255, 255, 255])
+    } else {
+        Rgba([32, 32, 32, 255])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_metrics() {
+        assert_eq!(text_width("", 20.0), 0);
+        assert!(text_width("screenshot.png", 20.0) > text_width("a.png", 20.0));
+        assert!(text_width("abc", 40.0) > text_width("abc", 20.0));
+        assert!(line_height(20.0) >= 20);
+
+        // Long text is shortened with an ellipsis, short text is kept
+        assert_eq!(fit_text("a.png", 20.0, 1000), "a.png");
+        let fitted = fit_text("a-very-long-screenshot-name.png", 20.0, 100);
+        assert!(fitted.ends_with('…') && text_width(&fitted, 20.0) <= 100, "{}", fitted);
+        assert_eq!(fit_text("abc", 20.0, 0), "");
+    }
+
+    #[test]
+    fn test_draw_text() {
+        let mut canvas = RgbaImage::from_pixel(100, 30, Rgba([255, 255, 255, 255]));
+        draw_text(&mut canvas, "Hi", 20.0, 5, 5, Rgba([0, 0, 0, 255]));
+        assert!(canvas.pixels().any(|pixel| pixel.0[0] < 128), "No text was drawn");
+        // Nothing is drawn right of the text
+        assert!((50..100).all(|x| canvas.get_pixel(x, 15).0 == [255, 255, 255, 255]));
+
+        // Text partly outside the canvas is clipped instead of panicking
+        draw_text(&mut canvas, "Hi", 20.0, -10, 25, Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_contrasting_color() {
+        assert_eq!(contrasting_color(Rgba([0, 0, 0, 255])), Rgba([255, 255, 255, 255]));
+        assert_eq!(contrasting_color(Rgba([255, 255, 255, 255])), Rgba([32, 32, 32, 255]));
+        assert_eq!(contrasting_color(Rgba([0, 0, 0, 0])), Rgba([32, 32, 32, 255]));
+    }
+}